@@ -4,17 +4,32 @@ CREATE TABLE IF NOT EXISTS album (
     name TEXT NOT NULL,
     year INTEGER,
     track INTEGER,
-    cover TEXT
+    cover TEXT,
+    -- EBU R128 analysis over all tracks of the album
+    loudness REAL,
+    loudness_range REAL,
+    true_peak REAL
 );
 
 -- Create Media table
 CREATE TABLE IF NOT EXISTS media (
     id INTEGER PRIMARY KEY AUTOINCREMENT,
-    file TEXT NOT NULL UNIQUE,
+    file TEXT NOT NULL,
+    -- track range inside the file in microseconds, a cue sheet splits one file into many
+    start_us INTEGER NOT NULL DEFAULT 0,
+    end_us INTEGER,
     name TEXT NOT NULL,
     artist TEXT,
     album_id INTEGER,
     track INTEGER,
+    -- EBU R128 analysis, integrated LUFS, LU and dBTP
+    loudness REAL,
+    loudness_range REAL,
+    true_peak REAL,
+    -- file modification time and size at the last analysis
+    scan_mtime INTEGER,
+    scan_size INTEGER,
+    UNIQUE (file, start_us),
     FOREIGN KEY (album_id) REFERENCES album(id)
 );
 
@@ -42,6 +57,8 @@ CREATE VIEW IF NOT EXISTS media_with_album AS
 SELECT 
     m.id,
     m.file,
+    m.start_us,
+    m.end_us,
     m.name,
     m.artist,
     m.track,
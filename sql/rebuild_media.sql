@@ -0,0 +1,37 @@
+-- Media was unique by file before cue sheets split one file into many tracks,
+-- the constraint can only go with the table, so it is made again
+DROP VIEW IF EXISTS media_with_album;
+
+CREATE TABLE media_rebuild (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    file TEXT NOT NULL,
+    -- track range inside the file in microseconds, a cue sheet splits one file into many
+    start_us INTEGER NOT NULL DEFAULT 0,
+    end_us INTEGER,
+    name TEXT NOT NULL,
+    artist TEXT,
+    album_id INTEGER,
+    track INTEGER,
+    -- EBU R128 analysis, integrated LUFS, LU and dBTP
+    loudness REAL,
+    loudness_range REAL,
+    true_peak REAL,
+    -- file modification time and size at the last analysis
+    scan_mtime INTEGER,
+    scan_size INTEGER,
+    UNIQUE (file, start_us),
+    FOREIGN KEY (album_id) REFERENCES album(id)
+);
+
+INSERT INTO media_rebuild (
+    id, file, start_us, end_us, name, artist, album_id, track,
+    loudness, loudness_range, true_peak, scan_mtime, scan_size
+)
+SELECT
+    id, file, start_us, end_us, name, artist, album_id, track,
+    loudness, loudness_range, true_peak, scan_mtime, scan_size
+FROM media;
+
+DROP TABLE media;
+
+ALTER TABLE media_rebuild RENAME TO media;
@@ -0,0 +1,352 @@
+use std::{thread, time::Duration};
+
+use anyhow::{anyhow, Result};
+
+use alsa::{
+    pcm::{
+        Format, HwParams, State
+    },
+    Direction,
+    PCM
+};
+
+use crate::{
+    devices::Capabilities,
+    media::{MediaSpec, OutputMode},
+    output::{OutputBackend, Xrun},
+    sample::{pack_s16, pack_s24, pack_s24_3le}
+};
+
+/// Pcm formats tried after the native one, best first.
+pub const PCM_FORMATS: [Format; 4] = [Format::S32LE, Format::S243LE, Format::S24LE, Format::S16LE];
+
+/// Device formats holding `bits` per sample without padding or loss.
+pub fn native_formats(bits: Option<u32>) -> &'static [Format] {
+    match bits {
+        Some(16) => &[Format::S16LE],
+        Some(24) => &[Format::S243LE, Format::S24LE],
+        Some(32) => &[Format::S32LE],
+        _ => &[],
+    }
+}
+
+/// Sample depth of a pcm format, `None` for anything else.
+pub fn format_bits(format: Format) -> Option<u32> {
+    match format {
+        Format::S16LE => Some(16),
+        Format::S24LE | Format::S243LE => Some(24),
+        Format::S32LE => Some(32),
+        _ => None,
+    }
+}
+
+/// Pause between attempts to resume a suspended device.
+const RESUME_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Attempts before a suspended device is prepared instead, five seconds.
+const RESUME_ATTEMPTS: u32 = 50;
+
+pub struct AlsaOutput {
+    pcm: PCM,
+    device: String,
+    /// spec and format of the configured device
+    spec: Option<MediaSpec>,
+    format: Option<Format>,
+    /// after an underrun the device waits for a full buffer instead of starting with the first write
+    refill: bool,
+    // scratch space for packing
+    s16: Vec<i16>,
+    s24: Vec<i32>,
+    s24_3: Vec<u8>,
+}
+
+impl AlsaOutput {
+    pub fn new(device_name: &str) -> Result<Self> {
+        let pcm = PCM::new(device_name, Direction::Playback, false)?;
+
+        Ok(Self {
+            pcm,
+            device: device_name.to_owned(),
+            spec: None,
+            format: None,
+            refill: false,
+            s16: Vec::new(),
+            s24: Vec::new(),
+            s24_3: Vec::new(),
+        })
+    }
+
+    pub fn set_hw_param(&self, spec: MediaSpec) -> Result<()> {
+        use OutputMode::*;
+        match spec.mode {
+            PCM => self.pcm_hw_param(spec.channel, spec.sample_rate, spec.bits),
+            DsdToPcm => self.pcm_hw_param(spec.channel, spec.sample_rate, None),
+            DSD => self.dsd_hw_param(spec.channel, spec.sample_rate),
+            DoP => self.dop_hw_param(spec.channel, spec.sample_rate),
+        }
+    }
+
+    pub fn pcm_hw_param(&self, channel: u32, bit_rate: u32, bits: Option<u32>) -> Result<()> {
+        let hwp = HwParams::any(&self.pcm)?;
+        hwp.set_channels(channel)?;
+        // the device may settle on another rate, which is resampled to
+        hwp.set_rate(bit_rate, alsa::ValueOr::Nearest)?;
+
+        // native depth keeps the output bit perfect, then the widest the device takes
+        let format = native_formats(bits)
+            .iter()
+            .chain(PCM_FORMATS.iter())
+            .find(|f| hwp.test_format(**f).is_ok())
+            .ok_or(anyhow!("device supports none of the pcm formats"))?;
+        hwp.set_format(*format)?;
+        hwp.set_access(alsa::pcm::Access::RWInterleaved)?;
+        self.pcm.hw_params(&hwp)?;
+        Ok(())
+    }
+
+    pub fn dsd_hw_param(&self, channel: u32, bit_rate: u32) -> Result<()> {
+        let hwp = HwParams::any(&self.pcm)?;
+        hwp.set_channels(channel)?;
+        hwp.set_format(alsa::pcm::Format::DSDU32LE)?;
+        // one DSD_U32 frame carries 32 dsd bits per channel
+        let rate = bit_rate / 32;
+        hwp.set_rate(rate, alsa::ValueOr::Nearest)?;
+        if hwp.get_rate()? != rate {
+            return Err(anyhow!("native dsd needs exactly {rate}Hz"));
+        }
+        hwp.set_access(alsa::pcm::Access::RWInterleaved)?;
+        self.pcm.hw_params(&hwp)?;
+        Ok(())
+    }
+
+    pub fn dop_hw_param(&self, channel: u32, bit_rate: u32) -> Result<()> {
+        // every DoP frame carries 16 dsd bits per channel
+        let rate = bit_rate / 16;
+        let hwp = HwParams::any(&self.pcm)?;
+        hwp.set_channels(channel)?;
+        hwp.set_rate(rate, alsa::ValueOr::Nearest)?;
+        if hwp.get_rate()? != rate {
+            return Err(anyhow!("DoP needs exactly {rate}Hz"));
+        }
+
+        // DoP needs at least 24 bits
+        let format = [Format::S32LE, Format::S243LE, Format::S24LE]
+            .into_iter()
+            .find(|f| hwp.test_format(*f).is_ok())
+            .ok_or(anyhow!("device supports no 24 bit format for DoP"))?;
+        hwp.set_format(format)?;
+        hwp.set_access(alsa::pcm::Access::RWInterleaved)?;
+        self.pcm.hw_params(&hwp)?;
+        Ok(())
+    }
+
+    pub fn set_sw_param(&self) -> Result<()> {
+        let swp = self.pcm.sw_params_current()?;
+        let hwp = self.pcm.hw_params_current()?;
+        swp.set_start_threshold(hwp.get_buffer_size().unwrap())?;
+        self.pcm.sw_params(&swp)?;
+        Ok(())
+    }
+}
+
+impl OutputBackend for AlsaOutput {
+    fn configure(&mut self, spec: MediaSpec) -> Result<()> {
+        // hw params are refused while the stream runs
+        self.format = None;
+        if self.pcm.state() != State::Open {
+            self.pcm.drop()?;
+        }
+        self.set_hw_param(spec)?;
+        self.set_sw_param()?;
+        self.spec = Some(spec);
+        self.format = Some(self.pcm.hw_params_current()?.get_format()?);
+        self.refill = false;
+
+        if !matches!(self.pcm.state(), State::Running | State::Prepared) {
+            self.pcm.prepare()?;
+        }
+        Ok(())
+    }
+
+    fn rate(&self) -> Result<u32> {
+        Ok(self.pcm.hw_params_current()?.get_rate()?)
+    }
+
+    fn bits(&self) -> Option<u32> {
+        format_bits(self.format?)
+    }
+
+    /// Every write takes its own io, alsa allows a single one per stream.
+    fn write(&mut self, buf: &[i32]) -> Result<usize> {
+        let frames = match self.format.ok_or(anyhow!("output not configured"))? {
+            Format::S16LE => {
+                pack_s16(buf, &mut self.s16);
+                self.pcm.io_i16()?.writei(&self.s16)?
+            },
+            Format::S24LE => {
+                pack_s24(buf, &mut self.s24);
+                self.pcm.io_i32_s24()?.writei(&self.s24)?
+            },
+            Format::S243LE => {
+                pack_s24_3le(buf, &mut self.s24_3);
+                self.pcm.io_bytes().writei(&self.s24_3)?
+            },
+            Format::S32LE => self.pcm.io_i32()?.writei(buf)?,
+            Format::DSDU32LE => {
+                // SAFETY: i32 and u32 share size and alignment, the slice borrows `buf` and lives no longer
+                let buf = unsafe {
+                    std::slice::from_raw_parts(
+                        buf.as_ptr() as *const u32,
+                        buf.len()
+                    )
+                };
+                // SAFETY: DSD_U32_LE frames are 32 bit words, this is the only io of the stream
+                unsafe { self.pcm.io_unchecked::<u32>() }.writei(buf)?
+            },
+            f => return Err(anyhow!("unsupported output format {f}")),
+        };
+
+        // start with the first write instead of waiting for a full buffer,
+        // unless refilling, then the start threshold starts it
+        match self.pcm.state() {
+            State::Prepared if !self.refill => self.pcm.start()?,
+            State::Running => self.refill = false,
+            _ => {},
+        }
+
+        Ok(frames)
+    }
+
+    fn wait(&mut self, timeout: Duration) -> Result<()> {
+        self.pcm.wait(Some(timeout.as_millis() as u32))?;
+        match self.pcm.state() {
+            // wait does not fail for every stopped stream, recover them alike
+            State::XRun => Err(alsa::Error::new("snd_pcm_wait", libc::EPIPE).into()),
+            State::Suspended => Err(alsa::Error::new("snd_pcm_wait", libc::ESTRPIPE).into()),
+            State::Disconnected => Err(alsa::Error::new("snd_pcm_wait", libc::ENODEV).into()),
+            State::Setup => Ok(self.pcm.prepare()?),
+            _ => Ok(()),
+        }
+    }
+
+    fn pause(&mut self, pause: bool) -> Result<()> {
+        Ok(self.pcm.pause(pause)?)
+    }
+
+    fn drain(&mut self) -> Result<()> {
+        Ok(self.pcm.drain()?)
+    }
+
+    fn discard(&mut self) -> Result<()> {
+        self.pcm.drop()?;
+        Ok(self.pcm.prepare()?)
+    }
+
+    fn delay(&self) -> Result<i64> {
+        Ok(self.pcm.delay()?)
+    }
+
+    fn recover(&mut self, e: anyhow::Error) -> Result<Xrun> {
+        let Some(errno) = e.downcast_ref::<alsa::Error>().map(alsa::Error::errno) else {
+            return Err(e);
+        };
+
+        match errno {
+            libc::EPIPE => {
+                self.pcm.prepare()?;
+                self.refill = true;
+                Ok(Xrun::Underrun)
+            },
+            libc::ESTRPIPE => {
+                // resume fails with EAGAIN until the device is back up
+                let mut resumed = self.pcm.resume();
+                for _ in 1..RESUME_ATTEMPTS {
+                    match resumed {
+                        Err(e) if e.errno() == libc::EAGAIN => thread::sleep(RESUME_INTERVAL),
+                        _ => break,
+                    }
+                    resumed = self.pcm.resume();
+                }
+                // not every device can resume, those start over, a device still
+                // suspended fails to prepare and the error is returned
+                if resumed.is_err() {
+                    self.pcm.prepare()?;
+                }
+                self.refill = true;
+                Ok(Xrun::Suspend)
+            },
+            libc::ENODEV | libc::EBADFD | libc::ENXIO | libc::EIO => {
+                self.format = None;
+                Ok(Xrun::Disconnect)
+            },
+            _ => Err(e),
+        }
+    }
+
+    fn reopen(&mut self) -> Result<()> {
+        let spec = self.spec.ok_or(anyhow!("output not configured"))?;
+        self.pcm = PCM::new(&self.device, Direction::Playback, false)?;
+        self.configure(spec)?;
+        self.refill = true;
+        Ok(())
+    }
+
+    fn capabilities(&self, rates: &[u32]) -> Option<Capabilities> {
+        // any() starts from the full space, whatever the stream is configured with
+        let hwp = HwParams::any(&self.pcm).ok()?;
+        Capabilities::from_hw_params(&hwp, rates).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn native_formats_follow_the_fallback_order() {
+        for bits in [Some(16), Some(24), Some(32)] {
+            let native = native_formats(bits);
+            assert!(!native.is_empty());
+            assert!(native.iter().all(|f| format_bits(*f) == bits));
+
+            // the native formats are tried in the order of the fallback chain
+            let order: Vec<usize> = native
+                .iter()
+                .map(|f| PCM_FORMATS.iter().position(|p| p == f).unwrap())
+                .collect();
+            assert!(order.is_sorted());
+        }
+
+        // packed 24 bit before 24 in 32
+        assert_eq!(native_formats(Some(24)), [Format::S243LE, Format::S24LE]);
+        assert!(native_formats(None).is_empty());
+        assert!(native_formats(Some(20)).is_empty());
+        assert_eq!(PCM_FORMATS.map(format_bits), [Some(32), Some(24), Some(24), Some(16)]);
+    }
+
+    #[test]
+    fn packers_keep_the_top_bytes_little_endian() {
+        let samples = [0x1234_5678, -0x1234_5678, i32::MAX, i32::MIN];
+
+        let mut s16 = Vec::new();
+        pack_s16(&samples, &mut s16);
+        assert_eq!(s16, [0x1234, -0x1235, i16::MAX, i16::MIN]);
+
+        // S24_LE is the sample right aligned in 32 bits, the top byte sign extended
+        let mut s24 = Vec::new();
+        pack_s24(&samples, &mut s24);
+        assert_eq!(s24[0].to_le_bytes(), [0x56, 0x34, 0x12, 0x00]);
+        assert_eq!(s24[1].to_le_bytes(), [0xa9, 0xcb, 0xed, 0xff]);
+        assert_eq!(s24[2], 0x7f_ffff);
+        assert_eq!(s24[3], -0x80_0000);
+
+        // S24_3LE is the same three low bytes without the padding
+        let mut s24_3 = Vec::new();
+        pack_s24_3le(&samples, &mut s24_3);
+        assert_eq!(s24_3.len(), samples.len() * 3);
+        assert_eq!(s24_3[..6], [0x56, 0x34, 0x12, 0xa9, 0xcb, 0xed]);
+        for (packed, padded) in s24_3.chunks(3).zip(&s24) {
+            assert_eq!(packed, &padded.to_le_bytes()[..3]);
+        }
+    }
+}
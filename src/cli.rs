@@ -1,7 +1,20 @@
-use std::path::PathBuf;
+use std::{path::PathBuf, time::Duration};
 
 use clap::{command, Parser, Subcommand};
 
+use crate::{
+    decoder::DEFAULT_ERROR_BUDGET,
+    devices::parse_spec,
+    dither::{DitherMode, NoiseShaping},
+    feed::DEFAULT_PREBUFFER_MS,
+    gain::GainMode,
+    media::{DsdOutput, MediaSpec},
+    output::OutputKind,
+    player::DEFAULT_RT_PRIORITY,
+    progress::parse_position,
+    resampler::ResampleQuality,
+};
+
 #[derive(Parser, Debug)]
 #[command(version, long_about = None)]
 pub struct Args {
@@ -11,13 +24,16 @@ pub struct Args {
 
 #[derive(Subcommand, Debug)]
 pub enum Commands {
-    Play {
-        #[arg(short, long)]
-        path: PathBuf,
+    Play(PlayArgs),
+
+    /// Measure EBU R128 loudness of the library files
+    Scan(ScanArgs),
 
-        #[arg(short, long)]
-        device: String,
-    },
+    /// Decode files end to end and check their checksums
+    Verify(VerifyArgs),
+
+    /// List alsa devices and the formats they accept
+    Devices(DevicesArgs),
 
     PlayList {
         #[command(subcommand)]
@@ -30,3 +46,112 @@ pub enum PlayListCommands {
     Init,
     Refresh,
 }
+
+#[derive(clap::Args, Debug)]
+pub struct PlayArgs {
+    /// tracks played in order, gapless when their formats match, `-` reads stdin,
+    /// http(s) urls are streamed
+    #[arg(short, long, num_args = 1.., required = true)]
+    pub path: Vec<PathBuf>,
+
+    /// format of stdin or a stream, e.g. flac or mp3
+    #[arg(long)]
+    pub format: Option<String>,
+
+    /// alsa device, or the file written with `--output file`
+    #[arg(short, long)]
+    pub device: Option<String>,
+
+    #[arg(long, value_enum, default_value_t)]
+    pub output: OutputKind,
+
+    /// audio track id played in files with several, see --list-tracks
+    #[arg(long)]
+    pub track: Option<u32>,
+
+    /// failed packets in a row before a track is skipped
+    #[arg(long, default_value_t = DEFAULT_ERROR_BUDGET)]
+    pub error_budget: u32,
+
+    /// position the first track starts at, `SECONDS`, `MM:SS` or `HH:MM:SS`
+    #[arg(long, value_parser = parse_position)]
+    pub start: Option<Duration>,
+
+    /// print the audio tracks of every path and exit
+    #[arg(long)]
+    pub list_tracks: bool,
+
+    #[arg(long, value_enum, default_value_t)]
+    pub dsd: DsdOutput,
+
+    /// pcm rate for dsd conversion, 88.2kHz for DSD64 and 176.4kHz above by default
+    #[arg(long)]
+    pub dsd_pcm_rate: Option<u32>,
+
+    /// dither applied when the device has fewer bits than the source
+    #[arg(long, value_enum, default_value_t)]
+    pub dither: DitherMode,
+
+    #[arg(long, value_enum, default_value_t)]
+    pub noise_shaping: NoiseShaping,
+
+    /// fixed output rate, e.g. 48000 for HDMI, other rates are resampled
+    #[arg(long)]
+    pub rate: Option<u32>,
+
+    #[arg(long, value_enum, default_value_t)]
+    pub resample_quality: ResampleQuality,
+
+    /// loudness normalization from ReplayGain or R128 tags, never applied to native dsd
+    #[arg(long, value_enum, default_value_t)]
+    pub replay_gain: GainMode,
+
+    /// extra gain in dB on top of the ReplayGain value
+    #[arg(long, default_value_t = 0.0, allow_negative_numbers = true)]
+    pub preamp: f32,
+
+    /// milliseconds decoded before the device starts, after opening and seeking
+    #[arg(long, default_value_t = DEFAULT_PREBUFFER_MS)]
+    pub prebuffer: u32,
+
+    /// SCHED_FIFO priority of the output thread, 0 keeps the normal scheduling
+    #[arg(long, default_value_t = DEFAULT_RT_PRIORITY, value_parser = clap::value_parser!(i32).range(0..=99))]
+    pub rt_priority: i32,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct ScanArgs {
+    /// analyze unchanged files again
+    #[arg(long)]
+    pub force: bool,
+
+    /// files decoded in parallel, one per cpu by default
+    #[arg(short, long)]
+    pub jobs: Option<usize>,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct VerifyArgs {
+    /// files or directories to check
+    #[arg(short, long, num_args = 1.., required = true)]
+    pub path: Vec<PathBuf>,
+
+    /// failed packets in a row before a file is given up
+    #[arg(long, default_value_t = DEFAULT_ERROR_BUDGET)]
+    pub error_budget: u32,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct DevicesArgs {
+    /// probe only this device, e.g. hw:1,0 or a plugin name
+    #[arg(short, long)]
+    pub device: Option<String>,
+
+    /// tell how this file would play on every device
+    #[arg(short, long, conflicts_with = "spec")]
+    pub path: Option<PathBuf>,
+
+    /// tell how a format would play, `96000:24:2` or `dsd:2822400:2`
+    #[arg(long, value_parser = parse_spec)]
+    pub spec: Option<MediaSpec>,
+}
@@ -0,0 +1,296 @@
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use symphonia::core::{
+    formats::FormatOptions,
+    io::MediaSourceStream,
+    meta::MetadataOptions,
+};
+
+use crate::{
+    decoder::{path_hint, TrackRange, STDIN_PATH},
+    http::is_url,
+};
+
+/// Cue sheet times count 75 frames per second.
+const CD_FRAMES: u64 = 75;
+
+/// One track of a cue sheet, the range of `path` from its INDEX 01 to the next one.
+#[derive(Clone, Debug)]
+pub struct CueTrack {
+    pub path: PathBuf,
+    /// TRACK number, none for a whole file without sheet
+    pub number: Option<u32>,
+    pub title: Option<String>,
+    pub performer: Option<String>,
+    pub start: Duration,
+    /// none for the last track of a file, it plays to the end
+    pub end: Option<Duration>,
+}
+
+impl CueTrack {
+    /// All of `path` as one track, for files without sheet.
+    pub fn whole(path: PathBuf) -> Self {
+        Self {
+            path,
+            number: None,
+            title: None,
+            performer: None,
+            start: Duration::ZERO,
+            end: None,
+        }
+    }
+
+    pub fn range(&self) -> TrackRange {
+        TrackRange {
+            path: self.path.clone(),
+            start: self.start,
+            end: self.end,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct CueSheet {
+    pub title: Option<String>,
+    pub performer: Option<String>,
+    pub tracks: Vec<CueTrack>,
+}
+
+impl CueSheet {
+    /// Read a `.cue` file, FILE entries are relative to its directory.
+    pub fn read(p: &Path) -> Result<Self> {
+        let data = std::fs::read(p)?;
+        let data = data.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(&data);
+
+        // older rips are often latin1 instead of utf8
+        let text = match std::str::from_utf8(data) {
+            Ok(text) => text.to_owned(),
+            Err(_) => data.iter().map(|b| *b as char).collect(),
+        };
+
+        let dir = p.parent().unwrap_or(Path::new(""));
+        Self::parse(&text, |file| dir.join(file))
+    }
+
+    /// Cue sheet of a CUESHEET tag, every track refers to `p` itself.
+    pub fn embedded(p: &Path) -> Result<Option<Self>> {
+        let mss = MediaSourceStream::new(Box::new(std::fs::File::open(p)?), Default::default());
+        let mut probed = symphonia::default::get_probe()
+            .format(&path_hint(p), mss, &FormatOptions::default(), &MetadataOptions::default())
+            .map_err(|e| anyhow!(e.to_string()))?;
+
+        let cue = |rev: &symphonia::core::meta::MetadataRevision| {
+            rev.tags()
+                .iter()
+                .find(|t| t.key.eq_ignore_ascii_case("cuesheet"))
+                .map(|t| t.value.to_string())
+        };
+
+        let text = probed.metadata
+            .get()
+            .and_then(|m| m.current().and_then(cue))
+            .or_else(|| probed.format.metadata().current().and_then(cue));
+
+        text.map(|text| Self::parse(&text, |_| p.to_path_buf())).transpose()
+    }
+
+    /// Parse sheet text, `resolve` maps a FILE entry to the audio file.
+    pub fn parse(text: &str, resolve: impl Fn(&str) -> PathBuf) -> Result<Self> {
+        let mut sheet = Self::default();
+        let mut file: Option<PathBuf> = None;
+        // the current track and its INDEX 01, tracks without one are left out
+        let mut track: Option<(CueTrack, Option<Duration>)> = None;
+        let finish = |track: Option<(CueTrack, Option<Duration>)>| {
+            track.and_then(|(track, start)| Some(CueTrack { start: start?, ..track }))
+        };
+
+        for (n, line) in text.lines().enumerate() {
+            let args = split_args(line);
+            let Some((command, args)) = args.split_first() else {
+                continue;
+            };
+            let arg = |i: usize| args.get(i).cloned().ok_or(anyhow!("line {}: missing argument", n + 1));
+
+            match command.to_ascii_uppercase().as_str() {
+                "FILE" => {
+                    sheet.tracks.extend(finish(track.take()));
+                    file = Some(resolve(&arg(0)?));
+                },
+                "TRACK" => {
+                    sheet.tracks.extend(finish(track.take()));
+                    let path = file.clone().ok_or(anyhow!("line {}: TRACK before FILE", n + 1))?;
+                    let number = arg(0)?.parse().ok();
+                    track = Some((CueTrack { number, ..CueTrack::whole(path) }, None));
+                },
+                "TITLE" => match track.as_mut() {
+                    Some((track, _)) => track.title = Some(arg(0)?),
+                    None => sheet.title = Some(arg(0)?),
+                },
+                "PERFORMER" => match track.as_mut() {
+                    Some((track, _)) => track.performer = Some(arg(0)?),
+                    None => sheet.performer = Some(arg(0)?),
+                },
+                "INDEX" if arg(0)?.parse::<u32>()? == 1 => {
+                    if let Some((_, start)) = track.as_mut() {
+                        *start = Some(parse_time(&arg(1)?).ok_or(anyhow!("line {}: bad INDEX time", n + 1))?);
+                    }
+                },
+                _ => {},
+            }
+        }
+
+        sheet.tracks.extend(finish(track));
+
+        // a track ends where the next one of the same file starts
+        for i in 1..sheet.tracks.len() {
+            let (prev, next) = sheet.tracks.split_at_mut(i);
+            let prev = prev.last_mut().unwrap();
+            if prev.path == next[0].path {
+                prev.end = Some(next[0].start);
+            }
+        }
+
+        Ok(sheet)
+    }
+}
+
+/// Tracks of a path, from the `.cue` itself, from an embedded sheet or the whole file.
+pub fn expand(p: PathBuf) -> Result<Vec<TrackRange>> {
+    Ok(tracks(p)?.iter().map(CueTrack::range).collect())
+}
+
+/// Like `expand`, with the numbers and titles of the sheet.
+pub fn tracks(p: PathBuf) -> Result<Vec<CueTrack>> {
+    // stdin can only be read once, streams are not probed twice
+    if p == Path::new(STDIN_PATH) || is_url(&p) {
+        return Ok(vec![CueTrack::whole(p)]);
+    }
+
+    if p.extension().is_some_and(|e| e.eq_ignore_ascii_case("cue")) {
+        return Ok(CueSheet::read(&p)?.tracks);
+    }
+
+    // files symphonia can not probe, like dsd, have no embedded sheet
+    match CueSheet::embedded(&p).ok().flatten() {
+        Some(sheet) if !sheet.tracks.is_empty() => Ok(sheet.tracks),
+        _ => Ok(vec![CueTrack::whole(p)]),
+    }
+}
+
+/// `MM:SS:FF`, frames are 1/75 second.
+fn parse_time(s: &str) -> Option<Duration> {
+    let mut fields = s.split(':').map(|f| f.parse::<u64>());
+    let (min, sec, frames) = (fields.next()?.ok()?, fields.next()?.ok()?, fields.next()?.ok()?);
+    let frames = (min * 60 + sec) * CD_FRAMES + frames;
+    Some(Duration::from_nanos(frames * 1_000_000_000 / CD_FRAMES))
+}
+
+/// Words of a line, double quoted arguments may contain spaces.
+fn split_args(line: &str) -> Vec<String> {
+    let mut args = Vec::new();
+    let mut chars = line.trim().chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => {},
+            '"' => args.push(chars.by_ref().take_while(|c| *c != '"').collect()),
+            c => {
+                let mut arg = String::from(c);
+                while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+                    arg.push(c);
+                }
+                args.push(arg);
+            },
+        }
+    }
+    args
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SHEET: &str = r#"REM GENRE Jazz
+PERFORMER "The Band"
+TITLE "The Album"
+FILE "disc 1.flac" WAVE
+  TRACK 01 AUDIO
+    TITLE "Intro"
+    INDEX 01 00:00:00
+  TRACK 02 AUDIO
+    TITLE "Second Song"
+    PERFORMER "Guest"
+    INDEX 00 03:10:00
+    INDEX 01 03:12:37
+  TRACK 03 AUDIO
+    TITLE "No Index"
+FILE extra.wav WAVE
+  TRACK 04 AUDIO
+    INDEX 01 00:01:00
+"#;
+
+    #[test]
+    fn sheet_tracks_run_from_index_01_to_the_next_one() {
+        let sheet = CueSheet::parse(SHEET, |f| Path::new("/music").join(f)).unwrap();
+
+        assert_eq!(sheet.title.as_deref(), Some("The Album"));
+        assert_eq!(sheet.performer.as_deref(), Some("The Band"));
+        assert_eq!(sheet.tracks.len(), 3);
+
+        let [intro, second, extra] = &sheet.tracks[..] else { unreachable!() };
+        assert_eq!(intro.path, Path::new("/music/disc 1.flac"));
+        assert_eq!(intro.number, Some(1));
+        assert_eq!(intro.start, Duration::ZERO);
+        assert_eq!(intro.end, Some(second.start));
+
+        assert_eq!(second.title.as_deref(), Some("Second Song"));
+        assert_eq!(second.performer.as_deref(), Some("Guest"));
+        // 37 of 75 frames
+        assert_eq!(second.start, Duration::from_nanos(192_493_333_333));
+        // the last track of a file plays to its end
+        assert_eq!(second.end, None);
+
+        assert_eq!(extra.path, Path::new("/music/extra.wav"));
+        assert_eq!(extra.number, Some(4));
+        assert_eq!(extra.start, Duration::from_secs(1));
+    }
+
+    #[test]
+    fn bad_sheets_are_errors() {
+        assert!(CueSheet::parse("TRACK 01 AUDIO", |f| Path::new(f).to_path_buf()).is_err());
+        assert!(CueSheet::parse("FILE a.wav WAVE\nTRACK 01 AUDIO\nINDEX 01 1:2", |f| Path::new(f).to_path_buf()).is_err());
+    }
+
+    #[test]
+    fn quoted_arguments_keep_their_spaces() {
+        assert_eq!(split_args(r#"  FILE "a b.flac" WAVE"#), ["FILE", "a b.flac", "WAVE"]);
+        assert_eq!(parse_time("01:02:75"), Some(Duration::from_secs(63)));
+        assert_eq!(parse_time("01:02"), None);
+    }
+
+    #[test]
+    fn expand_reads_cue_files_and_keeps_other_paths() {
+        let dir = std::env::temp_dir().join(format!("oto-cue-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let cue = dir.join("album.cue");
+        // latin1 with a BOM-less é
+        let mut data = SHEET.replace("Intro", "Intr\u{e9}").into_bytes();
+        let e = data.windows(2).position(|w| w == "é".as_bytes()).unwrap();
+        data.splice(e..e + 2, [0xe9]);
+        std::fs::write(&cue, data).unwrap();
+
+        let tracks = tracks(cue).unwrap();
+        assert_eq!(tracks[0].path, dir.join("disc 1.flac"));
+        assert_eq!(tracks[0].title.as_deref(), Some("Intré"));
+        assert_eq!(expand(dir.join("album.cue")).unwrap().len(), 3);
+
+        // a file that can not be probed is one track
+        let plain = expand(dir.join("missing.flac")).unwrap();
+        assert_eq!(plain.len(), 1);
+        assert!(plain[0].start.is_zero() && plain[0].end.is_none());
+        assert_eq!(expand(PathBuf::from(STDIN_PATH)).unwrap()[0].path, Path::new(STDIN_PATH));
+    }
+}
@@ -3,6 +3,8 @@ use std::{
     fmt::Display,
     io::{Cursor, Read, Seek, SeekFrom},
     path::{Path, PathBuf},
+    sync::mpsc::Sender,
+    time::Duration,
 };
 
 use anyhow::{anyhow, Result};
@@ -20,21 +22,50 @@ use symphonia::core::{
     errors::Error,
     formats::{
         FormatOptions,
-        FormatReader
+        FormatReader,
+        SeekMode,
+        SeekTo,
+        Track
     },
-    io::MediaSourceStream,
-    meta::MetadataOptions,
+    io::{MediaSource, MediaSourceStream, ReadOnlySource},
+    meta::{MetadataOptions, MetadataRevision, StandardTagKey},
     probe::Hint
 };
 
-use crate::media::MediaSpec;
+use id3::TagLike;
+
+use crate::{
+    event::PlayerEvent,
+    gain::ReplayGain,
+    http::{is_url, HttpSource},
+    media::MediaSpec,
+    source::BufferedSource,
+    tags::Tags,
+};
+
+/// Consecutive failures after which `DecoderManager` gives a track up.
+pub const DEFAULT_ERROR_BUDGET: u32 = 16;
 
 #[allow(clippy::upper_case_acronyms)]
 #[derive(Debug, Clone)]
 pub enum DecoderError {
     EOF,
+    /// nothing decoded, e.g. a packet of another track, not a failure
     Ignored,
-    Raw(String)
+    /// the stream continues with another rate or channel count, see `spec`
+    SpecChanged,
+    /// reading or seeking the source failed
+    Io(String),
+    /// a packet could not be decoded and was skipped
+    Corrupt(String),
+    /// the codec or one of its features is not supported
+    Unsupported(String),
+    /// the stream changed its tracks and no decoder could be made for them
+    Reset(String),
+    /// the track failed too often in a row and was given up, carries the last failure
+    Abandoned(Box<DecoderError>),
+    /// a queued track could not be opened and was skipped
+    Open(String),
 }
 
 impl std::error::Error for DecoderError {}
@@ -44,7 +75,36 @@ impl Display for DecoderError {
         match self {
             DecoderError::EOF => write!(f, "eof"),
             DecoderError::Ignored => write!(f, "Ignored"),
-            DecoderError::Raw(s) => write!(f, "{s}"),
+            DecoderError::SpecChanged => write!(f, "spec changed"),
+            DecoderError::Io(s) => write!(f, "io error: {s}"),
+            DecoderError::Corrupt(s) => write!(f, "corrupt packet: {s}"),
+            DecoderError::Unsupported(s) => write!(f, "unsupported: {s}"),
+            DecoderError::Reset(s) => write!(f, "stream reset failed: {s}"),
+            DecoderError::Abandoned(e) => write!(f, "track abandoned after {e}"),
+            DecoderError::Open(s) => write!(f, "can not open {s}"),
+        }
+    }
+}
+
+impl From<Error> for DecoderError {
+    fn from(err: Error) -> Self {
+        match err {
+            Error::IoError(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => DecoderError::EOF,
+            Error::IoError(e) => DecoderError::Io(e.to_string()),
+            Error::DecodeError(s) => DecoderError::Corrupt(s.to_owned()),
+            Error::SeekError(e) => DecoderError::Io(format!("seek failed: {e:?}")),
+            Error::Unsupported(s) => DecoderError::Unsupported(s.to_owned()),
+            Error::LimitError(s) => DecoderError::Unsupported(s.to_owned()),
+            Error::ResetRequired => DecoderError::Reset("reset required".to_owned()),
+        }
+    }
+}
+
+impl From<std::io::Error> for DecoderError {
+    fn from(err: std::io::Error) -> Self {
+        match err.kind() {
+            std::io::ErrorKind::UnexpectedEof => DecoderError::EOF,
+            _ => DecoderError::Io(err.to_string()),
         }
     }
 }
@@ -52,34 +112,249 @@ impl Display for DecoderError {
 pub trait Decoder {
     fn decode(&mut self, buf: &mut VecDeque<i32>) -> Result<(), DecoderError>;
     fn spec(&self) -> Option<MediaSpec>;
+    /// Jump to `pos` from the start of the track, the next decode starts exactly there.
+    fn seek(&mut self, pos: Duration) -> Result<(), DecoderError>;
+    /// Track length in frames of `spec().sample_rate`, dsd frames are single bits.
+    fn frames(&self) -> Option<u64>;
+    /// Loudness normalization from the track tags.
+    fn replay_gain(&self) -> ReplayGain;
+    /// Audio tracks of the container, dsd files have a single one.
+    fn tracks(&self) -> Vec<AudioTrack>;
+    /// Tags of the track, streams update them when they carry new ones.
+    fn metadata(&self) -> Tags;
+
+    fn duration(&self) -> Option<Duration> {
+        let rate = self.spec()?.sample_rate;
+        Some(Duration::from_secs_f64(self.frames()? as f64 / rate as f64))
+    }
+}
+
+/// Path that reads from stdin.
+pub const STDIN_PATH: &str = "-";
+
+/// Probe hint from the extension of `p`.
+pub fn path_hint(p: &Path) -> Hint {
+    let mut hint = Hint::new();
+    if let Some(ext) = p.extension().and_then(|e| e.to_str()) {
+        hint.with_extension(ext);
+    }
+    hint
+}
+
+/// Audio track of a container as listed to the user.
+#[derive(Clone, Debug)]
+pub struct AudioTrack {
+    pub id: u32,
+    pub codec: String,
+    pub channel: Option<u32>,
+    pub sample_rate: Option<u32>,
+    pub language: Option<String>,
+    /// the track played when none is selected
+    pub default: bool,
+}
+
+impl AudioTrack {
+    fn dsd(spec: MediaSpec) -> Self {
+        Self {
+            id: 0,
+            codec: "dsd".to_owned(),
+            channel: Some(spec.channel),
+            sample_rate: Some(spec.sample_rate),
+            language: None,
+            default: true,
+        }
+    }
+}
+
+impl Display for AudioTrack {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "#{} {}", self.id, self.codec)?;
+        if let Some(channel) = self.channel {
+            write!(f, " {channel}ch")?;
+        }
+        if let Some(rate) = self.sample_rate {
+            write!(f, " {rate}Hz")?;
+        }
+        if let Some(language) = &self.language {
+            write!(f, " [{language}]")?;
+        }
+        if self.default {
+            write!(f, " (default)")?;
+        }
+        Ok(())
+    }
+}
+
+/// A whole file or a part of it, e.g. one track of a cue sheet.
+#[derive(Clone, Debug)]
+pub struct TrackRange {
+    pub path: PathBuf,
+    pub start: Duration,
+    /// none plays to the end of the file
+    pub end: Option<Duration>,
+}
+
+impl From<PathBuf> for TrackRange {
+    fn from(path: PathBuf) -> Self {
+        Self {
+            path,
+            start: Duration::ZERO,
+            end: None,
+        }
+    }
 }
 
 #[derive(Default)]
 pub struct DecoderManager {
     decoder: Option<Box<dyn Decoder>>,
+    /// tracks to play after the current one
+    queue: VecDeque<TrackRange>,
+    /// decoder of the next track, opened before the current one ends
+    next: Option<Box<dyn Decoder>>,
+    /// the current decoder was replaced by the next one without an EOF
+    track_changed: bool,
+    /// album gain of the previous track, decides the auto gain mode
+    prev_album_gain: Option<f32>,
+    /// audio track picked in every opened file instead of the default one
+    track_id: Option<u32>,
+    /// format of stdin and network streams
+    format_hint: Option<String>,
+    /// failed decodes in a row of the current track
+    failures: u32,
+    /// failures in a row before a track is abandoned, `DEFAULT_ERROR_BUDGET` when none
+    error_budget: Option<u32>,
+    /// receives stream titles of network sources
+    events: Option<Sender<PlayerEvent>>,
 }
 
 impl DecoderManager {
-    pub fn open(&mut self, p: PathBuf) -> Result<()> {
-        let mut file = std::fs::File::open(&p)?;
-        let is_dsd_file = Self::is_dsd_file(&mut file)?;
-        file.seek(std::io::SeekFrom::Start(0))?;
+    pub fn open(&mut self, track: impl Into<TrackRange>) -> Result<()> {
+        let decoder = self.open_decoder(&track.into())?;
+        self.decoder.replace(decoder);
+        Ok(())
+    }
 
-        let decoder: Box<dyn Decoder> = if is_dsd_file {
-            Box::new(DsdReader::new(file)?)
+    /// Play audio track `id` of this and every following file, `None` for the default track.
+    pub fn set_track_id(&mut self, id: Option<u32>) {
+        self.track_id = id;
+    }
+
+    pub fn set_error_budget(&mut self, budget: u32) {
+        self.error_budget = Some(budget.max(1));
+    }
+
+    pub fn set_event_sender(&mut self, events: Sender<PlayerEvent>) {
+        self.events = Some(events);
+    }
+
+    /// Format of stdin, e.g. `flac`, which can not be told by an extension.
+    pub fn set_format_hint(&mut self, format: Option<String>) {
+        self.format_hint = format;
+    }
+
+    fn open_decoder(&self, track: &TrackRange) -> Result<Box<dyn Decoder>> {
+        let p = track.path.as_path();
+        let decoder = if p == Path::new(STDIN_PATH) {
+            let mut hint = Hint::new();
+            if let Some(format) = &self.format_hint {
+                hint.with_extension(format);
+            }
+            Self::source_decoder(Box::new(ReadOnlySource::new(std::io::stdin())), &hint, self.track_id)?
+        } else if is_url(p) {
+            let url = p.to_str().ok_or(anyhow!("invalid url"))?;
+            let (source, mut hint) = HttpSource::open(url, self.events.clone())?;
+            if let Some(format) = &self.format_hint {
+                hint.with_extension(format);
+            }
+            Self::source_decoder(Box::new(source), &hint, self.track_id)?
         } else {
-            Box::new(PcmDecoder::new(file, &p)?)
+            Self::source_decoder(Box::new(std::fs::File::open(p)?), &path_hint(p), self.track_id)?
         };
 
-        self.decoder.replace(decoder);
-        Ok(())
+        if track.start.is_zero() && track.end.is_none() {
+            return Ok(decoder);
+        }
+
+        Ok(Box::new(RangeDecoder::new(decoder, track.start, track.end)?))
     }
 
-    fn is_dsd_file(r: &mut std::fs::File) -> Result<bool> {
-        let mut buf = [0u8; 4];
-        r.read_exact(&mut buf)?;
-        Ok(&buf == b"DSD ")
+    /// Decoder of any source, e.g. a file, a pipe or a network stream. `hint` helps to probe
+    /// the format, dsd containers are recognized by their magic.
+    fn source_decoder(source: Box<dyn MediaSource>, hint: &Hint, track_id: Option<u32>) -> Result<Box<dyn Decoder>> {
+        let mut source = BufferedSource::new(source);
+        let container = Self::sniff(&source.peek(16)?);
+
+        let decoder: Box<dyn Decoder> = match container {
+            Container::Dsf => Box::new(DsdReader::new(source)?),
+            Container::Dff => Box::new(DffReader::new(source)?),
+            Container::Other => Box::new(PcmDecoder::new(Box::new(source), hint, track_id, false)?),
+        };
+
+        Ok(decoder)
+    }
+
+    pub fn enqueue(&mut self, track: impl Into<TrackRange>) {
+        self.queue.push_back(track.into());
+    }
+
+    /// Open the decoder of the next playable track in queue.
+    fn preload(&mut self) {
+        while self.next.is_none() && let Some(track) = self.queue.pop_front() {
+            match self.open_decoder(&track) {
+                Ok(decoder) => self.next = Some(decoder),
+                Err(e) => {
+                    let e = DecoderError::Open(format!("{}: {e}", track.path.display()));
+                    if let Some(events) = &self.events {
+                        let _ = events.send(PlayerEvent::TrackAbandoned(e));
+                    }
+                },
+            }
+        }
     }
+
+    /// Switch to the next track after an EOF whose spec differs from the current one.
+    pub fn next_track(&mut self) -> bool {
+        self.preload();
+        match self.next.take() {
+            Some(next) => {
+                self.prev_album_gain = self.replay_gain().album_gain;
+                self.decoder.replace(next);
+                self.failures = 0;
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// The current track shares its album gain with the track before or after it.
+    pub fn in_album(&mut self) -> bool {
+        self.preload();
+        let album = self.replay_gain().album_gain;
+        let next = self.next.as_ref().map(|n| n.replay_gain().album_gain);
+        album.is_some() && (self.prev_album_gain == album || next == Some(album))
+    }
+
+    /// True once after the decoder switched to the next track gaplessly.
+    pub fn take_track_change(&mut self) -> bool {
+        std::mem::take(&mut self.track_changed)
+    }
+
+    fn sniff(head: &[u8]) -> Container {
+        // 'FRM8' + size + 'DSD ' for dsdiff, 'DSD ' for dsf
+        if head.starts_with(b"DSD ") {
+            Container::Dsf
+        } else if head.starts_with(b"FRM8") && head.get(12..16) == Some(b"DSD ") {
+            Container::Dff
+        } else {
+            Container::Other
+        }
+    }
+}
+
+enum Container {
+    Dsf,
+    Dff,
+    Other,
 }
 
 impl Decoder for DecoderManager {
@@ -89,53 +364,236 @@ impl Decoder for DecoderManager {
     }
 
     fn decode(&mut self, buf: &mut VecDeque<i32>) -> Result<(), DecoderError> {
+        self.preload();
+
+        let Some(decoder) = self.decoder.as_mut() else {
+            return Ok(());
+        };
+
+        match decoder.decode(buf) {
+            Err(DecoderError::EOF) => {
+                // same spec, splice the next track without touching the device
+                match self.next.take() {
+                    Some(next) if next.spec() == decoder.spec() => {
+                        self.prev_album_gain = decoder.replay_gain().album_gain;
+                        self.decoder.replace(next);
+                        self.track_changed = true;
+                        Ok(())
+                    },
+                    next => {
+                        self.next = next;
+                        Err(DecoderError::EOF)
+                    },
+                }
+            },
+            Ok(()) => {
+                self.failures = 0;
+                Ok(())
+            },
+            // a single bad packet or read is skipped, a run of them gives the track up
+            Err(e @ (DecoderError::Io(_) | DecoderError::Corrupt(_))) => {
+                self.failures += 1;
+                if self.failures < self.error_budget.unwrap_or(DEFAULT_ERROR_BUDGET) {
+                    return Err(e);
+                }
+
+                self.failures = 0;
+                Err(DecoderError::Abandoned(Box::new(e)))
+            },
+            Err(e @ (DecoderError::Unsupported(_) | DecoderError::Reset(_))) => {
+                self.failures = 0;
+                Err(DecoderError::Abandoned(Box::new(e)))
+            },
+            r => r,
+        }
+    }
+
+    fn seek(&mut self, pos: Duration) -> Result<(), DecoderError> {
         if let Some(decoder) = self.decoder.as_mut() {
-            decoder.decode(buf)?;
+            decoder.seek(pos)?;
         }
 
         Ok(())
     }
+
+    #[inline]
+    fn frames(&self) -> Option<u64> {
+        self.decoder.as_ref().and_then(|d| d.frames())
+    }
+
+    fn replay_gain(&self) -> ReplayGain {
+        self.decoder.as_ref().map(|d| d.replay_gain()).unwrap_or_default()
+    }
+
+    fn tracks(&self) -> Vec<AudioTrack> {
+        self.decoder.as_ref().map(|d| d.tracks()).unwrap_or_default()
+    }
+
+    fn metadata(&self) -> Tags {
+        self.decoder.as_ref().map(|d| d.metadata()).unwrap_or_default()
+    }
+}
+
+/// Plays `start..end` of another decoder, seeks are relative to `start`.
+struct RangeDecoder {
+    inner: Box<dyn Decoder>,
+    start: Duration,
+    /// frames from start to the end of the range, or of the file when it has no end
+    frames: Option<u64>,
+    /// the range stops before the end of the file
+    bounded: bool,
+    /// frames left before the end of a bounded range
+    frames_left: u64,
+}
+
+impl RangeDecoder {
+    fn new(mut inner: Box<dyn Decoder>, start: Duration, end: Option<Duration>) -> Result<Self> {
+        let spec = inner.spec().ok_or(anyhow!("unknown codec"))?;
+        if !start.is_zero() {
+            inner.seek(start)?;
+        }
+
+        let to_frames = |d: Duration| (d.as_secs_f64() * spec.sample_rate as f64).round() as u64;
+        let frames = match end {
+            Some(end) => Some(to_frames(end).saturating_sub(to_frames(start))),
+            None => inner.frames().map(|f| f.saturating_sub(to_frames(start))),
+        };
+
+        Ok(Self {
+            inner,
+            start,
+            frames,
+            bounded: end.is_some(),
+            frames_left: frames.unwrap_or(0),
+        })
+    }
+}
+
+impl Decoder for RangeDecoder {
+    fn decode(&mut self, buf: &mut VecDeque<i32>) -> Result<(), DecoderError> {
+        if self.bounded && self.frames_left == 0 {
+            return Err(DecoderError::EOF);
+        }
+
+        let from = buf.len();
+        let decoded = self.inner.decode(buf);
+        if !self.bounded {
+            return decoded;
+        }
+
+        let Some(spec) = self.inner.spec() else {
+            return decoded;
+        };
+        let channel = spec.channel as usize;
+        // a dsd word carries 32 frames of one channel
+        let per_sample = if spec.mode == crate::media::OutputMode::DSD { 32 } else { 1 };
+
+        let frames = ((buf.len() - from) / channel) as u64 * per_sample;
+        if frames >= self.frames_left {
+            buf.truncate(from + self.frames_left.div_ceil(per_sample) as usize * channel);
+            self.frames_left = 0;
+        } else {
+            self.frames_left -= frames;
+        }
+
+        decoded
+    }
+
+    fn spec(&self) -> Option<MediaSpec> {
+        self.inner.spec()
+    }
+
+    fn seek(&mut self, pos: Duration) -> Result<(), DecoderError> {
+        self.inner.seek(self.start + pos)?;
+        if let Some(spec) = self.inner.spec() {
+            let pos = (pos.as_secs_f64() * spec.sample_rate as f64).round() as u64;
+            self.frames_left = self.frames.unwrap_or(0).saturating_sub(pos);
+        }
+        Ok(())
+    }
+
+    fn frames(&self) -> Option<u64> {
+        self.frames
+    }
+
+    fn replay_gain(&self) -> ReplayGain {
+        self.inner.replay_gain()
+    }
+
+    fn tracks(&self) -> Vec<AudioTrack> {
+        self.inner.tracks()
+    }
+
+    fn metadata(&self) -> Tags {
+        self.inner.metadata()
+    }
 }
 
 pub struct PcmDecoder {
     format: Box<dyn FormatReader>,
     track_id: u32,
     decoder: Box<dyn symphonia::core::codecs::Decoder>,
+    /// timestamp asked by the last seek, samples before it are dropped
+    seek_ts: Option<u64>,
+    /// encoder delay from iTunSMPB, frames still to drop from the start
+    start_trim: u64,
+    /// frame count without encoder delay and padding from iTunSMPB
+    smpb_frames: Option<u64>,
+    /// frames left before the iTunSMPB padding starts
+    frames_left: Option<u64>,
+    replay_gain: ReplayGain,
+    tags: Tags,
+    /// packets dropped because they could not be read or decoded
+    skipped_packets: u64,
+    /// audio track the user picked, kept when the track list resets
+    selected: Option<u32>,
+    dec_opts: DecoderOptions,
 }
 
 impl PcmDecoder {
-    fn new(src: std::fs::File, p: &Path) -> Result<Self> {
+    /// Decode audio track `track_id` of the file, or the default one.
+    /// `verify` lets the codec check the decoded samples against its checksum, see `finalize`.
+    pub fn new(src: Box<dyn MediaSource>, hint: &Hint, track_id: Option<u32>, verify: bool) -> Result<Self> {
         // Create the media source stream.
-        let mss = MediaSourceStream::new(Box::new(src), Default::default());
+        let mss = MediaSourceStream::new(src, Default::default());
 
-        // Create a probe hint using the file's extension. [Optional]
-        let mut hint = Hint::new();
-        if let Some(ext) = p.extension() {
-            if let Some(ext) = ext.to_str() {
-                hint.with_extension(ext);
-            }
-        }
-
-        // Use the default options for metadata and format readers.
+        // Use the default options for metadata, let format readers trim encoder delay and padding.
         let meta_opts = MetadataOptions::default();
-        let fmt_opts = FormatOptions::default();
+        let fmt_opts = FormatOptions {
+            enable_gapless: true,
+            ..Default::default()
+        };
 
         // Probe the media source.
-        let probed = symphonia::default::get_probe()
-            .format(&hint, mss, &fmt_opts, &meta_opts)
+        let mut probed = symphonia::default::get_probe()
+            .format(hint, mss, &fmt_opts, &meta_opts)
             .map_err(|e| anyhow::anyhow!(e.to_string()))?;
 
-        // Get the instantiated format reader.
-        let format = probed.format;
+        let probed_rev = probed.metadata.get().and_then(|m| m.current().cloned());
+        Self::from_format(probed.format, probed_rev, track_id, verify)
+    }
 
-        let track = format
-            .tracks()
-            .iter()
-            .find(|t| t.codec_params.codec != CODEC_TYPE_NULL)
-            .ok_or(anyhow!("no supported audio tracks"))?;
+    /// Decode audio track `track_id` of an opened format reader, `probed_rev` holds tags
+    /// found in front of the container, e.g. id3v2 before flac.
+    fn from_format(
+        mut format: Box<dyn FormatReader>,
+        probed_rev: Option<MetadataRevision>,
+        track_id: Option<u32>,
+        verify: bool,
+    ) -> Result<Self> {
+        // iTunSMPB and ReplayGain live in the tags
+        let format_rev = format.metadata().current().cloned();
+        let revs = || probed_rev.iter().chain(format_rev.iter());
+
+        let smpb = revs().find_map(Self::itunsmpb);
+        let mut replay_gain = ReplayGain::default();
+        revs().for_each(|rev| Self::read_replay_gain(rev, &mut replay_gain));
+        let mut tags = Tags::default();
+        revs().for_each(|rev| tags.update(Tags::from_revision(rev)));
 
-        // Use the default options for the decoder.
-        let dec_opts = DecoderOptions::default();
+        let selected = track_id;
+        let track = Self::select_track(format.as_ref(), selected)?;
+        let dec_opts = DecoderOptions { verify };
 
         // Create a decoder for the track.
         let decoder = symphonia::default::get_codecs()
@@ -143,14 +601,117 @@ impl PcmDecoder {
 
         // Store the track identifier and decoder
         let track_id = track.id;
-        let decoder = decoder;
+
+        // the format reader already trims what it knows about, iTunSMPB is only the fallback
+        let (start_trim, smpb_frames) = match smpb {
+            Some((delay, frames)) if track.codec_params.delay.is_none() => (delay, Some(frames)),
+            _ => (0, None),
+        };
 
         Ok(Self {
             format,
             track_id,
             decoder,
+            seek_ts: None,
+            start_trim,
+            smpb_frames,
+            frames_left: smpb_frames,
+            replay_gain,
+            tags,
+            skipped_packets: 0,
+            selected,
+            dec_opts,
         })
     }
+
+    pub fn skipped_packets(&self) -> u64 {
+        self.skipped_packets
+    }
+
+    /// Checksum result after the last packet, e.g. the flac STREAMINFO md5.
+    /// None when the decoder was not verifying or the stream has no checksum.
+    pub fn finalize(&mut self) -> Option<bool> {
+        self.decoder.finalize().verify_ok
+    }
+
+    fn default_track(format: &dyn FormatReader) -> Option<&Track> {
+        format
+            .default_track()
+            .filter(|t| t.codec_params.codec != CODEC_TYPE_NULL)
+            .or_else(|| format.tracks().iter().find(|t| t.codec_params.codec != CODEC_TYPE_NULL))
+    }
+
+    /// Audio track `id`, or the default one when none was picked.
+    fn select_track(format: &dyn FormatReader, id: Option<u32>) -> Result<&Track> {
+        match id {
+            Some(id) => format
+                .tracks()
+                .iter()
+                .find(|t| t.id == id && t.codec_params.codec != CODEC_TYPE_NULL)
+                .ok_or(anyhow!("no audio track {id}")),
+            None => Self::default_track(format).ok_or(anyhow!("no supported audio tracks")),
+        }
+    }
+
+    /// Rebuild the decoder after the track list changed, e.g. the next link of a chained ogg.
+    fn reset_track(&mut self) -> Result<()> {
+        let track = Self::select_track(self.format.as_ref(), self.selected)?;
+
+        self.decoder = symphonia::default::get_codecs()
+            .make(&track.codec_params, &self.dec_opts)?;
+        self.track_id = track.id;
+
+        // gapless info and seek state belong to the previous link
+        self.seek_ts = None;
+        self.start_trim = 0;
+        self.smpb_frames = None;
+        self.frames_left = None;
+        Ok(())
+    }
+
+    /// Parse iTunSMPB, returns encoder delay and the frame count without delay and padding.
+    /// ` 00000000 00000840 000001CA 00000000003F31F6 ...`
+    fn itunsmpb(rev: &MetadataRevision) -> Option<(u64, u64)> {
+        let tag = rev
+            .tags()
+            .iter()
+            .find(|t| t.key.to_lowercase().ends_with("itunsmpb"))?;
+
+        let value = tag.value.to_string();
+        let mut fields = value.split_whitespace().map(|f| u64::from_str_radix(f, 16));
+        let _reserved = fields.next()?;
+        let delay = fields.next()?.ok()?;
+        let _padding = fields.next()?;
+        let frames = fields.next()?.ok()?;
+
+        (frames > 0).then_some((delay, frames))
+    }
+
+    /// ReplayGain and R128 tags, later revisions override earlier ones.
+    fn read_replay_gain(rev: &MetadataRevision, gain: &mut ReplayGain) {
+        for tag in rev.tags() {
+            // mp4 freeform and id3 TXXX keys carry a prefix, e.g. `----:com.apple.iTunes:replaygain_track_gain`
+            let key = match tag.std_key {
+                Some(StandardTagKey::ReplayGainTrackGain) => "REPLAYGAIN_TRACK_GAIN",
+                Some(StandardTagKey::ReplayGainTrackPeak) => "REPLAYGAIN_TRACK_PEAK",
+                Some(StandardTagKey::ReplayGainAlbumGain) => "REPLAYGAIN_ALBUM_GAIN",
+                Some(StandardTagKey::ReplayGainAlbumPeak) => "REPLAYGAIN_ALBUM_PEAK",
+                _ => tag.key.rsplit(':').next().unwrap_or(&tag.key),
+            };
+            gain.set_tag(key, &tag.value.to_string());
+        }
+    }
+
+    fn ts_to_frames(&self, ts: u64) -> u64 {
+        let params = self.decoder.codec_params();
+        match (params.time_base, params.sample_rate) {
+            (Some(tb), Some(rate)) => {
+                let time = tb.calc_time(ts);
+                time.seconds * rate as u64 + (time.frac * rate as f64) as u64
+            },
+            _ => ts,
+        }
+    }
 }
 
 impl Decoder for PcmDecoder {
@@ -159,6 +720,7 @@ impl Decoder for PcmDecoder {
         Some(MediaSpec {
             sample_rate: params.sample_rate?,
             channel: params.channels.map(|c| c.count() as u32)?,
+            bits: params.bits_per_sample,
             mode: crate::media::OutputMode::PCM,
         })
     }
@@ -168,25 +730,32 @@ impl Decoder for PcmDecoder {
         let packet = match self.format.next_packet() {
             Ok(packet) => packet,
             Err(Error::ResetRequired) => {
-                // The track list has been changed. Re-examine it and create a new set of decoders,
-                // then restart the decode loop. This is an advanced feature and it is not
-                // unreasonable to consider this "the end." As of v0.5.0, the only usage of this is
-                // for chained OGG physical streams.
-                return Err(DecoderError::Ignored);
-            }
-            Err(Error::IoError(err)) if err.kind() == std::io::ErrorKind::UnexpectedEof => {
-                return Err(DecoderError::EOF)
-            },
-            Err(err) => {
-                // A unrecoverable error occurred, halt decoding.
-                return Err(DecoderError::Raw(err.to_string()))
+                // The track list has been changed. Re-examine it and create a new decoder.
+                // As of v0.5.0, the only usage of this is for chained OGG physical streams.
+                let spec = self.spec();
+                self.reset_track().map_err(|e| DecoderError::Reset(e.to_string()))?;
+
+                return if self.spec() == spec {
+                    Err(DecoderError::Ignored)
+                } else {
+                    Err(DecoderError::SpecChanged)
+                };
             }
+            Err(err) => return Err(err.into()),
         };
 
-        // Consume any new metadata that has been read since the last packet.
+        // Consume any new metadata that has been read since the last packet,
+        // e.g. the comments of the next chained ogg link.
+        let mut changed = false;
         while !self.format.metadata().is_latest() {
-            // Pop the old head of the metadata queue.
             self.format.metadata().pop();
+            changed = true;
+        }
+        if changed && let Some(rev) = self.format.metadata().current() {
+            self.tags.update(Tags::from_revision(rev));
+            // the gain of the previous link does not carry over
+            self.replay_gain = ReplayGain::default();
+            Self::read_replay_gain(rev, &mut self.replay_gain);
         }
 
         // If the packet does not belong to the selected track, skip over it.
@@ -194,10 +763,22 @@ impl Decoder for PcmDecoder {
             return Err(DecoderError::Ignored);
         }
 
+        // accurate seek lands on a packet before the requested timestamp
+        let mut skip = 0;
+        if let Some(required) = self.seek_ts {
+            if packet.ts() + packet.dur() <= required {
+                return Err(DecoderError::Ignored);
+            }
+
+            skip = self.ts_to_frames(required.saturating_sub(packet.ts()));
+            self.seek_ts = None;
+        }
+
         match self.decoder.decode(&packet) {
             Ok(_decoded) => {
                 // Consume the decoded audio samples (see below).
                 let duration = _decoded.capacity() as u64;
+                let duration_frames = _decoded.frames();
                 let spec = _decoded.spec().to_owned();
                 let mut sb: SampleBuffer<i32> = SampleBuffer::new(duration, spec);
                 match _decoded {
@@ -211,40 +792,144 @@ impl Decoder for PcmDecoder {
                     }
                 }
 
+                let channels = spec.channels.count();
                 let data = sb.samples();
-                buf.extend(data);
+
+                // decoders which do not trim by themselves hand out the whole block
+                let (head, tail) = if duration_frames as u64 == packet.block_dur() {
+                    (packet.trim_start() as usize, packet.trim_end() as usize)
+                } else {
+                    (0, 0)
+                };
+
+                let smpb_trim = std::mem::take(&mut self.start_trim) as usize;
+                let start = ((head + skip as usize + smpb_trim) * channels).min(data.len());
+                let mut end = data.len().saturating_sub(tail * channels).max(start);
+
+                if let Some(left) = self.frames_left.as_mut() {
+                    let frames = ((end - start) / channels) as u64;
+                    end = start + frames.min(*left) as usize * channels;
+                    *left = left.saturating_sub(frames);
+                }
+
+                buf.extend(&data[start..end]);
                 Ok(())
             }
-            Err(Error::IoError(_)) => {
+            Err(Error::IoError(err)) => {
                 // The packet failed to decode due to an IO error, skip the packet.
-                Err(DecoderError::Ignored)
+                self.skipped_packets += 1;
+                Err(DecoderError::Io(err.to_string()))
             }
-            Err(Error::DecodeError(_)) => {
+            Err(Error::DecodeError(err)) => {
                 // The packet failed to decode due to invalid data, skip the packet.
-                Err(DecoderError::Ignored)
+                self.skipped_packets += 1;
+                Err(DecoderError::Corrupt(err.to_owned()))
             }
             Err(err) => {
                 // An unrecoverable error occurred, halt decoding.
-                Err(DecoderError::Raw(err.to_string()))
+                Err(err.into())
             }
         }
     }
+
+    fn seek(&mut self, pos: Duration) -> Result<(), DecoderError> {
+        let seeked = self.format
+            .seek(SeekMode::Accurate, SeekTo::Time { time: pos.into(), track_id: Some(self.track_id) })?;
+
+        self.decoder.reset();
+        self.seek_ts = Some(seeked.required_ts);
+        self.start_trim = 0;
+        self.frames_left = self.smpb_frames
+            .map(|frames| frames.saturating_sub(self.ts_to_frames(seeked.required_ts)));
+        Ok(())
+    }
+
+    fn frames(&self) -> Option<u64> {
+        self.smpb_frames.or(self.decoder.codec_params().n_frames)
+    }
+
+    fn replay_gain(&self) -> ReplayGain {
+        self.replay_gain
+    }
+
+    fn metadata(&self) -> Tags {
+        self.tags.clone()
+    }
+
+    fn tracks(&self) -> Vec<AudioTrack> {
+        let default = Self::default_track(self.format.as_ref()).map(|t| t.id);
+        self.format
+            .tracks()
+            .iter()
+            .filter(|t| t.codec_params.codec != CODEC_TYPE_NULL)
+            .map(|t| {
+                let params = &t.codec_params;
+                let codec = symphonia::default::get_codecs()
+                    .get_codec(params.codec)
+                    .map(|c| c.short_name.to_owned())
+                    .unwrap_or_else(|| params.codec.to_string());
+
+                AudioTrack {
+                    id: t.id,
+                    codec,
+                    channel: params.channels.map(|c| c.count() as u32),
+                    sample_rate: params.sample_rate,
+                    language: t.language.clone(),
+                    default: Some(t.id) == default,
+                }
+            })
+            .collect()
+    }
+}
+
+/// DSD silence pattern, used to pad the last incomplete word of a stream.
+const DSD_SILENCE: u8 = 0x69;
+
+/// Number of DSD bytes packed into one `DSD_U32_LE` word.
+const DSD_WORD_BYTES: usize = 4;
+
+/// Pack per channel DSD bytes (chronological, MSB first) into frame interleaved
+/// `DSD_U32_LE` words: the oldest byte goes into the most significant byte.
+fn pack_dsd_u32(channels: &[&[u8]], buf: &mut VecDeque<i32>) {
+    let len = channels.iter().map(|c| c.len()).min().unwrap_or(0);
+
+    for offset in (0..len).step_by(DSD_WORD_BYTES) {
+        for ch in channels {
+            let mut word = [DSD_SILENCE; DSD_WORD_BYTES];
+            let end = (offset + DSD_WORD_BYTES).min(len);
+            word[..end - offset].copy_from_slice(&ch[offset..end]);
+            buf.push_back(u32::from_be_bytes(word) as i32);
+        }
+    }
 }
 
+/// Largest id3 chunk read from a dsf file, enough for a tag with cover art.
+const DSF_METADATA_MAX: u64 = 16 * 1024 * 1024;
+
 pub struct DsdReader {
     spec: MediaSpec,
     metadata: id3::Tag,
     dsd_chunk_size: u64,
     fmt_chunk_size: u64,
-    data_chunk_size: u64,
-    reader: std::fs::File,
-    size: u64,
+    /// block size per channel, 4096 for every known encoder
+    block_size: usize,
+    /// dsd samples per channel
+    sample_count: u64,
+    /// byte count of real dsd data per channel, without the padding of the last block
+    channel_data_size: u64,
+    /// byte count per channel already read from the data chunk
+    channel_read: u64,
+    /// bytes per channel to drop from the next block after a seek
+    skip: usize,
+    /// dsf stores 1 bit samples LSB first, 8 bit samples MSB first
+    lsb_first: bool,
+    block: Vec<u8>,
+    reader: BufferedSource,
 }
 
 impl DsdReader {
-    pub fn new(mut reader: std::fs::File) -> Result<Self> {
+    pub fn new(mut reader: BufferedSource) -> Result<Self> {
         let mut u32_buf = [0u8; 4];
-        let mut u64_buf = [0u8; 8];
         let mut dsd_chunk_size_buf = [0u8; 8];
         let mut fmt_chunk_size_buf = [0u8; 8];
         let mut data_chunk_size_buf = [0u8; 8];
@@ -252,6 +937,9 @@ impl DsdReader {
         let mut metadata_pot_buf = [0u8; 8];
         let mut channel_num_buf = [0u8; 4];
         let mut sample_freq_buf =  [0u8; 4];
+        let mut bit_per_sample_buf = [0u8; 4];
+        let mut sample_count_buf = [0u8; 8];
+        let mut block_size_buf = [0u8; 4];
 
         // 'DSD '
         reader.read_exact(&mut u32_buf)?;
@@ -276,11 +964,11 @@ impl DsdReader {
         // sampleling frequency
         reader.read_exact(&mut sample_freq_buf)?;
         // bit per sample
-        reader.read_exact(&mut u32_buf)?;
+        reader.read_exact(&mut bit_per_sample_buf)?;
         // sample count
-        reader.read_exact(&mut u64_buf)?;
+        reader.read_exact(&mut sample_count_buf)?;
         // block size per channel
-        reader.read_exact(&mut u32_buf)?;
+        reader.read_exact(&mut block_size_buf)?;
         // reserved
         reader.read_exact(&mut u32_buf)?;
         // 'data'
@@ -293,25 +981,43 @@ impl DsdReader {
         let fmt_chunk_size = u64::from_le_bytes(fmt_chunk_size_buf);
         let data_chunk_size = u64::from_le_bytes(data_chunk_size_buf);
         let file_size = u64::from_le_bytes(file_size_buf);
-        let dsd_size = dsd_chunk_size + fmt_chunk_size + data_chunk_size;
-        if dsd_size > file_size {
+        let dsd_size = dsd_chunk_size
+            .checked_add(fmt_chunk_size)
+            .and_then(|size| size.checked_add(data_chunk_size))
+            .ok_or(anyhow!("dsd file parser error"))?;
+        if dsd_size > file_size || data_chunk_size < 12 {
             return Err(anyhow!("dsd file parser error"));
         }
 
-        let metadata_size = file_size - dsd_size;
-        reader.seek(SeekFrom::Start(metadata_pot))?;
+        let channel = u32::from_le_bytes(channel_num_buf);
+        let block_size = u32::from_le_bytes(block_size_buf) as usize;
+        if channel == 0 || block_size == 0 || !block_size.is_multiple_of(DSD_WORD_BYTES) {
+            return Err(anyhow!("dsd file parser error"));
+        }
 
-        let mut metadata = vec![0u8; metadata_size as usize];
-        reader.read_exact(&mut metadata)?;
+        // a zero pointer means the file has no metadata chunk, a stream can not come back from the end
+        let metadata = if metadata_pot != 0 && metadata_pot < file_size && reader.is_seekable() {
+            reader.seek(SeekFrom::Start(metadata_pot))?;
+
+            // the header's file size is not trusted, the tag ends with the file or the limit
+            let mut metadata = Vec::new();
+            reader.by_ref().take((file_size - metadata_pot).min(DSF_METADATA_MAX)).read_to_end(&mut metadata)?;
+            id3::v1v2::read_from(Cursor::new(metadata)).unwrap_or_default()
+        } else {
+            id3::Tag::new()
+        };
 
-        let metadata = id3::v1v2::read_from(Cursor::new(metadata))?;
         let spec = MediaSpec {
             sample_rate: u32::from_le_bytes(sample_freq_buf),
-            channel: u32::from_le_bytes(channel_num_buf),
+            channel,
+            bits: Some(1),
             mode: crate::media::OutputMode::DSD,
         };
 
-        println!("{:?}", spec);
+        // sample count is per channel and in bits, the rest of the last block is padding
+        let sample_count = u64::from_le_bytes(sample_count_buf);
+        let blocks_per_channel = (data_chunk_size - 12) / (block_size as u64 * channel as u64);
+        let channel_data_size = sample_count.div_ceil(8).min(blocks_per_channel * block_size as u64);
 
         // reset reader to data position
         reader.seek(SeekFrom::Start(dsd_chunk_size + fmt_chunk_size + 12))?;
@@ -321,19 +1027,844 @@ impl DsdReader {
             metadata,
             dsd_chunk_size,
             fmt_chunk_size,
-            data_chunk_size,
+            block_size,
+            sample_count,
+            channel_data_size,
+            channel_read: 0,
+            skip: 0,
+            lsb_first: u32::from_le_bytes(bit_per_sample_buf) == 1,
+            block: vec![0u8; block_size * channel as usize],
             reader,
-            size: file_size - 12,
         })
     }
 }
 
 impl Decoder for DsdReader {
-    fn decode(&mut self, _buf: &mut VecDeque<i32>) -> Result<(), DecoderError> {
-        todo!()
+    fn decode(&mut self, buf: &mut VecDeque<i32>) -> Result<(), DecoderError> {
+        if self.channel_read >= self.channel_data_size {
+            return Err(DecoderError::EOF);
+        }
+
+        // one block group holds a full block of every channel, one after another
+        self.reader.read_exact(&mut self.block)?;
+
+        let valid = (self.channel_data_size - self.channel_read).min(self.block_size as u64) as usize;
+        self.channel_read += self.block_size as u64;
+
+        if self.lsb_first {
+            self.block.iter_mut().for_each(|b| *b = b.reverse_bits());
+        }
+
+        let skip = std::mem::take(&mut self.skip).min(valid);
+        let channels: Vec<&[u8]> = self.block
+            .chunks_exact(self.block_size)
+            .map(|c| &c[skip..valid])
+            .collect();
+
+        pack_dsd_u32(&channels, buf);
+        Ok(())
+    }
+
+    fn seek(&mut self, pos: Duration) -> Result<(), DecoderError> {
+        let byte = (pos.as_secs_f64() * self.spec.sample_rate as f64 / 8.0) as u64;
+        let byte = byte.min(self.channel_data_size);
+        let block = byte / self.block_size as u64;
+        let data_pos = self.dsd_chunk_size + self.fmt_chunk_size + 12;
+        let offset = block * self.block_size as u64 * self.spec.channel as u64;
+
+        self.reader.seek(SeekFrom::Start(data_pos + offset))?;
+        self.channel_read = block * self.block_size as u64;
+        self.skip = (byte - self.channel_read) as usize;
+        Ok(())
+    }
+
+    fn frames(&self) -> Option<u64> {
+        Some(self.sample_count)
+    }
+
+    fn replay_gain(&self) -> ReplayGain {
+        ReplayGain::from_id3(&self.metadata)
+    }
+
+    fn tracks(&self) -> Vec<AudioTrack> {
+        vec![AudioTrack::dsd(self.spec)]
+    }
+
+    fn metadata(&self) -> Tags {
+        Tags::from_id3(&self.metadata)
     }
 
     fn spec(&self) -> Option<MediaSpec> {
         Some(self.spec)
     }
 }
+
+/// Philips DSDIFF (.dff) reader. All numbers are big endian, dsd data is byte
+/// interleaved per channel and MSB first.
+pub struct DffReader {
+    spec: MediaSpec,
+    metadata: id3::Tag,
+    /// byte count of the 'DSD ' sound data chunk
+    data_size: u64,
+    /// byte count already read from the sound data chunk
+    data_read: u64,
+    /// file position of the sound data chunk
+    data_pos: u64,
+    /// byte interleaved data, one read per decode call
+    block: Vec<u8>,
+    channel_buf: Vec<Vec<u8>>,
+    reader: BufferedSource,
+}
+
+/// Read size of the data chunk per channel and decode call.
+const DFF_BLOCK_SIZE: usize = 4096;
+
+impl DffReader {
+    pub fn new(mut reader: BufferedSource) -> Result<Self> {
+        // 'FRM8'
+        Self::read_id(&mut reader)?;
+        // size of form chunk
+        let form_size = Self::read_u64(&mut reader)?;
+        // 'DSD '
+        Self::read_id(&mut reader)?;
+
+        // chunks end within the form and the file, whatever their sizes claim
+        let form_end = form_size.checked_add(12).ok_or(anyhow!("dsd file parser error"))?;
+        let form_end = reader.byte_len().map_or(form_end, |len| form_end.min(len));
+        let mut sample_rate = None;
+        let mut channel = None;
+        let mut data = None;
+        let mut metadata = id3::Tag::new();
+
+        let mut pos = 16u64;
+        while pos.saturating_add(12) <= form_end {
+            reader.seek(SeekFrom::Start(pos))?;
+            let id = Self::read_id(&mut reader)?;
+            let size = Self::read_u64(&mut reader)?;
+            let body = pos + 12;
+            let end = Self::chunk_end(body, size, form_end)?;
+
+            match &id {
+                b"PROP" => {
+                    // 'SND '
+                    Self::read_id(&mut reader)?;
+                    let mut prop_pos = body + 4;
+
+                    while prop_pos.saturating_add(12) <= end {
+                        reader.seek(SeekFrom::Start(prop_pos))?;
+                        let id = Self::read_id(&mut reader)?;
+                        let size = Self::read_u64(&mut reader)?;
+                        let prop_end = Self::chunk_end(prop_pos + 12, size, end)?;
+
+                        match &id {
+                            b"FS  " => sample_rate = Some(Self::read_u32(&mut reader)?),
+                            b"CHNL" => {
+                                let mut u16_buf = [0u8; 2];
+                                reader.read_exact(&mut u16_buf)?;
+                                channel = Some(u16::from_be_bytes(u16_buf) as u32);
+                            },
+                            b"CMPR" if &Self::read_id(&mut reader)? != b"DSD " => {
+                                return Err(anyhow!("dst compressed dff is not supported"));
+                            },
+                            _ => {},
+                        }
+
+                        prop_pos = prop_end.saturating_add(size & 1);
+                    }
+                },
+                b"DSD " => {
+                    data = Some((body, size));
+                    // tags after the data are out of reach of a stream
+                    if !reader.is_seekable() {
+                        break;
+                    }
+                },
+                b"DST " => return Err(anyhow!("dst compressed dff is not supported")),
+                b"DIIN" => Self::read_diin(&mut reader, body, end, &mut metadata)?,
+                b"ID3 " => {
+                    let mut tag = Vec::new();
+                    reader.by_ref().take(size).read_to_end(&mut tag)?;
+                    if let Ok(tag) = id3::Tag::read_from2(Cursor::new(tag)) {
+                        // id3 is more complete than DIIN, keep it as the base
+                        let diin = std::mem::replace(&mut metadata, tag);
+                        if metadata.title().is_none() && let Some(title) = diin.title() {
+                            metadata.set_title(title);
+                        }
+                        if metadata.artist().is_none() && let Some(artist) = diin.artist() {
+                            metadata.set_artist(artist);
+                        }
+                    }
+                },
+                _ => {},
+            }
+
+            // chunks are padded to an even length
+            pos = end.saturating_add(size & 1);
+        }
+
+        let (data_pos, data_size) = data.ok_or(anyhow!("dff file has no dsd data"))?;
+        let channel = channel.filter(|c| *c > 0).ok_or(anyhow!("dsd file parser error"))?;
+        let spec = MediaSpec {
+            sample_rate: sample_rate.ok_or(anyhow!("dsd file parser error"))?,
+            channel,
+            bits: Some(1),
+            mode: crate::media::OutputMode::DSD,
+        };
+
+        // reset reader to data position
+        reader.seek(SeekFrom::Start(data_pos))?;
+
+        Ok(Self {
+            spec,
+            metadata,
+            data_size,
+            data_read: 0,
+            data_pos,
+            block: vec![0u8; DFF_BLOCK_SIZE * channel as usize],
+            channel_buf: vec![Vec::with_capacity(DFF_BLOCK_SIZE); channel as usize],
+            reader,
+        })
+    }
+
+    /// End of a chunk of `size` bytes starting at `body`, it has to end before `limit`.
+    fn chunk_end(body: u64, size: u64, limit: u64) -> Result<u64> {
+        body.checked_add(size)
+            .filter(|end| *end <= limit)
+            .ok_or(anyhow!("dsd file parser error"))
+    }
+
+    /// Edited master information, only title and artist are used.
+    fn read_diin(reader: &mut BufferedSource, mut pos: u64, end: u64, tag: &mut id3::Tag) -> Result<()> {
+        while pos.saturating_add(12) <= end {
+            reader.seek(SeekFrom::Start(pos))?;
+            let id = Self::read_id(reader)?;
+            let size = Self::read_u64(reader)?;
+            let chunk_end = Self::chunk_end(pos + 12, size, end)?;
+
+            if matches!(&id, b"DITI" | b"DIAR") {
+                let count = Self::read_u32(reader)?.min(size.saturating_sub(4) as u32);
+                let mut text = Vec::new();
+                reader.by_ref().take(count as u64).read_to_end(&mut text)?;
+                let text = String::from_utf8_lossy(&text);
+
+                if &id == b"DITI" {
+                    tag.set_title(text.trim_end_matches('\0'));
+                } else {
+                    tag.set_artist(text.trim_end_matches('\0'));
+                }
+            }
+
+            pos = chunk_end.saturating_add(size & 1);
+        }
+
+        Ok(())
+    }
+
+    fn read_id(reader: &mut BufferedSource) -> Result<[u8; 4]> {
+        let mut buf = [0u8; 4];
+        reader.read_exact(&mut buf)?;
+        Ok(buf)
+    }
+
+    fn read_u32(reader: &mut BufferedSource) -> Result<u32> {
+        Ok(u32::from_be_bytes(Self::read_id(reader)?))
+    }
+
+    fn read_u64(reader: &mut BufferedSource) -> Result<u64> {
+        let mut buf = [0u8; 8];
+        reader.read_exact(&mut buf)?;
+        Ok(u64::from_be_bytes(buf))
+    }
+}
+
+impl Decoder for DffReader {
+    fn decode(&mut self, buf: &mut VecDeque<i32>) -> Result<(), DecoderError> {
+        let channel = self.spec.channel as usize;
+        let remain = self.data_size - self.data_read;
+        // only whole frames, a trailing partial frame is garbage
+        let len = (self.block.len() as u64).min(remain) as usize / channel * channel;
+        if len == 0 {
+            return Err(DecoderError::EOF);
+        }
+
+        let block = &mut self.block[..len];
+        self.reader.read_exact(block)?;
+        self.data_read += len as u64;
+
+        self.channel_buf.iter_mut().for_each(|c| c.clear());
+        for frame in block.chunks_exact(channel) {
+            for (ch, b) in self.channel_buf.iter_mut().zip(frame) {
+                ch.push(*b);
+            }
+        }
+
+        let channels: Vec<&[u8]> = self.channel_buf.iter().map(|c| c.as_slice()).collect();
+        pack_dsd_u32(&channels, buf);
+        Ok(())
+    }
+
+    fn seek(&mut self, pos: Duration) -> Result<(), DecoderError> {
+        let channel = self.spec.channel as u64;
+        let byte = (pos.as_secs_f64() * self.spec.sample_rate as f64 / 8.0) as u64;
+        let offset = (byte * channel).min(self.data_size / channel * channel);
+
+        self.reader.seek(SeekFrom::Start(self.data_pos + offset))?;
+        self.data_read = offset;
+        Ok(())
+    }
+
+    fn frames(&self) -> Option<u64> {
+        Some(self.data_size / self.spec.channel as u64 * 8)
+    }
+
+    fn replay_gain(&self) -> ReplayGain {
+        ReplayGain::from_id3(&self.metadata)
+    }
+
+    fn tracks(&self) -> Vec<AudioTrack> {
+        vec![AudioTrack::dsd(self.spec)]
+    }
+
+    fn metadata(&self) -> Tags {
+        Tags::from_id3(&self.metadata)
+    }
+
+    fn spec(&self) -> Option<MediaSpec> {
+        Some(self.spec)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use symphonia::core::{
+        audio::Channels,
+        codecs::{CodecParameters, CODEC_TYPE_PCM_S16LE},
+        formats::{Cue, Packet, SeekedTo},
+        meta::{Metadata, MetadataBuilder, MetadataLog, Tag as MetaTag, Value},
+        units::TimeBase,
+    };
+
+    use super::*;
+    use crate::media::OutputMode;
+
+    fn source(bytes: Vec<u8>) -> BufferedSource {
+        BufferedSource::new(Box::new(Cursor::new(bytes)))
+    }
+
+    /// A DSF file of `block_size` byte blocks, `data[ch]` holds the MSB first bytes of each channel.
+    fn dsf(data: &[Vec<u8>], rate: u32, block_size: usize) -> Vec<u8> {
+        let channel = data.len();
+        let len = data[0].len();
+        let blocks = len.div_ceil(block_size);
+
+        let mut body = Vec::new();
+        for block in 0..blocks {
+            for ch in data {
+                let mut chunk = vec![0u8; block_size];
+                let from = block * block_size;
+                let to = (from + block_size).min(len);
+                chunk[..to - from].copy_from_slice(&ch[from..to]);
+                // 1 bit samples are stored LSB first
+                body.extend(chunk.iter().map(|b| b.reverse_bits()));
+            }
+        }
+
+        let mut fmt = Vec::new();
+        fmt.extend(b"fmt ");
+        fmt.extend(52u64.to_le_bytes());
+        for v in [1u32, 0, 2, channel as u32, rate, 1] {
+            fmt.extend(v.to_le_bytes());
+        }
+        fmt.extend((len as u64 * 8).to_le_bytes());
+        fmt.extend((block_size as u32).to_le_bytes());
+        fmt.extend(0u32.to_le_bytes());
+
+        let total = 28 + fmt.len() + 12 + body.len();
+        let mut file = Vec::new();
+        file.extend(b"DSD ");
+        file.extend(28u64.to_le_bytes());
+        file.extend((total as u64).to_le_bytes());
+        file.extend(0u64.to_le_bytes());
+        file.extend(fmt);
+        file.extend(b"data");
+        file.extend((12 + body.len() as u64).to_le_bytes());
+        file.extend(body);
+        file
+    }
+
+    fn dff_chunk(id: &[u8; 4], body: &[u8]) -> Vec<u8> {
+        let mut chunk = id.to_vec();
+        chunk.extend((body.len() as u64).to_be_bytes());
+        chunk.extend(body);
+        if body.len() % 2 == 1 {
+            chunk.push(0);
+        }
+        chunk
+    }
+
+    /// A DSDIFF file with byte interleaved `data` of `channel` channels and a DIIN title.
+    fn dff(data: &[u8], channel: u16, rate: u32, title: &str) -> Vec<u8> {
+        let mut prop = b"SND ".to_vec();
+        prop.extend(dff_chunk(b"FS  ", &rate.to_be_bytes()));
+        let mut chnl = channel.to_be_bytes().to_vec();
+        (0..channel).for_each(|_| chnl.extend(b"SLFT"));
+        prop.extend(dff_chunk(b"CHNL", &chnl));
+        prop.extend(dff_chunk(b"CMPR", b"DSD \x03not"));
+
+        let mut diti = (title.len() as u32).to_be_bytes().to_vec();
+        diti.extend(title.as_bytes());
+
+        let mut form = b"DSD ".to_vec();
+        form.extend(dff_chunk(b"FVER", &[1, 5, 0, 0]));
+        form.extend(dff_chunk(b"PROP", &prop));
+        form.extend(dff_chunk(b"DSD ", data));
+        form.extend(dff_chunk(b"DIIN", &dff_chunk(b"DITI", &diti)));
+        dff_chunk(b"FRM8", &form)
+    }
+
+    /// Every word of `reader` until the end.
+    fn decode_all(reader: &mut dyn Decoder) -> Vec<i32> {
+        let mut buf = VecDeque::new();
+        loop {
+            match reader.decode(&mut buf) {
+                Ok(()) => {},
+                Err(DecoderError::EOF) => return buf.into(),
+                Err(e) => panic!("{e}"),
+            }
+        }
+    }
+
+    /// A mono 16 bit WAV file, every sample holds its own index.
+    fn wav(rate: u32, frames: u16) -> Vec<u8> {
+        let data: Vec<u8> = (0..frames).flat_map(|i| i.to_le_bytes()).collect();
+
+        let mut file = b"RIFF".to_vec();
+        file.extend((36 + data.len() as u32).to_le_bytes());
+        file.extend(b"WAVEfmt ");
+        file.extend(16u32.to_le_bytes());
+        file.extend(1u16.to_le_bytes());
+        file.extend(1u16.to_le_bytes());
+        file.extend(rate.to_le_bytes());
+        file.extend((rate * 2).to_le_bytes());
+        file.extend(2u16.to_le_bytes());
+        file.extend(16u16.to_le_bytes());
+        file.extend(b"data");
+        file.extend((data.len() as u32).to_le_bytes());
+        file.extend(data);
+        file
+    }
+
+    /// The first word decoded after a seek.
+    fn first(reader: &mut dyn Decoder) -> i32 {
+        let mut buf = VecDeque::new();
+        while buf.is_empty() {
+            match reader.decode(&mut buf) {
+                Ok(()) | Err(DecoderError::Ignored) => {},
+                Err(e) => panic!("{e}"),
+            }
+        }
+        buf[0]
+    }
+
+    fn word(bytes: [u8; 4]) -> i32 {
+        u32::from_be_bytes(bytes) as i32
+    }
+
+    #[test]
+    fn pack_dsd_u32_interleaves_and_pads_with_silence() {
+        let left = [1u8, 2, 3, 4, 5];
+        let right = [6u8, 7, 8, 9, 10];
+        let mut buf = VecDeque::new();
+        pack_dsd_u32(&[&left, &right], &mut buf);
+
+        assert_eq!(Vec::from(buf), vec![
+            word([1, 2, 3, 4]),
+            word([6, 7, 8, 9]),
+            word([5, 0x69, 0x69, 0x69]),
+            word([10, 0x69, 0x69, 0x69]),
+        ]);
+    }
+
+    #[test]
+    fn dsf_decodes_without_block_padding() {
+        let left: Vec<u8> = (0..12).collect();
+        let right: Vec<u8> = (0..12).map(|b| 0x80 | b).collect();
+        let mut reader = DsdReader::new(source(dsf(&[left, right], 2822400, 8))).unwrap();
+
+        assert_eq!(reader.spec().unwrap().channel, 2);
+        assert_eq!(reader.frames(), Some(96));
+        assert_eq!(decode_all(&mut reader), vec![
+            word([0, 1, 2, 3]),
+            word([0x80, 0x81, 0x82, 0x83]),
+            word([4, 5, 6, 7]),
+            word([0x84, 0x85, 0x86, 0x87]),
+            word([8, 9, 10, 11]),
+            word([0x88, 0x89, 0x8a, 0x8b]),
+        ]);
+    }
+
+    #[test]
+    fn dsf_data_chunk_bounds_the_sample_count() {
+        let data: Vec<u8> = (0..16).collect();
+        let mut file = dsf(&[data], 2822400, 8);
+        // a sample count beyond the data chunk stops at its end
+        let sample_count_pos = 28 + 36;
+        file[sample_count_pos..sample_count_pos + 8].copy_from_slice(&(1024u64 * 8).to_le_bytes());
+        let mut reader = DsdReader::new(source(file)).unwrap();
+
+        assert_eq!(decode_all(&mut reader).len(), 4);
+    }
+
+    #[test]
+    fn dsf_rejects_overflowing_chunk_sizes() {
+        let file = dsf(&[vec![0; 16]], 2822400, 8);
+        // the dsd chunk size, then the fmt chunk size
+        for at in [4, 32] {
+            let mut file = file.clone();
+            file[at..at + 8].copy_from_slice(&(u64::MAX - 8).to_le_bytes());
+            assert!(DsdReader::new(source(file)).is_err());
+        }
+    }
+
+    #[test]
+    fn dsf_metadata_is_read_up_to_the_real_end() {
+        let mut tag = id3::Tag::new();
+        tag.set_title("Title");
+        let mut id3 = Vec::new();
+        tag.write_to(&mut id3, id3::Version::Id3v23).unwrap();
+
+        let mut file = dsf(&[vec![0; 16]], 2822400, 8);
+        let metadata_pos = file.len() as u64;
+        file.extend(id3);
+        file[20..28].copy_from_slice(&metadata_pos.to_le_bytes());
+        // a file size far beyond the real one must not be allocated
+        file[12..20].copy_from_slice(&(1u64 << 40).to_le_bytes());
+
+        let reader = DsdReader::new(source(file)).unwrap();
+        assert_eq!(reader.metadata().title.as_deref(), Some("Title"));
+    }
+
+    #[test]
+    fn dff_deinterleaves_channels_and_reads_diin() {
+        // left 0..8, right 0x80..0x88, byte interleaved
+        let data: Vec<u8> = (0..8).flat_map(|b| [b, 0x80 | b]).collect();
+        let mut reader = DffReader::new(source(dff(&data, 2, 2822400, "Title"))).unwrap();
+
+        assert_eq!(reader.spec().unwrap().sample_rate, 2822400);
+        assert_eq!(reader.frames(), Some(64));
+        assert_eq!(reader.metadata().title.as_deref(), Some("Title"));
+        assert_eq!(decode_all(&mut reader), vec![
+            word([0, 1, 2, 3]),
+            word([0x80, 0x81, 0x82, 0x83]),
+            word([4, 5, 6, 7]),
+            word([0x84, 0x85, 0x86, 0x87]),
+        ]);
+    }
+
+    #[test]
+    fn dff_rejects_dst() {
+        let mut file = dff(&[0; 8], 2, 2822400, "");
+        let cmpr = file.windows(8).position(|w| w == b"DSD \x03not").unwrap();
+        file[cmpr..cmpr + 4].copy_from_slice(b"DST ");
+        assert!(DffReader::new(source(file)).is_err());
+    }
+
+    /// `file` with the size of the first `id` chunk replaced.
+    fn dff_with_size(mut file: Vec<u8>, id: &[u8; 4], size: u64) -> Vec<u8> {
+        let at = file.windows(4).position(|w| w == id).unwrap() + 4;
+        file[at..at + 8].copy_from_slice(&size.to_be_bytes());
+        file
+    }
+
+    #[test]
+    fn dff_rejects_chunk_sizes_past_the_end() {
+        let file = dff(&[0; 8], 2, 2822400, "Title");
+        for (id, size) in [(b"FRM8", u64::MAX), (b"PROP", u64::MAX - 8), (b"FS  ", u64::MAX), (b"DIIN", 1 << 40), (b"DITI", u64::MAX - 20)] {
+            let e = DffReader::new(source(dff_with_size(file.clone(), id, size)));
+            assert!(e.is_err(), "{}", String::from_utf8_lossy(id));
+        }
+
+        // a data chunk cut short by the end of the file, the 34 bytes of DIIN follow it
+        let len = file.len();
+        assert!(DffReader::new(source(file[..len - 40].to_vec())).is_err());
+    }
+
+    #[test]
+    fn dff_reads_the_id3_chunk() {
+        let mut tag = id3::Tag::new();
+        tag.set_title("From ID3");
+        let mut id3 = Vec::new();
+        tag.write_to(&mut id3, id3::Version::Id3v24).unwrap();
+
+        let mut file = dff(&[0; 8], 2, 2822400, "From DIIN");
+        file.extend(dff_chunk(b"ID3 ", &id3));
+        let form_size = file.len() as u64 - 12;
+        let file = dff_with_size(file, b"FRM8", form_size);
+        let reader = DffReader::new(source(file.clone())).unwrap();
+        assert_eq!(reader.metadata().title.as_deref(), Some("From ID3"));
+
+        // an id3 chunk claiming gigabytes is refused instead of allocated
+        let huge = dff_with_size(file, b"ID3 ", 1 << 32);
+        assert!(DffReader::new(source(huge)).is_err());
+    }
+
+    /// A PCM track of 16 bit mono or stereo, four frames a packet.
+    fn pcm_track(id: u32, rate: u32, channel: usize) -> Track {
+        let channels = match channel {
+            1 => Channels::FRONT_LEFT,
+            _ => Channels::FRONT_LEFT | Channels::FRONT_RIGHT,
+        };
+        let mut params = CodecParameters::new();
+        params
+            .for_codec(CODEC_TYPE_PCM_S16LE)
+            .with_sample_rate(rate)
+            .with_channels(channels)
+            .with_bits_per_sample(16)
+            .with_max_frames_per_packet(4)
+            .with_time_base(TimeBase::new(1, rate));
+        Track::new(id, params)
+    }
+
+    /// Comments of a chained link carrying its track gain.
+    fn gain_rev(gain: Option<&str>) -> MetadataRevision {
+        let mut builder = MetadataBuilder::new();
+        if let Some(gain) = gain {
+            builder.add_tag(MetaTag::new(Some(StandardTagKey::ReplayGainTrackGain), "REPLAYGAIN_TRACK_GAIN", Value::from(gain)));
+        }
+        builder.metadata()
+    }
+
+    /// A chained stream: two packets of every link, then a reset to the next link's tracks
+    /// and comments. Packets belong to the last track of a link and hold its number.
+    struct Chained {
+        tracks: Vec<Track>,
+        links: VecDeque<(Vec<Track>, MetadataRevision)>,
+        metadata: MetadataLog,
+        packets: u64,
+        link: i16,
+    }
+
+    impl Chained {
+        fn new(links: Vec<(Vec<Track>, MetadataRevision)>) -> Self {
+            let mut links = VecDeque::from(links);
+            let (tracks, rev) = links.pop_front().unwrap();
+            let mut metadata = MetadataLog::default();
+            metadata.push(rev);
+            Self { tracks, links, metadata, packets: 0, link: 1 }
+        }
+    }
+
+    impl FormatReader for Chained {
+        fn try_new(_source: MediaSourceStream, _options: &FormatOptions) -> symphonia::core::errors::Result<Self> {
+            unimplemented!()
+        }
+
+        fn cues(&self) -> &[Cue] {
+            &[]
+        }
+
+        fn metadata(&mut self) -> Metadata<'_> {
+            self.metadata.metadata()
+        }
+
+        fn seek(&mut self, _mode: SeekMode, _to: SeekTo) -> symphonia::core::errors::Result<SeekedTo> {
+            Err(Error::Unsupported("seek"))
+        }
+
+        fn tracks(&self) -> &[Track] {
+            &self.tracks
+        }
+
+        fn next_packet(&mut self) -> symphonia::core::errors::Result<Packet> {
+            if self.packets == 2 {
+                let Some((tracks, rev)) = self.links.pop_front() else {
+                    return Err(Error::IoError(std::io::ErrorKind::UnexpectedEof.into()));
+                };
+                self.tracks = tracks;
+                self.metadata.push(rev);
+                self.packets = 0;
+                self.link += 1;
+                return Err(Error::ResetRequired);
+            }
+
+            let track = self.tracks.last().unwrap();
+            let channel = track.codec_params.channels.unwrap().count();
+            let data: Vec<u8> = (0..4 * channel).flat_map(|_| self.link.to_le_bytes()).collect();
+            self.packets += 1;
+            Ok(Packet::new_from_slice(track.id, (self.packets - 1) * 4, 4, &data))
+        }
+
+        fn into_inner(self: Box<Self>) -> MediaSourceStream {
+            unimplemented!()
+        }
+    }
+
+    /// Decode until samples come out, returns the errors seen on the way.
+    fn next_samples(decoder: &mut PcmDecoder) -> (Vec<i32>, Vec<DecoderError>) {
+        let mut errors = Vec::new();
+        let mut buf = VecDeque::new();
+        loop {
+            match decoder.decode(&mut buf) {
+                Ok(()) => return (buf.into(), errors),
+                Err(DecoderError::EOF) => panic!("end of the chain"),
+                Err(e) => errors.push(e),
+            }
+        }
+    }
+
+    #[test]
+    fn chained_links_rebuild_the_decoder() {
+        let chain = Chained::new(vec![
+            (vec![pcm_track(1, 8000, 1)], gain_rev(Some("-3.00 dB"))),
+            (vec![pcm_track(2, 8000, 1)], gain_rev(Some("-6.00 dB"))),
+            (vec![pcm_track(3, 16000, 2)], gain_rev(None)),
+        ]);
+        let mut decoder = PcmDecoder::from_format(Box::new(chain), None, None, true).unwrap();
+        assert_eq!(decoder.replay_gain().track_gain, Some(-3.0));
+        next_samples(&mut decoder);
+        next_samples(&mut decoder);
+
+        // same spec, the next link plays on without reopening the device
+        let (samples, errors) = next_samples(&mut decoder);
+        assert!(matches!(errors[..], [DecoderError::Ignored]));
+        assert_eq!(samples, vec![2 << 16; 4]);
+        assert_eq!(decoder.track_id, 2);
+        assert_eq!(decoder.replay_gain().track_gain, Some(-6.0));
+        assert!(decoder.dec_opts.verify);
+        next_samples(&mut decoder);
+
+        // another rate and channel count, the player renegotiates
+        let (samples, errors) = next_samples(&mut decoder);
+        assert!(matches!(errors[..], [DecoderError::SpecChanged]));
+        assert_eq!(samples, vec![3 << 16; 8]);
+        assert_eq!(decoder.spec().unwrap().sample_rate, 16000);
+        assert_eq!(decoder.spec().unwrap().channel, 2);
+        assert_eq!(decoder.replay_gain(), ReplayGain::default());
+        assert!(decoder.dec_opts.verify);
+    }
+
+    #[test]
+    fn chained_links_keep_the_picked_track() {
+        let chain = Chained::new(vec![
+            (vec![pcm_track(1, 8000, 1), pcm_track(2, 8000, 1)], gain_rev(None)),
+            (vec![pcm_track(1, 44100, 1), pcm_track(2, 8000, 1)], gain_rev(None)),
+            (vec![pcm_track(1, 8000, 1)], gain_rev(None)),
+        ]);
+        let mut decoder = PcmDecoder::from_format(Box::new(chain), None, Some(2), false).unwrap();
+        next_samples(&mut decoder);
+        next_samples(&mut decoder);
+
+        let (_, errors) = next_samples(&mut decoder);
+        assert!(matches!(errors[..], [DecoderError::Ignored]));
+        assert_eq!(decoder.track_id, 2);
+        assert_eq!(decoder.spec().unwrap().sample_rate, 8000);
+        next_samples(&mut decoder);
+
+        // the picked track is gone from the next link
+        let mut buf = VecDeque::new();
+        assert!(matches!(decoder.decode(&mut buf), Err(DecoderError::Reset(e)) if e == "no audio track 2"));
+    }
+
+    /// A file with a cover art stream before two audio tracks.
+    fn multi_track() -> Chained {
+        let mut stereo = pcm_track(3, 16000, 2);
+        stereo.language = Some("eng".to_owned());
+        Chained::new(vec![(vec![Track::new(1, CodecParameters::new()), pcm_track(2, 8000, 1), stereo], gain_rev(None))])
+    }
+
+    #[test]
+    fn tracks_lists_every_audio_track() {
+        let decoder = PcmDecoder::from_format(Box::new(multi_track()), None, None, false).unwrap();
+        let tracks = decoder.tracks();
+
+        let ids: Vec<u32> = tracks.iter().map(|t| t.id).collect();
+        assert_eq!(ids, [2, 3]);
+        // the first audio track plays when none is picked
+        assert!(tracks[0].default && !tracks[1].default);
+        assert_eq!(decoder.track_id, 2);
+        assert_eq!(tracks[0].codec, "pcm_s16le");
+        assert_eq!((tracks[1].channel, tracks[1].sample_rate), (Some(2), Some(16000)));
+        assert_eq!(tracks[1].language.as_deref(), Some("eng"));
+    }
+
+    #[test]
+    fn picked_track_is_played_or_refused() {
+        let mut decoder = PcmDecoder::from_format(Box::new(multi_track()), None, Some(3), false).unwrap();
+        assert_eq!(decoder.track_id, 3);
+        assert_eq!(decoder.spec().unwrap().channel, 2);
+        assert_eq!(next_samples(&mut decoder).0, vec![1 << 16; 8]);
+
+        // a missing or non audio id is an error, not the default track
+        for id in [1, 9] {
+            let e = PcmDecoder::from_format(Box::new(multi_track()), None, Some(id), false).err().unwrap();
+            assert_eq!(e.to_string(), format!("no audio track {id}"));
+        }
+    }
+
+    #[test]
+    fn pcm_seek_starts_at_the_requested_frame() {
+        let mut hint = Hint::new();
+        hint.with_extension("wav");
+        let mut decoder = PcmDecoder::new(Box::new(Cursor::new(wav(8000, 8000))), &hint, None, false).unwrap();
+
+        decoder.seek(Duration::from_millis(500)).unwrap();
+        assert_eq!(first(&mut decoder), 4000 << 16);
+        decoder.seek(Duration::from_millis(125)).unwrap();
+        assert_eq!(first(&mut decoder), 1000 << 16);
+    }
+
+    #[test]
+    fn dsf_seek_starts_inside_a_block() {
+        let left: Vec<u8> = (0..40).collect();
+        let right: Vec<u8> = (0..40).map(|b| 0x80 | b).collect();
+        // 100 bytes a second
+        let mut reader = DsdReader::new(source(dsf(&[left, right], 800, 8))).unwrap();
+
+        reader.seek(Duration::from_millis(250)).unwrap();
+        assert_eq!(first(&mut reader), word([25, 26, 27, 28]));
+        reader.seek(Duration::from_millis(80)).unwrap();
+        assert_eq!(first(&mut reader), word([8, 9, 10, 11]));
+    }
+
+    #[test]
+    fn dff_seek_starts_at_the_requested_byte() {
+        let data: Vec<u8> = (0..40).flat_map(|b| [b, 0x80 | b]).collect();
+        // 100 bytes a second
+        let mut reader = DffReader::new(source(dff(&data, 2, 800, ""))).unwrap();
+
+        reader.seek(Duration::from_millis(250)).unwrap();
+        let mut buf = VecDeque::new();
+        reader.decode(&mut buf).unwrap();
+        assert_eq!(buf[0], word([25, 26, 27, 28]));
+        assert_eq!(buf[1], word([0x99, 0x9a, 0x9b, 0x9c]));
+    }
+
+    #[test]
+    fn unplayable_queued_track_is_reported() {
+        let (tx, rx) = std::sync::mpsc::channel();
+        let mut dm = DecoderManager::default();
+        dm.set_event_sender(tx);
+        dm.enqueue(PathBuf::from("/nonexistent/track.flac"));
+
+        assert!(!dm.next_track());
+        match rx.try_recv() {
+            Ok(PlayerEvent::TrackAbandoned(DecoderError::Open(s))) => assert!(s.starts_with("/nonexistent/track.flac: ")),
+            _ => panic!("no event"),
+        }
+    }
+
+    #[test]
+    fn sources_are_routed_by_their_magic() {
+        let open = |bytes: Vec<u8>| {
+            DecoderManager::source_decoder(Box::new(Cursor::new(bytes)), &Hint::new(), None)
+                .unwrap()
+                .spec()
+                .unwrap()
+        };
+
+        assert_eq!(open(dsf(&[vec![0; 8]], 2822400, 8)).mode, OutputMode::DSD);
+        assert_eq!(open(dff(&[0; 8], 2, 2822400, "")).mode, OutputMode::DSD);
+        // no extension to go by, symphonia probes the content
+        assert_eq!(open(wav(8000, 100)).mode, OutputMode::PCM);
+    }
+}
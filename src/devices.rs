@@ -0,0 +1,279 @@
+use std::fmt::Display;
+
+use anyhow::{anyhow, Result};
+
+use alsa::{
+    card,
+    ctl::{Ctl, DeviceIter},
+    device_name::HintIter,
+    pcm::{Format, HwParams},
+    Direction,
+    PCM
+};
+
+use crate::{
+    alsa_output::{format_bits, native_formats, PCM_FORMATS},
+    cli::DevicesArgs,
+    decoder::{Decoder, DecoderManager},
+    dsd::dsd_pcm_rate,
+    media::{DsdOutput, MediaSpec, OutputMode},
+    output::frame_rate,
+    player::{candidates, Candidate, OutputPath},
+};
+
+/// Formats probed on every device, pcm first, then dsd.
+const PROBED_FORMATS: [Format; 8] = [
+    Format::S16LE,
+    Format::S243LE,
+    Format::S24LE,
+    Format::S32LE,
+    Format::FloatLE,
+    Format::DSDU8,
+    Format::DSDU16LE,
+    Format::DSDU32LE,
+];
+
+/// Rates of the 44.1 and 48kHz families, DSD64 to DSD512 in DSD_U32 words fall among them.
+const PROBED_RATES: [u32; 10] = [44100, 48000, 88200, 96000, 176400, 192000, 352800, 384000, 705600, 768000];
+
+/// What a device accepts, from its hw params.
+pub struct Capabilities {
+    pub formats: Vec<Format>,
+    /// probed rates the device takes exactly
+    pub rates: Vec<u32>,
+    pub rate_range: (u32, u32),
+    pub channels: (u32, u32),
+}
+
+/// How a spec plays on a device.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Verdict {
+    BitPerfect(String),
+    Converted(String),
+    Rejected(String),
+}
+
+impl Display for Verdict {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Verdict::BitPerfect(how) => write!(f, "bit-perfect, {how}"),
+            Verdict::Converted(how) => write!(f, "converted, {how}"),
+            Verdict::Rejected(why) => write!(f, "rejected, {why}"),
+        }
+    }
+}
+
+impl Capabilities {
+    /// Probe `device` without configuring it, `spec` adds the rates it would need.
+    pub fn probe(device: &str, spec: Option<MediaSpec>) -> Result<Self> {
+        // a device busy with another stream fails at once instead of blocking
+        let pcm = PCM::new(device, Direction::Playback, true)?;
+        let rates: Vec<u32> = spec
+            .into_iter()
+            .flat_map(|spec| candidates(spec, DsdOutput::Auto, dsd_pcm_rate(spec.sample_rate), None, None))
+            .map(|c| frame_rate(c.spec))
+            .collect();
+        Self::from_hw_params(&HwParams::any(&pcm)?, &rates)
+    }
+
+    /// What `hwp` leaves open, `rates` are tested besides the common ones.
+    pub fn from_hw_params(hwp: &HwParams, rates: &[u32]) -> Result<Self> {
+        let formats = PROBED_FORMATS
+            .into_iter()
+            .filter(|f| hwp.test_format(*f).is_ok())
+            .collect();
+
+        let mut rates: Vec<u32> = PROBED_RATES.iter().chain(rates).copied().collect();
+        rates.sort_unstable();
+        rates.dedup();
+        rates.retain(|r| hwp.test_rate(*r).is_ok());
+
+        Ok(Self {
+            formats,
+            rates,
+            rate_range: (hwp.get_rate_min()?, hwp.get_rate_max()?),
+            channels: (hwp.get_channels_min()?, hwp.get_channels_max()?),
+        })
+    }
+
+    fn has_rate(&self, rate: u32) -> bool {
+        self.rates.contains(&rate)
+    }
+
+    /// The format the device would be opened with for `spec` as is, or why it can not.
+    pub fn accepts(&self, spec: MediaSpec) -> Result<Format, String> {
+        let (min, max) = self.channels;
+        if !(min..=max).contains(&spec.channel) {
+            return Err(format!("{} channels, the device takes {min} to {max}", spec.channel));
+        }
+
+        let (formats, what): (Vec<Format>, _) = match spec.mode {
+            OutputMode::DSD => (vec![Format::DSDU32LE], "no DSD_U32_LE"),
+            OutputMode::DoP => (vec![Format::S32LE, Format::S243LE, Format::S24LE], "no 24 bit format"),
+            OutputMode::PCM => (native_formats(spec.bits).iter().chain(PCM_FORMATS.iter()).copied().collect(), "no pcm format"),
+            OutputMode::DsdToPcm => (PCM_FORMATS.to_vec(), "no pcm format"),
+        };
+        let format = formats
+            .into_iter()
+            .find(|f| self.formats.contains(f))
+            .ok_or(what.to_owned())?;
+
+        let rate = frame_rate(spec);
+        if !self.has_rate(rate) {
+            return Err(format!("no {rate}Hz"));
+        }
+        Ok(format)
+    }
+
+    /// Rate to resample `rate` to, a multiple within the same family first, higher before lower.
+    pub fn resample_rate(&self, rate: u32) -> Option<u32> {
+        let family = |r: u32| r.max(rate).is_multiple_of(r.min(rate));
+        self.rates
+            .iter()
+            .copied()
+            .filter(|r| *r != rate)
+            .min_by_key(|r| (!family(*r), *r < rate, r.abs_diff(rate)))
+    }
+
+    /// How the player would send `spec`, following the same chain as `Player::init`.
+    pub fn classify(&self, spec: MediaSpec) -> Verdict {
+        let candidates = candidates(spec, DsdOutput::Auto, dsd_pcm_rate(spec.sample_rate), None, Some(self));
+        let mut rejected = Vec::new();
+
+        for Candidate { path, spec: candidate } in candidates {
+            let format = match self.accepts(candidate) {
+                Ok(format) => format,
+                Err(e) => {
+                    rejected.push(format!("{path}: {e}"));
+                    continue;
+                },
+            };
+
+            // lossy sources decode to full 32 bit samples
+            let dither = match (format_bits(format), spec.mode) {
+                (Some(bits), OutputMode::PCM) if bits < spec.bits.unwrap_or(32) => format!(", dithered to {bits} bit"),
+                _ => String::new(),
+            };
+
+            return match path {
+                OutputPath::NativeDsd => Verdict::BitPerfect("native DSD_U32_LE".to_owned()),
+                OutputPath::DoP => Verdict::BitPerfect(format!("DoP in {format}")),
+                OutputPath::NativePcm if dither.is_empty() => Verdict::BitPerfect(format!("{format}")),
+                OutputPath::NativePcm => Verdict::Converted(format!("{format}{dither}")),
+                OutputPath::DsdToPcm => Verdict::Converted(format!("dsd to {}Hz pcm, {format}", candidate.sample_rate)),
+                OutputPath::Resampled => {
+                    Verdict::Converted(format!("resampled to {}Hz, {format}{dither}", candidate.sample_rate))
+                },
+            };
+        }
+
+        Verdict::Rejected(rejected.join(", "))
+    }
+}
+
+impl Display for Capabilities {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let formats: Vec<String> = self.formats.iter().map(|f| f.to_string()).collect();
+        let rates: Vec<String> = self.rates.iter().map(|r| r.to_string()).collect();
+        writeln!(f, "    formats: {}", formats.join(" "))?;
+        writeln!(f, "    rates: {} ({} to {}Hz)", rates.join(" "), self.rate_range.0, self.rate_range.1)?;
+        write!(f, "    channels: {} to {}", self.channels.0, self.channels.1)
+    }
+}
+
+/// `96000:24:2` for pcm, `dsd:2822400:2` for dsd, the bit depth may be left out.
+pub fn parse_spec(s: &str) -> Result<MediaSpec> {
+    let fields: Vec<&str> = s.split(':').collect();
+    let number = |f: &str| f.parse::<u32>().map_err(|_| anyhow!("`{f}` is not a number"));
+
+    match fields.as_slice() {
+        ["dsd", rate, channel] => Ok(MediaSpec {
+            sample_rate: number(rate)?,
+            channel: number(channel)?,
+            bits: Some(1),
+            mode: OutputMode::DSD,
+        }),
+        [rate, bits, channel] => Ok(MediaSpec {
+            sample_rate: number(rate)?,
+            channel: number(channel)?,
+            bits: Some(number(bits)?),
+            mode: OutputMode::PCM,
+        }),
+        [rate, channel] => Ok(MediaSpec {
+            sample_rate: number(rate)?,
+            channel: number(channel)?,
+            bits: None,
+            mode: OutputMode::PCM,
+        }),
+        _ => Err(anyhow!("expected RATE:BITS:CHANNELS or dsd:RATE:CHANNELS")),
+    }
+}
+
+/// List cards, their pcm devices with what they accept, and the configured pcm names.
+pub fn devices(args: DevicesArgs) -> Result<()> {
+    let spec = match (&args.path, args.spec) {
+        (Some(p), _) => {
+            let mut dm = DecoderManager::default();
+            dm.open(p.clone())?;
+            Some(dm.spec().ok_or(anyhow!("unknown codec"))?)
+        },
+        (None, spec) => spec,
+    };
+
+    let report = |device: &str| {
+        match Capabilities::probe(device, spec) {
+            Ok(caps) => {
+                println!("{caps}");
+                if let Some(spec) = spec {
+                    println!("    {}: {}", describe(spec), caps.classify(spec));
+                }
+            },
+            Err(e) => println!("    not probed: {e}"),
+        }
+    };
+
+    if let Some(device) = &args.device {
+        println!("{device}");
+        report(device);
+        return Ok(());
+    }
+
+    for card in card::Iter::new() {
+        let card = card?;
+        let ctl = Ctl::from_card(&card, false)?;
+        let info = ctl.card_info()?;
+        println!("card {}: {} [{}]", card.get_index(), info.get_id()?, info.get_name()?);
+
+        for device in DeviceIter::new(&ctl) {
+            let Ok(pcm) = ctl.pcm_info(device as u32, 0, Direction::Playback) else {
+                // capture only
+                continue;
+            };
+            let name = format!("hw:{},{device}", card.get_index());
+            println!("  {name}  {}", pcm.get_name()?);
+            report(&name);
+        }
+    }
+
+    println!("pcm names:");
+    for hint in HintIter::new_str(None, "pcm")? {
+        if hint.direction == Some(Direction::Capture) {
+            continue;
+        }
+        let Some(name) = hint.name else {
+            continue;
+        };
+        let desc = hint.desc.as_deref().unwrap_or_default().replace('\n', ", ");
+        println!("  {name}  {desc}");
+    }
+
+    Ok(())
+}
+
+fn describe(spec: MediaSpec) -> String {
+    match (spec.mode, spec.bits) {
+        (OutputMode::DSD, _) => format!("dsd {}Hz {}ch", spec.sample_rate, spec.channel),
+        (_, Some(bits)) => format!("{}Hz {bits} bit {}ch", spec.sample_rate, spec.channel),
+        (_, None) => format!("{}Hz {}ch", spec.sample_rate, spec.channel),
+    }
+}
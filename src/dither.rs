@@ -0,0 +1,165 @@
+/// Dither used when the device format is narrower than the source.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, clap::ValueEnum)]
+pub enum DitherMode {
+    Off,
+    /// triangular pdf, ±1 lsb
+    #[default]
+    Tpdf,
+}
+
+/// Error feedback curves moving requantization noise out of the audible band.
+/// The shaped curves are designed for 44.1kHz.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, clap::ValueEnum)]
+pub enum NoiseShaping {
+    #[default]
+    None,
+    /// second order high pass
+    HighPass,
+    /// 5 tap Lipshitz
+    Lipshitz,
+    /// 9 tap F-weighted by Wannamaker
+    FWeighted,
+}
+
+impl NoiseShaping {
+    fn coefficients(&self) -> &'static [f64] {
+        match self {
+            NoiseShaping::None => &[],
+            NoiseShaping::HighPass => &[2.0, -1.0],
+            NoiseShaping::Lipshitz => &[2.033, -2.165, 1.959, -1.590, 0.6149],
+            NoiseShaping::FWeighted => &[2.412, -3.370, 3.937, -4.174, 3.353, -2.205, 1.281, -0.569, 0.0847],
+        }
+    }
+}
+
+/// Requantize S32 samples to `bits` with TPDF dither and optional noise shaping.
+/// Samples keep their S32 scale with the lower bits cleared.
+pub struct Dither {
+    channel: usize,
+    /// width of one output lsb in S32 steps
+    step: f64,
+    coefficients: &'static [f64],
+    /// past quantization errors per channel, newest first
+    errors: Vec<Vec<f64>>,
+    rng: u64,
+}
+
+impl Dither {
+    /// `None` when the output keeps every source bit or dither is off.
+    pub fn new(
+        mode: DitherMode,
+        shaping: NoiseShaping,
+        channel: usize,
+        source_bits: Option<u32>,
+        output_bits: Option<u32>,
+    ) -> Option<Self> {
+        // lossy and converted sources have no fixed depth, treat them as 32 bit
+        let source_bits = source_bits.unwrap_or(32);
+        let output_bits = output_bits?;
+        if mode == DitherMode::Off || output_bits >= source_bits || output_bits >= 32 {
+            return None;
+        }
+
+        let coefficients = shaping.coefficients();
+        Some(Self {
+            channel,
+            step: (1u64 << (32 - output_bits)) as f64,
+            coefficients,
+            errors: vec![vec![0.0; coefficients.len()]; channel],
+            rng: 0x2545_f491_4f6c_dd1d,
+        })
+    }
+
+    /// Uniform in [0, 1), xorshift64.
+    fn random(&mut self) -> f64 {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        (self.rng >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// Process frame aligned interleaved samples in place.
+    pub fn process<'a>(&mut self, samples: impl Iterator<Item = &'a mut i32>) {
+        let max = i32::MAX as f64 / self.step;
+        let min = i32::MIN as f64 / self.step;
+
+        for (i, s) in samples.enumerate() {
+            let ch = i % self.channel;
+            let x = *s as f64 / self.step;
+
+            let feedback: f64 = self.coefficients
+                .iter()
+                .zip(&self.errors[ch])
+                .map(|(c, e)| c * e)
+                .sum();
+            let shaped = x - feedback;
+
+            let dither = self.random() - self.random();
+            let q = (shaped + dither).round().clamp(min.ceil(), max.floor());
+
+            let errors = &mut self.errors[ch];
+            if !errors.is_empty() {
+                errors.rotate_right(1);
+                errors[0] = q - shaped;
+            }
+
+            *s = (q * self.step) as i32;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Difference of `input` requantized to 16 bits, in output lsb.
+    fn errors(shaping: NoiseShaping, input: &[i32]) -> Vec<f64> {
+        let mut dither = Dither::new(DitherMode::Tpdf, shaping, 1, Some(24), Some(16)).unwrap();
+        let mut out = input.to_vec();
+        dither.process(out.iter_mut());
+
+        assert!(out.iter().all(|s| s & 0xffff == 0));
+        out.iter().zip(input).map(|(o, i)| (*o as f64 - *i as f64) / 65536.0).collect()
+    }
+
+    #[test]
+    fn dither_only_when_bits_are_lost() {
+        let new = |mode, source, output| Dither::new(mode, NoiseShaping::None, 2, source, output).is_some();
+
+        assert!(new(DitherMode::Tpdf, Some(24), Some(16)));
+        assert!(new(DitherMode::Tpdf, None, Some(24)));
+        assert!(!new(DitherMode::Tpdf, Some(16), Some(16)));
+        assert!(!new(DitherMode::Tpdf, Some(24), Some(32)));
+        assert!(!new(DitherMode::Tpdf, Some(24), None));
+        assert!(!new(DitherMode::Off, Some(24), Some(16)));
+    }
+
+    #[test]
+    fn tpdf_is_bounded_and_keeps_the_level() {
+        // a quarter lsb, plain rounding would turn it into silence
+        let input = vec![1 << 14; 100_000];
+        let e = errors(NoiseShaping::None, &input);
+
+        assert!(e.iter().all(|e| e.abs() <= 1.5));
+        let mean = e.iter().sum::<f64>() / e.len() as f64 + 0.25;
+        assert!((mean - 0.25).abs() < 0.01, "{mean}");
+    }
+
+    #[test]
+    fn noise_shaping_moves_noise_out_of_the_low_band() {
+        let input: Vec<i32> = (0..20_000).map(|n| ((n * 7919) % 65536) << 8).collect();
+        // energy below 2.2kHz at 44.1kHz
+        let h = crate::dsd::lowpass(255, 0.05);
+        let low_band = |e: &[f64]| {
+            e.windows(h.len())
+                .map(|w| w.iter().zip(&h).map(|(e, h)| e * h).sum::<f64>().powi(2))
+                .sum::<f64>()
+        };
+
+        let flat = low_band(&errors(NoiseShaping::None, &input));
+        for shaping in [NoiseShaping::HighPass, NoiseShaping::Lipshitz, NoiseShaping::FWeighted] {
+            let shaped = low_band(&errors(shaping, &input));
+            assert!(shaped < flat / 10.0, "{shaping:?} {shaped} {flat}");
+        }
+    }
+}
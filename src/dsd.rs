@@ -0,0 +1,349 @@
+/// DoP markers, alternating on every pcm frame.
+const DOP_MARKER: [u32; 2] = [0x05, 0xFA];
+
+/// DSD over PCM encoder. Every `DSD_U32` word is split into two pcm frames,
+/// each carrying 16 dsd bits and an 8 bit marker in the upper 24 bits of a
+/// S32 sample.
+pub struct DopEncoder {
+    channel: usize,
+    /// count of frames written to device, decides the next marker
+    frame: usize,
+    /// the first half of the head word was written, it goes on with the second
+    half: bool,
+    buf: Vec<i32>,
+}
+
+impl DopEncoder {
+    pub fn new(channel: usize) -> Self {
+        Self {
+            channel,
+            frame: 0,
+            half: false,
+            buf: vec![],
+        }
+    }
+
+    /// Encode frame interleaved `DSD_U32` words into S32 DoP frames.
+    pub fn encode(&mut self, words: &[i32]) -> &[i32] {
+        self.buf.clear();
+
+        for (i, frame) in words.chunks_exact(self.channel).enumerate() {
+            let n = self.frame.wrapping_add(i * 2).wrapping_sub(self.half as usize);
+            if i > 0 || !self.half {
+                let marker = DOP_MARKER[n & 1] << 24;
+                self.buf.extend(frame.iter().map(|w| (marker | ((*w as u32 >> 8) & 0x00ffff00)) as i32));
+            }
+
+            let marker = DOP_MARKER[(n + 1) & 1] << 24;
+            self.buf.extend(frame.iter().map(|w| (marker | ((*w as u32) << 8 & 0x00ffff00)) as i32));
+        }
+
+        &self.buf
+    }
+
+    /// Keep markers alternating across writes, `frames` is the count of pcm
+    /// frames the device accepted. Returns the count of words done with, a
+    /// word split by the write is finished with the next one.
+    pub fn commit(&mut self, frames: usize) -> usize {
+        let halves = frames + self.half as usize;
+        self.frame = self.frame.wrapping_add(frames);
+        self.half = halves & 1 == 1;
+        halves / 2
+    }
+
+    /// Forget a split word, the words after it were dropped.
+    pub fn reset(&mut self) {
+        self.half = false;
+    }
+}
+
+/// Taps of the first decimation stage, working on whole dsd bytes.
+const STAGE1_TAPS: usize = 384;
+
+/// Taps of the second decimation stage per decimation step.
+const STAGE2_TAPS_PER_RATIO: usize = 64;
+
+/// Idle pattern of a dsd stream, the filters are filled with it.
+const DSD_SILENCE: u8 = 0x69;
+
+/// Highest pcm rate chosen when the user asks for none.
+const DSD_PCM_MAX_RATE: u32 = 192_000;
+
+/// Default pcm rate for a dsd rate: 88.2kHz for DSD64, 176.4kHz for DSD128
+/// and above.
+pub fn dsd_pcm_rate(dsd_rate: u32) -> u32 {
+    let mut rate = dsd_rate / 32;
+    while rate > DSD_PCM_MAX_RATE {
+        rate /= 2;
+    }
+    rate
+}
+
+/// Windowed sinc low pass, `cutoff` relative to the sample rate, unity gain at DC.
+pub fn lowpass(taps: usize, cutoff: f64) -> Vec<f64> {
+    let center = (taps - 1) as f64 / 2.0;
+    let mut h: Vec<f64> = (0..taps)
+        .map(|n| {
+            let x = n as f64 - center;
+            let sinc = if x == 0.0 {
+                2.0 * cutoff
+            } else {
+                (2.0 * std::f64::consts::PI * cutoff * x).sin() / (std::f64::consts::PI * x)
+            };
+
+            // blackman window
+            let w = n as f64 / (taps - 1) as f64 * 2.0 * std::f64::consts::PI;
+            sinc * (0.42 - 0.5 * w.cos() + 0.08 * (2.0 * w).cos())
+        })
+        .collect();
+
+    let sum: f64 = h.iter().sum();
+    h.iter_mut().for_each(|v| *v /= sum);
+    h
+}
+
+/// DSD to pcm converter. The first stage decimates by 8 through byte lookup
+/// tables, the second stage is a plain FIR down to the output rate.
+pub struct DsdToPcm {
+    channel: usize,
+    /// stage 1 response of every possible byte, per byte position of the window
+    table: Vec<[f32; 256]>,
+    stage2: Vec<f32>,
+    ratio: usize,
+    /// stage 1 input, last `table.len()` bytes per channel
+    bytes: Vec<Vec<u8>>,
+    /// stage 2 input, last `stage2.len()` samples per channel
+    mid: Vec<Vec<f32>>,
+    byte_pos: usize,
+    mid_pos: usize,
+    phase: usize,
+}
+
+impl DsdToPcm {
+    pub fn new(dsd_rate: u32, pcm_rate: u32, channel: usize) -> anyhow::Result<Self> {
+        let decimation = dsd_rate / pcm_rate.max(1);
+        if !dsd_rate.is_multiple_of(pcm_rate.max(1)) || decimation < 8 || !decimation.is_multiple_of(8) {
+            return Err(anyhow::anyhow!("can not convert {dsd_rate}Hz dsd to {pcm_rate}Hz pcm"));
+        }
+
+        let ratio = (decimation / 8) as usize;
+
+        // with a second stage the first one only needs to remove the shaped noise
+        let cutoff = if ratio > 1 { 1.0 / 32.0 } else { 0.45 / 8.0 };
+        let h1 = lowpass(STAGE1_TAPS, cutoff);
+        let table = h1
+            .chunks_exact(8)
+            .map(|taps| {
+                let mut t = [0f32; 256];
+                for (byte, v) in t.iter_mut().enumerate() {
+                    // LSB is the newest bit
+                    *v = taps
+                        .iter()
+                        .enumerate()
+                        .map(|(bit, h)| if byte >> bit & 1 == 1 { *h } else { -*h })
+                        .sum::<f64>() as f32;
+                }
+                t
+            })
+            .collect::<Vec<_>>();
+
+        let stage2 = if ratio > 1 {
+            lowpass(STAGE2_TAPS_PER_RATIO * ratio, 0.45 / ratio as f64)
+                .into_iter()
+                .map(|v| v as f32)
+                .collect()
+        } else {
+            vec![1.0]
+        };
+
+        Ok(Self {
+            channel,
+            bytes: vec![vec![DSD_SILENCE; table.len()]; channel],
+            mid: vec![vec![0.0; stage2.len()]; channel],
+            table,
+            stage2,
+            ratio,
+            byte_pos: 0,
+            mid_pos: 0,
+            phase: 0,
+        })
+    }
+
+    /// Drop the filter history, e.g. after a seek.
+    pub fn reset(&mut self) {
+        self.bytes.iter_mut().for_each(|b| b.fill(DSD_SILENCE));
+        self.mid.iter_mut().for_each(|m| m.fill(0.0));
+        self.byte_pos = 0;
+        self.mid_pos = 0;
+        self.phase = 0;
+    }
+
+    /// Push out the filter delay at the end of the input, then start over like after `reset`.
+    pub fn flush(&mut self, out: &mut std::collections::VecDeque<i32>) {
+        let bytes = self.table.len() / 2 + self.stage2.len() / 2;
+        let word = i32::from_ne_bytes([DSD_SILENCE; 4]);
+        self.process(&vec![word; bytes.div_ceil(4) * self.channel], out);
+        self.reset();
+    }
+
+    /// Convert frame interleaved `DSD_U32` words into interleaved S32 pcm.
+    /// 0dB SACD is 50% modulation and comes out at -6dBFS, which leaves room
+    /// for the +3dB peaks the format allows.
+    pub fn process(&mut self, words: &[i32], out: &mut std::collections::VecDeque<i32>) {
+        let taps1 = self.table.len();
+        let taps2 = self.stage2.len();
+
+        for frame in words.chunks_exact(self.channel) {
+            for shift in [24, 16, 8, 0] {
+                self.byte_pos = (self.byte_pos + 1) % taps1;
+                self.mid_pos = (self.mid_pos + 1) % taps2;
+
+                for (ch, word) in frame.iter().enumerate() {
+                    let bytes = &mut self.bytes[ch];
+                    bytes[self.byte_pos] = (*word as u32 >> shift) as u8;
+
+                    // table 0 holds the newest byte
+                    let v: f32 = self.table
+                        .iter()
+                        .enumerate()
+                        .map(|(k, t)| t[bytes[(self.byte_pos + taps1 - k) % taps1] as usize])
+                        .sum();
+                    self.mid[ch][self.mid_pos] = v;
+                }
+
+                self.phase += 1;
+                if self.phase < self.ratio {
+                    continue;
+                }
+                self.phase = 0;
+
+                for mid in &self.mid {
+                    let v: f32 = self.stage2
+                        .iter()
+                        .enumerate()
+                        .map(|(k, h)| h * mid[(self.mid_pos + taps2 - k) % taps2])
+                        .sum();
+                    let v = v.clamp(-1.0, 1.0) as f64 * i32::MAX as f64;
+                    out.push_back(v as i32);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WORDS: [i32; 4] = [0x1122_3344, 0x5566_7788u32 as i32, 0x99aa_bbcc_u32 as i32, 0x0102_0304];
+
+    #[test]
+    fn dop_splits_words_and_alternates_markers() {
+        let mut dop = DopEncoder::new(2);
+        let out = dop.encode(&WORDS).to_vec();
+
+        assert_eq!(out, [
+            0x0511_2200, 0x0555_6600, 0xfa33_4400u32 as i32, 0xfa77_8800u32 as i32,
+            0x0599_aa00, 0x0501_0200, 0xfabb_cc00u32 as i32, 0xfa03_0400u32 as i32,
+        ]);
+        assert_eq!(dop.commit(4), 2);
+
+        // markers go on from the last frame written
+        let out = dop.encode(&WORDS[..2]).to_vec();
+        assert_eq!(out[0] as u32 >> 24, 0x05);
+        assert_eq!(out[2] as u32 >> 24, 0xfa);
+    }
+
+    #[test]
+    fn dop_finishes_a_word_split_by_the_write() {
+        let mut dop = DopEncoder::new(2);
+        let all = dop.encode(&WORDS).to_vec();
+
+        // the device took the first half of the second word only
+        dop.encode(&WORDS);
+        assert_eq!(dop.commit(3), 1);
+
+        // the next write starts with the missing half, nothing is sent twice
+        let rest = dop.encode(&WORDS[2..]).to_vec();
+        assert_eq!(rest, all[6..]);
+        assert_eq!(dop.commit(1), 1);
+
+        dop.reset();
+        assert_eq!(dop.encode(&WORDS[..2]).len(), 4);
+    }
+
+    /// Second order sigma delta modulation of `x`, packed into mono `DSD_U32` words.
+    fn modulate(x: impl Iterator<Item = f64>) -> Vec<i32> {
+        let (mut i1, mut i2, mut y) = (0.0, 0.0, 0.0);
+        let bits: Vec<bool> = x
+            .map(|x| {
+                i1 += x - y;
+                i2 += i1 - y;
+                y = if i2 >= 0.0 { 1.0 } else { -1.0 };
+                y > 0.0
+            })
+            .collect();
+
+        // the oldest bit is the MSB
+        bits.chunks_exact(32)
+            .map(|b| b.iter().fold(0u32, |w, bit| w << 1 | *bit as u32) as i32)
+            .collect()
+    }
+
+    #[test]
+    fn sacd_reference_tone_is_minus_6_dbfs() {
+        let dsd_rate = 2_822_400;
+        let tone = 1000.0;
+        // 0dB SACD, 50% modulation
+        let x = (0..dsd_rate / 5).map(|n| 0.5 * (2.0 * std::f64::consts::PI * tone * n as f64 / dsd_rate as f64).sin());
+
+        let mut conv = DsdToPcm::new(dsd_rate, 88_200, 1).unwrap();
+        let mut out = std::collections::VecDeque::new();
+        conv.process(&modulate(x), &mut out);
+
+        // past the filter delay
+        let settled: Vec<f64> = out.iter().skip(1000).map(|v| *v as f64 / i32::MAX as f64).collect();
+        let rms = (settled.iter().map(|v| v * v).sum::<f64>() / settled.len() as f64).sqrt();
+        let peak_db = 20.0 * (rms * std::f64::consts::SQRT_2).log10();
+        assert!((peak_db + 6.02).abs() < 0.3, "{peak_db}dBFS");
+    }
+
+    #[test]
+    fn reset_forgets_the_old_position() {
+        let loud = modulate((0..32 * 1000).map(|_| 0.5));
+        let silence = vec![i32::from_ne_bytes([DSD_SILENCE; 4]); 1000];
+
+        let mut fresh = DsdToPcm::new(2_822_400, 88_200, 1).unwrap();
+        let mut clean = std::collections::VecDeque::new();
+        fresh.process(&silence, &mut clean);
+
+        let mut conv = DsdToPcm::new(2_822_400, 88_200, 1).unwrap();
+        let mut out = std::collections::VecDeque::new();
+        conv.process(&loud, &mut out);
+        conv.reset();
+        out.clear();
+        conv.process(&silence, &mut out);
+        assert_eq!(out, clean);
+    }
+
+    #[test]
+    fn flush_pushes_out_the_filter_delay() {
+        // a burst too short to pass the filters before the input ends
+        let burst = modulate((0..32 * 8).map(|_| 0.5));
+        let level = |out: &std::collections::VecDeque<i32>| out.iter().map(|s| (*s as f64 / i32::MAX as f64).abs()).fold(0.0, f64::max);
+
+        let mut conv = DsdToPcm::new(2_822_400, 88_200, 1).unwrap();
+        let mut out = std::collections::VecDeque::new();
+        conv.process(&burst, &mut out);
+        let before = level(&out);
+        conv.flush(&mut out);
+        assert!(level(&out) > 2.0 * before, "{before} {}", level(&out));
+
+        // and starts over
+        let mut fresh = DsdToPcm::new(2_822_400, 88_200, 1).unwrap();
+        let (mut a, mut b) = (std::collections::VecDeque::new(), std::collections::VecDeque::new());
+        conv.process(&burst, &mut a);
+        fresh.process(&burst, &mut b);
+        assert_eq!(a, b);
+    }
+}
@@ -1,9 +1,56 @@
-use crate::media::MediaSpec;
+use std::{fmt::Display, time::Duration};
+
+use crate::{
+    decoder::DecoderError,
+    media::MediaSpec,
+    output::{Xrun, XrunStats},
+    player::OutputPath,
+};
 
 #[derive(Copy, Clone)]
 pub enum PlayerCommand {
     Play(MediaSpec),
     Resume,
     Pause,
+    Seek(Duration),
+}
+
+/// Sent from the player to the controller.
+#[derive(Clone, Debug)]
+pub enum PlayerEvent {
+    /// a read or packet failed and was skipped, playback goes on
+    DecodeError(DecoderError),
+    /// the track failed too often in a row, the player moves on to the next one
+    TrackAbandoned(DecoderError),
+    /// a radio stream announced the title now playing
+    NowPlaying(String),
+    /// the device was opened along `path`
+    OutputChosen { path: OutputPath, reason: String },
+    /// the device refused `path`, the next one is tried
+    OutputRejected { path: OutputPath, reason: String },
+    /// the device was interrupted, `count` times so far
+    Xrun { xrun: Xrun, count: u32 },
+    /// the disconnected device is back
+    Reconnected,
+    /// interruptions of the whole playback, sent at the end
+    XrunSummary(XrunStats),
+    /// the output thread could not get realtime priority
+    NoRealtime(String),
 }
 
+impl Display for PlayerEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PlayerEvent::DecodeError(e) => write!(f, "decode error: {e}"),
+            PlayerEvent::TrackAbandoned(e) => write!(f, "skip track: {e}"),
+            PlayerEvent::NowPlaying(title) => write!(f, "now playing: {title}"),
+            PlayerEvent::OutputChosen { path, reason } => write!(f, "output: {path}, {reason}"),
+            PlayerEvent::OutputRejected { path, reason } => write!(f, "output: no {path}, {reason}"),
+            PlayerEvent::Xrun { xrun: Xrun::Disconnect, .. } => write!(f, "output: device disconnected, waiting for it"),
+            PlayerEvent::Xrun { xrun, count } => write!(f, "output: {xrun} #{count}, recovered"),
+            PlayerEvent::Reconnected => write!(f, "output: device is back"),
+            PlayerEvent::XrunSummary(stats) => write!(f, "output: {stats}"),
+            PlayerEvent::NoRealtime(e) => write!(f, "output: normal priority, {e}"),
+        }
+    }
+}
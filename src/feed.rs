@@ -0,0 +1,393 @@
+use std::{
+    collections::VecDeque,
+    sync::{mpsc::{channel, Receiver, Sender, TryRecvError}, Arc},
+    thread::{self, JoinHandle, Thread},
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use ringbuf::{
+    traits::{Observer, Producer},
+    HeapProd,
+};
+
+use crate::{
+    cli::PlayArgs,
+    cue,
+    decoder::{Decoder, DecoderError, DecoderManager},
+    event::{PlayerCommand, PlayerEvent},
+    media::{MediaSpec, OutputMode},
+    pipeline::Pipeline,
+    progress::Progress,
+    TMP_BUF_ALLOC,
+};
+
+/// Milliseconds decoded ahead before the device starts.
+pub const DEFAULT_PREBUFFER_MS: u32 = 500;
+
+/// Longest sleep of a thread waiting on the other one, commands are checked in between.
+pub const IDLE: Duration = Duration::from_millis(20);
+
+/// Decoding stops once the ring is this full, so a stall of the decoder has
+/// seconds of samples to play through.
+pub fn high_watermark(capacity: usize) -> usize {
+    capacity / 8 * 7
+}
+
+/// Decoding starts again once the ring drained to this, in one burst up to the high watermark.
+pub fn low_watermark(capacity: usize) -> usize {
+    capacity / 2
+}
+
+/// Samples of `ms` milliseconds for a device opened as `spec` at `rate` frames per
+/// second, no more than the decoder fills the ring with.
+pub fn prebuffer_len(ms: u32, spec: MediaSpec, rate: u32, capacity: usize) -> usize {
+    // DoP writes two device frames for every dsd word
+    let rate = if spec.mode == OutputMode::DoP { rate / 2 } else { rate };
+    let samples = rate as u64 * spec.channel as u64 * ms as u64 / 1000;
+    (samples as usize).min(high_watermark(capacity))
+}
+
+/// Sent from the decode thread to the output thread, in order with the samples
+/// of the ring: whatever was pushed before a message plays before it.
+pub enum Feed {
+    /// play out the ring, then open the device for `src` and answer with `Reply::Opened`.
+    /// `pos` is where the track starts, none when the position goes on
+    Open {
+        src: MediaSpec,
+        gain: Option<f32>,
+        pos: Option<Duration>,
+        duration: Option<Duration>,
+    },
+    /// the decoder jumped to `pos`, drop the ring and answer with `Reply::Cleared`
+    Seeked { pos: Duration, duration: Option<Duration> },
+    /// play out the ring, nothing follows but the answer to a seek
+    End,
+}
+
+/// Answers of the output thread, the decode thread waits for them.
+pub enum Reply {
+    /// the device was opened as `spec`, decoded samples go through `pipeline` from now on
+    Opened { spec: MediaSpec, pipeline: Box<Pipeline> },
+    Cleared,
+}
+
+/// The output thread's end of the decode thread.
+pub struct DecodeThread {
+    handle: Option<JoinHandle<Result<()>>>,
+    commands: Sender<PlayerCommand>,
+    feed: Receiver<Feed>,
+    replies: Sender<Reply>,
+}
+
+impl DecodeThread {
+    /// Decode `args.path` into `prod` on a new thread, the calling thread is woken
+    /// whenever samples were pushed.
+    pub fn spawn(
+        args: Arc<PlayArgs>,
+        prod: HeapProd<i32>,
+        progress: Arc<Progress>,
+        events: Sender<PlayerEvent>,
+    ) -> Result<Self> {
+        let (commands, commands_rx) = channel();
+        let (feed_tx, feed) = channel();
+        let (replies, replies_rx) = channel();
+
+        let feeder = Feeder {
+            prod,
+            feed: feed_tx,
+            replies: replies_rx,
+            output: thread::current(),
+        };
+        let handle = thread::Builder::new()
+            .name("decode".to_owned())
+            .spawn(move || decode(&args, feeder, commands_rx, &progress, &events))?;
+
+        Ok(Self {
+            handle: Some(handle),
+            commands,
+            feed,
+            replies,
+        })
+    }
+
+    /// Pass a seek or a new spec on, the decode thread answers with the feed.
+    pub fn command(&self, cmd: PlayerCommand) {
+        let _ = self.commands.send(cmd);
+        self.wake();
+    }
+
+    /// Wait for the next message.
+    pub fn recv(&mut self) -> Result<Feed> {
+        match self.feed.recv() {
+            Ok(feed) => Ok(feed),
+            Err(_) => Err(self.join().err().unwrap_or(anyhow!("decode thread stopped"))),
+        }
+    }
+
+    /// The next message if there is one.
+    pub fn try_recv(&mut self) -> Result<Option<Feed>> {
+        match self.feed.try_recv() {
+            Ok(feed) => Ok(Some(feed)),
+            Err(TryRecvError::Empty) => Ok(None),
+            Err(TryRecvError::Disconnected) => Err(self.join().err().unwrap_or(anyhow!("decode thread stopped"))),
+        }
+    }
+
+    pub fn reply(&self, reply: Reply) {
+        let _ = self.replies.send(reply);
+    }
+
+    /// Let the decoder fill the ring, e.g. once it drained below the low watermark.
+    pub fn wake(&self) {
+        if let Some(handle) = &self.handle {
+            handle.thread().unpark();
+        }
+    }
+
+    /// Stop the thread after the end of the feed.
+    pub fn finish(mut self) -> Result<()> {
+        let handle = self.handle.take();
+        // it waits for a seek until the commands close
+        drop(self);
+        join(handle)
+    }
+
+    /// The error the thread ended with.
+    fn join(&mut self) -> Result<()> {
+        join(self.handle.take())
+    }
+}
+
+fn join(handle: Option<JoinHandle<Result<()>>>) -> Result<()> {
+    match handle {
+        Some(handle) => handle.join().map_err(|_| anyhow!("decode thread panicked"))?,
+        None => Ok(()),
+    }
+}
+
+/// The decode thread's end of the ring and the feed.
+struct Feeder {
+    prod: HeapProd<i32>,
+    feed: Sender<Feed>,
+    replies: Receiver<Reply>,
+    output: Thread,
+}
+
+impl Feeder {
+    /// Send `feed` and wait for the answer.
+    fn ask(&self, feed: Feed) -> Result<Reply> {
+        self.feed.send(feed).map_err(|_| anyhow!("output thread stopped"))?;
+        self.replies.recv().map_err(|_| anyhow!("output thread stopped"))
+    }
+
+    /// Have the device opened for `src`, returns the spec it was opened with and the pipeline to it.
+    fn open(&self, src: MediaSpec, gain: Option<f32>, pos: Option<Duration>, duration: Option<Duration>) -> Result<(MediaSpec, Pipeline)> {
+        match self.ask(Feed::Open { src, gain, pos, duration })? {
+            Reply::Opened { spec, pipeline } => Ok((spec, *pipeline)),
+            Reply::Cleared => Err(anyhow!("output thread out of step")),
+        }
+    }
+
+    /// Push what fits of `buf`.
+    fn push(&mut self, buf: &mut VecDeque<i32>) {
+        let n = self.prod.vacant_len().min(buf.len());
+        if n > 0 {
+            self.prod.push_iter(buf.drain(..n));
+            self.output.unpark();
+        }
+    }
+}
+
+fn decode(
+    args: &PlayArgs,
+    mut feeder: Feeder,
+    commands: Receiver<PlayerCommand>,
+    progress: &Progress,
+    events: &Sender<PlayerEvent>,
+) -> Result<()> {
+    let mut tracks = Vec::new();
+    for p in &args.path {
+        tracks.extend(cue::expand(p.clone())?);
+    }
+
+    let mut tracks = tracks.into_iter();
+    let mut dm = DecoderManager::default();
+    dm.set_track_id(args.track);
+    dm.set_format_hint(args.format.clone());
+    dm.set_error_budget(args.error_budget);
+    dm.set_event_sender(events.clone());
+    dm.open(tracks.next().ok_or(anyhow!("nothing to play"))?)?;
+    tracks.for_each(|t| dm.enqueue(t));
+    let now_playing = |dm: &DecoderManager| {
+        let title = dm.metadata().to_string();
+        if !title.is_empty() {
+            let _ = events.send(PlayerEvent::NowPlaying(title));
+        }
+    };
+    now_playing(&dm);
+
+    let track_gain = |dm: &mut DecoderManager| {
+        let in_album = dm.in_album();
+        dm.replay_gain().factor(args.replay_gain, args.preamp, in_album)
+    };
+    let src_spec = dm.spec().ok_or(anyhow!("unknown codec"))?;
+    let (mut spec, mut pipeline) = feeder.open(src_spec, track_gain(&mut dm), Some(Duration::ZERO), dm.duration())?;
+    // tags the gain of the pipeline was taken from, the next link of a chained stream brings its own
+    let mut gain_tags = dm.replay_gain();
+
+    // samples are decoded into decode_buf, then processed into temp_buf until the ring takes them
+    let mut decode_buf = VecDeque::<i32>::new();
+    let mut temp_buf = VecDeque::<i32>::with_capacity(TMP_BUF_ALLOC);
+    let capacity = feeder.prod.capacity().get();
+    // between the watermarks
+    let mut filling = true;
+
+    let mut eof = false;
+    // the decoder switched spec, reopen the device once the old samples are played
+    let mut respec = false;
+    // everything was decoded, only a seek goes on
+    let mut ended = false;
+
+    loop {
+        let cmd = match ended {
+            true => commands.recv().map_err(|_| TryRecvError::Disconnected),
+            false => commands.try_recv(),
+        };
+        match cmd {
+            Ok(PlayerCommand::Seek(pos)) => {
+                dm.seek(pos)?;
+
+                // nothing decoded before the jump may reach the device
+                temp_buf.clear();
+                decode_buf.clear();
+                pipeline.reset();
+                feeder.ask(Feed::Seeked { pos, duration: dm.duration() })?;
+                eof = false;
+                ended = false;
+                filling = true;
+            },
+            Ok(PlayerCommand::Play(media_spec)) => {
+                (spec, pipeline) = feeder.open(media_spec, track_gain(&mut dm), None, dm.duration())?;
+                gain_tags = dm.replay_gain();
+            },
+            Ok(PlayerCommand::Pause | PlayerCommand::Resume) => {},
+            // the output thread is gone
+            Err(TryRecvError::Disconnected) => return Ok(()),
+            Err(TryRecvError::Empty) => {},
+        }
+        if ended {
+            continue;
+        }
+
+        feeder.push(&mut temp_buf);
+        if temp_buf.is_empty() {
+            temp_buf.shrink_to(TMP_BUF_ALLOC);
+        }
+
+        let occupied = feeder.prod.occupied_len();
+        if !temp_buf.is_empty() || occupied >= high_watermark(capacity) {
+            filling = false;
+        } else if occupied <= low_watermark(capacity) {
+            filling = true;
+        }
+        if !filling {
+            // woken by the output thread at the low watermark
+            thread::park_timeout(IDLE);
+            continue;
+        }
+
+        if eof || respec {
+            if eof && !dm.next_track() {
+                feeder.feed.send(Feed::End).map_err(|_| anyhow!("output thread stopped"))?;
+                ended = true;
+                continue;
+            }
+
+            // the next track could not be spliced or the stream changed its spec, reopen the device
+            let src_spec = dm.spec().ok_or(anyhow!("unknown codec"))?;
+            let pos = eof.then_some(Duration::ZERO);
+            (spec, pipeline) = feeder.open(src_spec, track_gain(&mut dm), pos, dm.duration())?;
+            gain_tags = dm.replay_gain();
+            if eof {
+                now_playing(&dm);
+            }
+            eof = false;
+            respec = false;
+            continue;
+        }
+
+        let decoded = dm.decode(&mut decode_buf);
+        // the next link of a chained stream is scaled by its own gain from its first samples on
+        if dm.replay_gain() != gain_tags {
+            gain_tags = dm.replay_gain();
+            pipeline.set_gain(track_gain(&mut dm));
+        }
+        pipeline.process(&mut decode_buf, &mut temp_buf);
+
+        if dm.take_track_change() {
+            // DoP writes two device frames for every dsd word
+            let factor = if spec.mode == OutputMode::DoP { 2 } else { 1 };
+            let pending = || (feeder.prod.occupied_len() + temp_buf.len()) / spec.channel as usize * factor;
+            progress.next_track(pending, dm.duration());
+            pipeline.set_gain(track_gain(&mut dm));
+            now_playing(&dm);
+        }
+
+        match decoded {
+            // pushed with the next round
+            Ok(_) => {},
+            Err(DecoderError::EOF) => eof = true,
+            Err(DecoderError::SpecChanged) => respec = true,
+            Err(DecoderError::Ignored) => {},
+            Err(DecoderError::Abandoned(e)) => {
+                // finish what was decoded, then move on like at the end of the track
+                let _ = events.send(PlayerEvent::TrackAbandoned(*e));
+                eof = true;
+            },
+            Err(e) => {
+                let _ = events.send(PlayerEvent::DecodeError(e));
+            },
+        }
+
+        // the device stops or is reopened next, the filter tail plays before that
+        if eof || respec {
+            pipeline.flush(&mut temp_buf);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::RING_BUF_ALLOC;
+
+    const CD: MediaSpec = MediaSpec { sample_rate: 44100, channel: 2, bits: Some(16), mode: OutputMode::PCM };
+
+    #[test]
+    fn watermarks_leave_room_for_a_burst() {
+        assert!(low_watermark(RING_BUF_ALLOC) < high_watermark(RING_BUF_ALLOC));
+        assert!(high_watermark(RING_BUF_ALLOC) < RING_BUF_ALLOC);
+        assert_eq!(high_watermark(800), 700);
+        assert_eq!(low_watermark(800), 400);
+    }
+
+    #[test]
+    fn prebuffer_holds_the_milliseconds_asked_for() {
+        assert_eq!(prebuffer_len(500, CD, 44100, RING_BUF_ALLOC), 44100);
+        assert_eq!(prebuffer_len(0, CD, 44100, RING_BUF_ALLOC), 0);
+
+        // the device rate counts, not the source one
+        assert_eq!(prebuffer_len(1000, CD, 96000, RING_BUF_ALLOC), 192_000);
+
+        // DoP frames carry half a dsd word of the ring
+        let dop = MediaSpec { sample_rate: 2_822_400, channel: 2, bits: Some(1), mode: OutputMode::DoP };
+        assert_eq!(prebuffer_len(1000, dop, 176_400, RING_BUF_ALLOC), 176_400);
+    }
+
+    #[test]
+    fn prebuffer_never_waits_beyond_the_high_watermark() {
+        assert_eq!(prebuffer_len(60_000, CD, 44100, RING_BUF_ALLOC), high_watermark(RING_BUF_ALLOC));
+        assert_eq!(prebuffer_len(500, CD, 44100, 1000), 875);
+    }
+}
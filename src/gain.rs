@@ -0,0 +1,270 @@
+/// Which ReplayGain value is applied.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, clap::ValueEnum)]
+pub enum GainMode {
+    #[default]
+    Off,
+    Track,
+    Album,
+    /// album gain while tracks of one album play in a row, track gain otherwise
+    Auto,
+}
+
+/// Loudness normalization read from tags, gains in dB against the ReplayGain
+/// reference, peaks linear.
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub struct ReplayGain {
+    pub track_gain: Option<f32>,
+    pub track_peak: Option<f32>,
+    pub album_gain: Option<f32>,
+    pub album_peak: Option<f32>,
+}
+
+/// R128 gains are relative to -23 LUFS, ReplayGain to -18 LUFS.
+const R128_TO_REPLAYGAIN: f32 = 5.0;
+
+/// Leading number of values like `-6.54 dB`.
+fn parse_number(value: &str) -> Option<f32> {
+    let value = value.trim();
+    let end = value
+        .find(|c: char| !(c.is_ascii_digit() || matches!(c, '-' | '+' | '.')))
+        .unwrap_or(value.len());
+    value[..end].parse().ok()
+}
+
+impl ReplayGain {
+    /// Apply one tag, keys are matched case insensitive.
+    pub fn set_tag(&mut self, key: &str, value: &str) {
+        match key.to_ascii_uppercase().as_str() {
+            "REPLAYGAIN_TRACK_GAIN" => self.track_gain = parse_number(value),
+            "REPLAYGAIN_TRACK_PEAK" => self.track_peak = parse_number(value),
+            "REPLAYGAIN_ALBUM_GAIN" => self.album_gain = parse_number(value),
+            "REPLAYGAIN_ALBUM_PEAK" => self.album_peak = parse_number(value),
+            // Q7.8 fixed point, only used when no ReplayGain tag exists
+            "R128_TRACK_GAIN" if self.track_gain.is_none() => {
+                self.track_gain = parse_number(value).map(|v| v / 256.0 + R128_TO_REPLAYGAIN);
+            },
+            "R128_ALBUM_GAIN" if self.album_gain.is_none() => {
+                self.album_gain = parse_number(value).map(|v| v / 256.0 + R128_TO_REPLAYGAIN);
+            },
+            _ => {},
+        }
+    }
+
+    /// ReplayGain from TXXX frames, RVA2 frames fill what is missing.
+    pub fn from_id3(tag: &id3::Tag) -> Self {
+        let mut gain = Self::default();
+        for text in tag.extended_texts() {
+            gain.set_tag(&text.description, &text.value);
+        }
+
+        for frame in tag.frames().filter(|f| f.id() == "RVA2") {
+            let Some((album, adjust, peak)) = frame.content().to_unknown().ok().and_then(|u| Self::parse_rva2(&u.data)) else {
+                continue;
+            };
+
+            if album {
+                gain.album_gain = gain.album_gain.or(Some(adjust));
+                gain.album_peak = gain.album_peak.or(peak);
+            } else {
+                gain.track_gain = gain.track_gain.or(Some(adjust));
+                gain.track_peak = gain.track_peak.or(peak);
+            }
+        }
+
+        gain
+    }
+
+    /// Master volume of a RVA2 frame: is album, adjustment in dB, linear peak.
+    fn parse_rva2(data: &[u8]) -> Option<(bool, f32, Option<f32>)> {
+        let id_end = data.iter().position(|b| *b == 0)?;
+        let album = String::from_utf8_lossy(&data[..id_end]).eq_ignore_ascii_case("album");
+
+        let mut rest = &data[id_end + 1..];
+        while rest.len() >= 4 {
+            let channel = rest[0];
+            let adjust = i16::from_be_bytes([rest[1], rest[2]]) as f32 / 512.0;
+            let peak_bits = rest[3] as usize;
+            let peak_len = peak_bits.div_ceil(8);
+            let peak_bytes = rest.get(4..4 + peak_len)?;
+
+            // 1 is the master volume
+            if channel == 1 {
+                let peak = (peak_bits > 0 && peak_bits <= 32).then(|| {
+                    let raw = peak_bytes.iter().fold(0u64, |v, b| v << 8 | *b as u64);
+                    (raw >> (peak_len * 8 - peak_bits)) as f32 / (1u64 << (peak_bits - 1)) as f32
+                });
+                return Some((album, adjust, peak));
+            }
+
+            rest = &rest[4 + peak_len..];
+        }
+
+        None
+    }
+
+    /// Linear factor for `mode`, limited so the tagged peak does not clip.
+    /// `in_album` tells auto mode whether the neighbouring tracks share this album.
+    pub fn factor(&self, mode: GainMode, preamp: f32, in_album: bool) -> Option<f32> {
+        let album = match mode {
+            GainMode::Off => return None,
+            GainMode::Track => false,
+            GainMode::Album => true,
+            GainMode::Auto => in_album,
+        };
+
+        let (gain, peak) = if album && self.album_gain.is_some() {
+            (self.album_gain, self.album_peak)
+        } else {
+            (self.track_gain.or(self.album_gain), self.track_peak.or(self.album_peak))
+        };
+
+        let factor = 10f32.powf((gain? + preamp) / 20.0);
+        Some(match peak {
+            Some(peak) if peak > 0.0 => factor.min(1.0 / peak),
+            _ => factor,
+        })
+    }
+}
+
+/// Scale S32 samples by a linear factor, saturating at full scale.
+pub fn apply<'a>(factor: f32, samples: impl Iterator<Item = &'a mut i32>) {
+    for s in samples {
+        *s = (*s as f64 * factor as f64).clamp(i32::MIN as f64, i32::MAX as f64) as i32;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: Option<f32>, b: f32) -> bool {
+        a.is_some_and(|a| (a - b).abs() < 1e-4)
+    }
+
+    #[test]
+    fn r128_gain_is_moved_to_the_replaygain_reference() {
+        let mut gain = ReplayGain::default();
+        gain.set_tag("r128_track_gain", "-1536");
+        gain.set_tag("R128_ALBUM_GAIN", "256");
+        // -6dB and +1dB against -23 LUFS
+        assert!(close(gain.track_gain, -1.0));
+        assert!(close(gain.album_gain, 6.0));
+    }
+
+    #[test]
+    fn replaygain_tags_win_over_r128_in_any_order() {
+        let mut first = ReplayGain::default();
+        first.set_tag("REPLAYGAIN_TRACK_GAIN", "-6.54 dB");
+        first.set_tag("R128_TRACK_GAIN", "-1536");
+
+        let mut last = ReplayGain::default();
+        last.set_tag("R128_TRACK_GAIN", "-1536");
+        last.set_tag("replaygain_track_gain", "-6.54 dB");
+
+        assert!(close(first.track_gain, -6.54));
+        assert!(close(last.track_gain, -6.54));
+    }
+
+    #[test]
+    fn peaks_and_unknown_tags() {
+        let mut gain = ReplayGain::default();
+        gain.set_tag("REPLAYGAIN_TRACK_PEAK", "0.988553");
+        gain.set_tag("REPLAYGAIN_ALBUM_PEAK", " 1.000000 ");
+        gain.set_tag("TITLE", "-3");
+        assert!(close(gain.track_peak, 0.988553));
+        assert!(close(gain.album_peak, 1.0));
+        assert_eq!(gain.track_gain, None);
+    }
+
+    /// A RVA2 frame body: identification, then channel, Q9 adjustment, peak bits and peak.
+    fn rva2(id: &str, channels: &[(u8, i16, u8, &[u8])]) -> Vec<u8> {
+        let mut data = id.as_bytes().to_vec();
+        data.push(0);
+        for (channel, adjust, bits, peak) in channels {
+            data.push(*channel);
+            data.extend(adjust.to_be_bytes());
+            data.push(*bits);
+            data.extend(*peak);
+        }
+        data
+    }
+
+    #[test]
+    fn rva2_takes_the_master_channel() {
+        // front right first, then master at -3dB with a 16 bit peak of half scale
+        let data = rva2("track", &[(2, 512 * 6, 8, &[0xff]), (1, -512 * 3, 16, &[0x40, 0x00])]);
+        let (album, adjust, peak) = ReplayGain::parse_rva2(&data).unwrap();
+        assert!(!album);
+        assert_eq!(adjust, -3.0);
+        assert_eq!(peak, Some(0.5));
+
+        // no master channel, no gain
+        assert_eq!(ReplayGain::parse_rva2(&rva2("track", &[(2, 512, 0, &[])])), None);
+    }
+
+    #[test]
+    fn rva2_peak_width_is_decoded() {
+        // 12 bits hold the top of two bytes
+        let (album, adjust, peak) = ReplayGain::parse_rva2(&rva2("Album", &[(1, 256, 12, &[0x60, 0x00])])).unwrap();
+        assert!(album);
+        assert_eq!(adjust, 0.5);
+        assert_eq!(peak, Some(0.75));
+
+        // no peak bits, no peak
+        let (_, _, peak) = ReplayGain::parse_rva2(&rva2("", &[(1, 0, 0, &[])])).unwrap();
+        assert_eq!(peak, None);
+
+        // a peak cut short
+        assert_eq!(ReplayGain::parse_rva2(&rva2("", &[(1, 0, 16, &[0x40])])), None);
+    }
+
+    fn tagged() -> ReplayGain {
+        ReplayGain {
+            track_gain: Some(-6.0),
+            track_peak: Some(0.5),
+            album_gain: Some(-8.0),
+            album_peak: Some(0.9),
+        }
+    }
+
+    #[test]
+    fn factor_follows_the_mode() {
+        let gain = tagged();
+        let db = |f: Option<f32>| 20.0 * f.unwrap().log10();
+
+        assert_eq!(gain.factor(GainMode::Off, 0.0, true), None);
+        assert!((db(gain.factor(GainMode::Track, 0.0, true)) + 6.0).abs() < 1e-3);
+        assert!((db(gain.factor(GainMode::Album, 0.0, false)) + 8.0).abs() < 1e-3);
+        assert!((db(gain.factor(GainMode::Auto, 0.0, true)) + 8.0).abs() < 1e-3);
+        assert!((db(gain.factor(GainMode::Auto, 0.0, false)) + 6.0).abs() < 1e-3);
+
+        // preamp adds to the tag, album mode falls back to the track gain
+        assert!((db(gain.factor(GainMode::Track, 2.5, false)) + 3.5).abs() < 1e-3);
+        let single = ReplayGain { album_gain: None, album_peak: None, ..gain };
+        assert!((db(single.factor(GainMode::Album, 0.0, true)) + 6.0).abs() < 1e-3);
+
+        assert_eq!(ReplayGain::default().factor(GainMode::Track, 0.0, false), None);
+    }
+
+    #[test]
+    fn factor_is_limited_by_the_peak() {
+        let boosted = ReplayGain { track_gain: Some(12.0), track_peak: Some(0.5), ..Default::default() };
+        assert_eq!(boosted.factor(GainMode::Track, 0.0, false), Some(2.0));
+        assert_eq!(boosted.factor(GainMode::Track, 6.0, false), Some(2.0));
+
+        // no peak, nothing to limit by
+        let blind = ReplayGain { track_gain: Some(12.0), ..Default::default() };
+        assert!(blind.factor(GainMode::Track, 0.0, false).unwrap() > 3.9);
+    }
+
+    #[test]
+    fn apply_saturates_instead_of_wrapping() {
+        let mut samples = [i32::MAX / 2 + 10, i32::MIN / 2 - 10, 1000, -1000, 0];
+        apply(2.0, samples.iter_mut());
+        assert_eq!(samples, [i32::MAX, i32::MIN, 2000, -2000, 0]);
+
+        let mut samples = [i32::MAX, i32::MIN];
+        apply(0.5, samples.iter_mut());
+        assert_eq!(samples, [i32::MAX / 2, i32::MIN / 2]);
+    }
+}
@@ -0,0 +1,446 @@
+use std::{
+    io::{self, Read, Seek, SeekFrom},
+    path::Path,
+    sync::{mpsc::{sync_channel, Receiver, Sender, SyncSender}, Mutex},
+    thread,
+    time::Duration,
+};
+
+use anyhow::Result;
+use symphonia::core::{io::MediaSource, probe::Hint};
+use ureq::{http::Response, Agent, Body};
+
+use crate::event::PlayerEvent;
+
+/// Bytes of one prefetched chunk.
+const CHUNK: usize = 16 * 1024;
+/// Chunks read ahead of the decoder, 1MiB.
+const PREFETCH_CHUNKS: usize = 64;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub fn is_url(p: &Path) -> bool {
+    p.to_str().is_some_and(|s| s.starts_with("http://") || s.starts_with("https://"))
+}
+
+/// What the reader thread passes on, in body order.
+enum Prefetched {
+    Audio(Vec<u8>),
+    /// ICY title of the audio after it
+    Title(String),
+}
+
+/// Media over HTTP(S). A background thread reads ahead of the decoder, servers
+/// that accept byte ranges are seekable, ICY stream titles are sent as events
+/// once the decoder reads the audio they belong to.
+pub struct HttpSource {
+    agent: Agent,
+    url: String,
+    /// total length, none for live streams
+    len: Option<u64>,
+    seekable: bool,
+    pos: u64,
+    /// chunks of the reader thread, closed at the end of the body
+    rx: Mutex<Receiver<io::Result<Prefetched>>>,
+    chunk: Vec<u8>,
+    chunk_pos: usize,
+    events: Option<Sender<PlayerEvent>>,
+}
+
+impl HttpSource {
+    /// Connect to `url`, the hint comes from the content type and the url extension.
+    pub fn open(url: &str, events: Option<Sender<PlayerEvent>>) -> Result<(Self, Hint)> {
+        let agent: Agent = Agent::config_builder()
+            .timeout_connect(Some(CONNECT_TIMEOUT))
+            .build()
+            .into();
+
+        // radio servers interleave titles into the body only when asked to
+        let resp = agent.get(url).header("Icy-MetaData", "1").call()?;
+        let header = |name: &str| resp.headers().get(name).and_then(|v| v.to_str().ok()).map(str::to_owned);
+
+        let len: Option<u64> = header("content-length").and_then(|v| v.parse().ok());
+        let seekable = len.is_some() && header("accept-ranges").is_some_and(|v| v.eq_ignore_ascii_case("bytes"));
+        let metaint: Option<usize> = header("icy-metaint").and_then(|v| v.parse().ok());
+
+        let mut hint = Hint::new();
+        if let Some(mime) = header("content-type") {
+            hint.mime_type(mime.split(';').next().unwrap_or_default().trim());
+        }
+        let path = url.split(['?', '#']).next().unwrap_or(url);
+        if let Some(ext) = Path::new(path).extension().and_then(|e| e.to_str()) {
+            hint.with_extension(ext);
+        }
+
+        let rx = prefetch(resp, metaint);
+        let source = Self {
+            agent,
+            url: url.to_owned(),
+            len,
+            seekable,
+            pos: 0,
+            rx: Mutex::new(rx),
+            chunk: Vec::new(),
+            chunk_pos: 0,
+            events,
+        };
+
+        Ok((source, hint))
+    }
+}
+
+/// Read `resp` on a new thread, with ICY titles taken out of the body.
+fn prefetch(resp: Response<Body>, metaint: Option<usize>) -> Receiver<io::Result<Prefetched>> {
+    let (tx, rx) = sync_channel(PREFETCH_CHUNKS);
+    let body = resp.into_body().into_reader();
+
+    thread::spawn(move || match metaint {
+        Some(metaint) if metaint > 0 => read_chunks(IcyReader::new(body, metaint), tx, IcyReader::take_title),
+        _ => read_chunks(body, tx, |_| None),
+    });
+
+    rx
+}
+
+/// Send chunks until the body ends or the source is dropped, `title` tells a
+/// title change right before the chunk just read.
+fn read_chunks<R: Read>(
+    mut body: R,
+    tx: SyncSender<io::Result<Prefetched>>,
+    mut title: impl FnMut(&mut R) -> Option<String>,
+) {
+    loop {
+        let mut chunk = vec![0u8; CHUNK];
+        match body.read(&mut chunk) {
+            Ok(0) => return,
+            Ok(n) => {
+                chunk.truncate(n);
+                if let Some(title) = title(&mut body)
+                    && tx.send(Ok(Prefetched::Title(title))).is_err()
+                {
+                    return;
+                }
+                if tx.send(Ok(Prefetched::Audio(chunk))).is_err() {
+                    return;
+                }
+            },
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {},
+            Err(e) => {
+                let _ = tx.send(Err(e));
+                return;
+            },
+        }
+    }
+}
+
+impl Read for HttpSource {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        while self.chunk_pos == self.chunk.len() {
+            let next = self.rx.get_mut().map_err(|_| io::Error::other("prefetch poisoned"))?.recv();
+            match next {
+                Ok(Ok(Prefetched::Audio(chunk))) => {
+                    self.chunk = chunk;
+                    self.chunk_pos = 0;
+                },
+                // the decoder reached the audio of the title
+                Ok(Ok(Prefetched::Title(title))) => {
+                    if let Some(events) = &self.events {
+                        let _ = events.send(PlayerEvent::NowPlaying(title));
+                    }
+                },
+                Ok(Err(e)) => return Err(e),
+                // the reader thread is done, end of body
+                Err(_) => return Ok(0),
+            }
+        }
+
+        let n = out.len().min(self.chunk.len() - self.chunk_pos);
+        out[..n].copy_from_slice(&self.chunk[self.chunk_pos..self.chunk_pos + n]);
+        self.chunk_pos += n;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for HttpSource {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let target = match pos {
+            SeekFrom::Start(n) => Some(n),
+            SeekFrom::Current(d) => self.pos.checked_add_signed(d),
+            SeekFrom::End(d) => self.len.and_then(|len| len.checked_add_signed(d)),
+        };
+        let target = target.ok_or(io::Error::new(io::ErrorKind::InvalidInput, "invalid seek"))?;
+        if target == self.pos {
+            return Ok(target);
+        }
+        if !self.seekable {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, "server does not accept ranges"));
+        }
+
+        // a new request from the target, the old reader thread stops with its channel
+        let resp = self.agent
+            .get(&self.url)
+            .header("Range", format!("bytes={target}-"))
+            .call()
+            .map_err(io::Error::other)?;
+        let skip = match resp.status().as_u16() {
+            206 => 0,
+            // the range was ignored, the body starts over
+            200 => target,
+            status => return Err(io::Error::other(format!("range request answered with {status}"))),
+        };
+
+        *self.rx.get_mut().map_err(|_| io::Error::other("prefetch poisoned"))? = prefetch(resp, None);
+        self.chunk.clear();
+        self.chunk_pos = 0;
+        self.pos = target - skip;
+
+        if skip > 0 && io::copy(&mut self.by_ref().take(skip), &mut io::sink())? < skip {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "body ended before the seek target"));
+        }
+        Ok(target)
+    }
+}
+
+impl MediaSource for HttpSource {
+    fn is_seekable(&self) -> bool {
+        self.seekable
+    }
+
+    fn byte_len(&self) -> Option<u64> {
+        self.len
+    }
+}
+
+/// Takes the metadata blocks out of a Shoutcast/Icecast body. Every `metaint`
+/// audio bytes a length byte follows, then that many 16 byte blocks of text
+/// like `StreamTitle='Artist - Title';`.
+struct IcyReader<R> {
+    inner: R,
+    metaint: usize,
+    /// audio bytes before the next metadata block
+    left: usize,
+    title: Option<String>,
+    /// the title changed before the audio read last
+    changed: bool,
+}
+
+impl<R: Read> IcyReader<R> {
+    fn new(inner: R, metaint: usize) -> Self {
+        Self {
+            inner,
+            metaint,
+            left: metaint,
+            title: None,
+            changed: false,
+        }
+    }
+
+    /// The new title if it changed before the audio read last, reads never span a metadata block.
+    fn take_title(&mut self) -> Option<String> {
+        std::mem::take(&mut self.changed).then(|| self.title.clone()).flatten()
+    }
+
+    /// Read the next metadata block, false when the body ended before it.
+    fn read_metadata(&mut self) -> io::Result<bool> {
+        let mut len = [0u8; 1];
+        if self.inner.read(&mut len)? == 0 {
+            return Ok(false);
+        }
+        let mut meta = vec![0u8; len[0] as usize * 16];
+        self.inner.read_exact(&mut meta)?;
+
+        // the block is padded with zeros, the title may contain quotes itself
+        let text = String::from_utf8_lossy(&meta);
+        let title = text.split_once("StreamTitle='").map(|(_, rest)| {
+            let rest = rest.trim_end_matches('\0');
+            rest.split_once("';").map_or(rest, |(title, _)| title).to_owned()
+        });
+
+        // empty blocks repeat the last title
+        if let Some(title) = title && self.title.as_ref() != Some(&title) {
+            self.title = Some(title);
+            self.changed = true;
+        }
+
+        Ok(true)
+    }
+}
+
+impl<R: Read> Read for IcyReader<R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        if self.left == 0 {
+            if !self.read_metadata()? {
+                return Ok(0);
+            }
+            self.left = self.metaint;
+        }
+
+        let len = out.len().min(self.left);
+        let n = self.inner.read(&mut out[..len])?;
+        self.left -= n;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufRead, BufReader, Write},
+        net::TcpListener,
+        sync::mpsc::channel,
+    };
+
+    use super::*;
+
+    /// Serve `body` with the extra `headers` on a local port, `ranges` answers a
+    /// Range request with its part, otherwise the whole body is sent.
+    fn serve(body: Vec<u8>, headers: &'static str, ranges: bool) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let body = body.clone();
+                thread::spawn(move || {
+                    let mut start = 0;
+                    let mut request = BufReader::new(stream.try_clone().unwrap());
+                    loop {
+                        let mut line = String::new();
+                        if request.read_line(&mut line).unwrap() == 0 || line == "\r\n" {
+                            break;
+                        }
+                        if let Some(range) = line.to_ascii_lowercase().strip_prefix("range: bytes=")
+                            && ranges
+                        {
+                            start = range.trim().trim_end_matches('-').parse().unwrap();
+                        }
+                    }
+
+                    let status = if start > 0 { "206 Partial Content" } else { "200 OK" };
+                    let part = &body[start..];
+                    let _ = write!(stream, "HTTP/1.1 {status}\r\nContent-Length: {}\r\n{headers}Connection: close\r\n\r\n", part.len());
+                    let _ = stream.write_all(part);
+                });
+            }
+        });
+
+        format!("http://{addr}/stream.bin")
+    }
+
+    fn data(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 7 % 251) as u8).collect()
+    }
+
+    fn read_at(source: &mut HttpSource, pos: u64, len: usize) -> Vec<u8> {
+        source.seek(SeekFrom::Start(pos)).unwrap();
+        let mut buf = vec![0; len];
+        source.read_exact(&mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn seeks_with_range_requests() {
+        let body = data(200_000);
+        let url = serve(body.clone(), "Accept-Ranges: bytes\r\n", true);
+        let (mut source, _) = HttpSource::open(&url, None).unwrap();
+
+        assert!(source.is_seekable());
+        assert_eq!(source.byte_len(), Some(200_000));
+        assert_eq!(read_at(&mut source, 150_000, 64), body[150_000..150_064]);
+        assert_eq!(read_at(&mut source, 10, 64), body[10..74]);
+        assert_eq!(source.seek(SeekFrom::End(-100)).unwrap(), 199_900);
+    }
+
+    #[test]
+    fn ignored_range_reads_up_to_the_target() {
+        let body = data(200_000);
+        // ranges are announced but every request gets the whole body
+        let url = serve(body.clone(), "Accept-Ranges: bytes\r\n", false);
+        let (mut source, _) = HttpSource::open(&url, None).unwrap();
+
+        assert_eq!(read_at(&mut source, 150_000, 64), body[150_000..150_064]);
+        assert_eq!(source.stream_position().unwrap(), 150_064);
+    }
+
+    #[test]
+    fn unseekable_without_accept_ranges() {
+        let url = serve(data(1000), "", true);
+        let (mut source, _) = HttpSource::open(&url, None).unwrap();
+
+        assert!(!source.is_seekable());
+        assert!(source.seek(SeekFrom::Start(500)).is_err());
+    }
+
+    /// `audio` with a metadata block every `metaint` bytes, `titles[i]` in the i-th block.
+    fn icy(audio: &[u8], metaint: usize, titles: &[Option<&str>]) -> Vec<u8> {
+        let mut body = Vec::new();
+        for (i, chunk) in audio.chunks(metaint).enumerate() {
+            if i > 0 {
+                let meta = titles
+                    .get(i - 1)
+                    .copied()
+                    .flatten()
+                    .map(|t| format!("StreamTitle='{t}';StreamUrl='';"))
+                    .unwrap_or_default();
+                let blocks = meta.len().div_ceil(16);
+                body.push(blocks as u8);
+                body.extend(meta.as_bytes());
+                body.resize(body.len() + blocks * 16 - meta.len(), 0);
+            }
+            body.extend(chunk);
+        }
+        body
+    }
+
+    #[test]
+    fn icy_reader_takes_titles_out_of_the_audio() {
+        let audio = data(5000);
+        let body = icy(&audio, 1000, &[Some("It's A - B"), None, Some("It's A - B"), Some("C - D")]);
+
+        let mut reader = IcyReader::new(io::Cursor::new(body), 1000);
+        let mut out: Vec<u8> = Vec::new();
+        let mut titles = Vec::new();
+        let mut buf = [0; 700];
+        loop {
+            let n = reader.read(&mut buf).unwrap();
+            if n == 0 {
+                break;
+            }
+            if let Some(title) = reader.take_title() {
+                titles.push((out.len(), title));
+            }
+            out.extend(&buf[..n]);
+        }
+
+        assert_eq!(out, audio);
+        // repeated and empty blocks keep the title
+        assert_eq!(titles, [(1000, "It's A - B".to_owned()), (4000, "C - D".to_owned())]);
+    }
+
+    #[test]
+    fn titles_are_sent_once_their_audio_is_read() {
+        let audio = data(5000);
+        let body = icy(&audio, 1000, &[Some("A - B"), None, Some("C - D")]);
+        let url = serve(body, "icy-metaint: 1000\r\n", false);
+
+        let (tx, rx) = channel();
+        let (mut source, _) = HttpSource::open(&url, Some(tx)).unwrap();
+        let titles = || rx.try_iter().map(|e| e.to_string()).collect::<Vec<_>>();
+
+        let mut buf = vec![0; 1000];
+        source.read_exact(&mut buf).unwrap();
+        // the reader thread is far ahead, the title waits for its audio
+        thread::sleep(Duration::from_millis(50));
+        assert!(titles().is_empty());
+
+        source.read_exact(&mut buf[..1]).unwrap();
+        assert_eq!(titles(), ["now playing: A - B"]);
+
+        let mut rest = Vec::new();
+        source.read_to_end(&mut rest).unwrap();
+        assert_eq!(titles(), ["now playing: C - D"]);
+        assert_eq!(rest, audio[1001..]);
+    }
+}
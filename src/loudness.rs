@@ -0,0 +1,382 @@
+use std::collections::VecDeque;
+
+use crate::dsd::lowpass;
+
+/// Loudness of the 400ms gating blocks, 75% overlapped.
+const BLOCK_STEPS: usize = 4;
+/// Short term loudness window of 3s for the loudness range.
+const SHORT_TERM_STEPS: usize = 30;
+/// Blocks advance in 100ms steps.
+const STEPS_PER_SEC: u32 = 10;
+
+const ABSOLUTE_GATE: f64 = -70.0;
+const RELATIVE_GATE: f64 = -10.0;
+const RANGE_RELATIVE_GATE: f64 = -20.0;
+
+/// Taps of every true peak interpolation phase.
+const TRUE_PEAK_TAPS: usize = 16;
+
+/// Loudness of a track or album after ITU-R BS.1770 and EBU Tech 3342.
+#[derive(Clone, Debug, Default)]
+pub struct Loudness {
+    /// integrated loudness in LUFS, none when everything is below the gate
+    pub integrated: Option<f64>,
+    /// loudness range in LU
+    pub range: f64,
+    /// true peak in dBTP
+    pub true_peak: f64,
+    /// mean square of every gating block, kept to measure albums
+    blocks: Vec<f64>,
+    /// mean square of every short term window
+    short_term: Vec<f64>,
+}
+
+fn to_lufs(energy: f64) -> f64 {
+    -0.691 + 10.0 * energy.log10()
+}
+
+impl Loudness {
+    fn new(blocks: Vec<f64>, short_term: Vec<f64>, peak: f64) -> Self {
+        Self {
+            integrated: Self::integrated(&blocks),
+            range: Self::range(&short_term),
+            true_peak: 20.0 * peak.log10(),
+            blocks,
+            short_term,
+        }
+    }
+
+    /// Measure the tracks as one programme, gating runs over the blocks of all of them.
+    pub fn album<'a>(tracks: impl IntoIterator<Item = &'a Loudness>) -> Self {
+        let mut blocks = Vec::new();
+        let mut short_term = Vec::new();
+        let mut peak = f64::NEG_INFINITY;
+        for track in tracks {
+            blocks.extend_from_slice(&track.blocks);
+            short_term.extend_from_slice(&track.short_term);
+            peak = peak.max(track.true_peak);
+        }
+
+        Self {
+            integrated: Self::integrated(&blocks),
+            range: Self::range(&short_term),
+            true_peak: peak,
+            blocks,
+            short_term,
+        }
+    }
+
+    /// Gain in dB to reach the ReplayGain 2 reference of -18 LUFS.
+    pub fn replay_gain(&self) -> Option<f64> {
+        self.integrated.map(|l| -18.0 - l)
+    }
+
+    fn integrated(blocks: &[f64]) -> Option<f64> {
+        let above = |gate: f64| blocks.iter().filter(move |e| to_lufs(**e) > gate);
+        let mean = |gate: f64| {
+            let (sum, count) = above(gate).fold((0.0, 0), |(s, c), e| (s + e, c + 1));
+            (count > 0).then(|| sum / count as f64)
+        };
+
+        let relative = to_lufs(mean(ABSOLUTE_GATE)?) + RELATIVE_GATE;
+        mean(relative.max(ABSOLUTE_GATE)).map(to_lufs)
+    }
+
+    fn range(short_term: &[f64]) -> f64 {
+        let gated: Vec<f64> = short_term.iter().copied().filter(|e| to_lufs(*e) > ABSOLUTE_GATE).collect();
+        if gated.is_empty() {
+            return 0.0;
+        }
+
+        let relative = to_lufs(gated.iter().sum::<f64>() / gated.len() as f64) + RANGE_RELATIVE_GATE;
+        let mut loudness: Vec<f64> = gated
+            .into_iter()
+            .filter(|e| to_lufs(*e) > relative)
+            .map(to_lufs)
+            .collect();
+        if loudness.is_empty() {
+            return 0.0;
+        }
+
+        loudness.sort_by(f64::total_cmp);
+        let percentile = |p: f64| loudness[((loudness.len() - 1) as f64 * p).round() as usize];
+        percentile(0.95) - percentile(0.10)
+    }
+}
+
+/// Direct form I biquad.
+#[derive(Clone, Copy, Default)]
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    x: [f64; 2],
+    y: [f64; 2],
+}
+
+impl Biquad {
+    fn process(&mut self, x: f64) -> f64 {
+        let y = self.b[0] * x + self.b[1] * self.x[0] + self.b[2] * self.x[1]
+            - self.a[0] * self.y[0]
+            - self.a[1] * self.y[1];
+        self.x = [x, self.x[0]];
+        self.y = [y, self.y[0]];
+        y
+    }
+}
+
+/// K-weighting, a high shelf for the head followed by the RLB high pass,
+/// designed for `rate` as in libebur128.
+fn k_weighting(rate: u32) -> [Biquad; 2] {
+    let rate = rate as f64;
+
+    let f0 = 1681.974450955533;
+    let gain = 3.999843853973347;
+    let q = 0.7071752369554196;
+    let k = (std::f64::consts::PI * f0 / rate).tan();
+    let vh = 10f64.powf(gain / 20.0);
+    let vb = vh.powf(0.4996667741545416);
+    let a0 = 1.0 + k / q + k * k;
+    let shelf = Biquad {
+        b: [
+            (vh + vb * k / q + k * k) / a0,
+            2.0 * (k * k - vh) / a0,
+            (vh - vb * k / q + k * k) / a0,
+        ],
+        a: [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        ..Default::default()
+    };
+
+    let f0 = 38.13547087602444;
+    let q = 0.5003270373238773;
+    let k = (std::f64::consts::PI * f0 / rate).tan();
+    let a0 = 1.0 + k / q + k * k;
+    let highpass = Biquad {
+        b: [1.0, -2.0, 1.0],
+        a: [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        ..Default::default()
+    };
+
+    [shelf, highpass]
+}
+
+/// Channel weights for the usual wav and flac orders, surrounds count 1.5dB more
+/// and the LFE of 5.1 is left out.
+fn channel_weights(channel: usize) -> Vec<f64> {
+    match channel {
+        6 => vec![1.0, 1.0, 1.0, 0.0, 1.41, 1.41],
+        5 => vec![1.0, 1.0, 1.0, 1.41, 1.41],
+        n => vec![1.0; n],
+    }
+}
+
+/// Sample peak of the signal oversampled to at least 192kHz.
+struct TruePeak {
+    channel: usize,
+    /// phases of the interpolation filter, each one output sample per input sample
+    phases: Vec<Vec<f64>>,
+    history: Vec<VecDeque<f64>>,
+    peak: f64,
+}
+
+impl TruePeak {
+    fn new(rate: u32, channel: usize) -> Self {
+        let factor = if rate < 96000 {
+            4
+        } else if rate < 192000 {
+            2
+        } else {
+            1
+        };
+
+        let h = lowpass(TRUE_PEAK_TAPS * factor, 0.45 / factor as f64);
+        let phases = (0..factor)
+            .map(|p| h.iter().skip(p).step_by(factor).map(|v| v * factor as f64).collect())
+            .collect();
+
+        Self {
+            channel,
+            phases,
+            history: vec![VecDeque::from(vec![0.0; TRUE_PEAK_TAPS]); channel],
+            peak: 0.0,
+        }
+    }
+
+    fn process(&mut self, ch: usize, x: f64) {
+        let history = &mut self.history[ch % self.channel];
+        history.pop_back();
+        history.push_front(x);
+
+        for phase in &self.phases {
+            let y: f64 = phase.iter().zip(history.iter()).map(|(h, s)| h * s).sum();
+            self.peak = self.peak.max(y.abs());
+        }
+        self.peak = self.peak.max(x.abs());
+    }
+}
+
+/// Streaming loudness meter over interleaved S32 samples of one spec.
+pub struct LoudnessMeter {
+    channel: usize,
+    weights: Vec<f64>,
+    filters: Vec<[Biquad; 2]>,
+    true_peak: TruePeak,
+    step_frames: usize,
+    /// weighted sum of squares in the current step
+    step_energy: f64,
+    step_pos: usize,
+    /// mean square of the last steps, newest first
+    steps: VecDeque<f64>,
+    /// next interleaved channel
+    ch: usize,
+    blocks: Vec<f64>,
+    short_term: Vec<f64>,
+}
+
+impl LoudnessMeter {
+    pub fn new(rate: u32, channel: usize) -> Self {
+        Self {
+            channel,
+            weights: channel_weights(channel),
+            filters: vec![k_weighting(rate); channel],
+            true_peak: TruePeak::new(rate, channel),
+            step_frames: (rate / STEPS_PER_SEC).max(1) as usize,
+            step_energy: 0.0,
+            step_pos: 0,
+            steps: VecDeque::with_capacity(SHORT_TERM_STEPS),
+            ch: 0,
+            blocks: Vec::new(),
+            short_term: Vec::new(),
+        }
+    }
+
+    pub fn process(&mut self, samples: &[i32]) {
+        for s in samples {
+            let x = *s as f64 / (1u64 << 31) as f64;
+            self.true_peak.process(self.ch, x);
+
+            let [shelf, highpass] = &mut self.filters[self.ch];
+            let y = highpass.process(shelf.process(x));
+            self.step_energy += self.weights[self.ch] * y * y;
+
+            self.ch += 1;
+            if self.ch == self.channel {
+                self.ch = 0;
+                self.step_pos += 1;
+                if self.step_pos == self.step_frames {
+                    self.end_step();
+                }
+            }
+        }
+    }
+
+    fn end_step(&mut self) {
+        if self.steps.len() == SHORT_TERM_STEPS {
+            self.steps.pop_back();
+        }
+        self.steps.push_front(self.step_energy / self.step_frames as f64);
+        self.step_energy = 0.0;
+        self.step_pos = 0;
+
+        let mean = |n: usize| self.steps.iter().take(n).sum::<f64>() / n as f64;
+        if self.steps.len() >= BLOCK_STEPS {
+            self.blocks.push(mean(BLOCK_STEPS));
+        }
+        if self.steps.len() >= SHORT_TERM_STEPS {
+            self.short_term.push(mean(SHORT_TERM_STEPS));
+        }
+    }
+
+    /// Loudness of everything processed, the incomplete last step is dropped.
+    pub fn finish(self) -> Loudness {
+        Loudness::new(self.blocks, self.short_term, self.true_peak.peak)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `secs` of a 1kHz tone with `db` dBFS peaks on every channel, interleaved.
+    fn tone(rate: u32, channel: usize, db: f64, secs: f64) -> Vec<i32> {
+        let amplitude = 10f64.powf(db / 20.0) * i32::MAX as f64;
+        (0..(rate as f64 * secs) as usize)
+            .flat_map(|n| {
+                let v = amplitude * (2.0 * std::f64::consts::PI * 1000.0 * n as f64 / rate as f64).sin();
+                vec![v as i32; channel]
+            })
+            .collect()
+    }
+
+    fn measure(rate: u32, channel: usize, parts: &[Vec<i32>]) -> Loudness {
+        let mut meter = LoudnessMeter::new(rate, channel);
+        parts.iter().for_each(|p| meter.process(p));
+        meter.finish()
+    }
+
+    #[test]
+    fn stereo_reference_tone_is_minus_23_lufs() {
+        // EBU Tech 3341 case 1
+        let loudness = measure(48_000, 2, &[tone(48_000, 2, -23.0, 5.0)]);
+
+        let integrated = loudness.integrated.unwrap();
+        assert!((integrated + 23.0).abs() < 0.1, "{integrated}");
+        assert!((loudness.replay_gain().unwrap() - 5.0).abs() < 0.1);
+    }
+
+    #[test]
+    fn silence_has_no_loudness() {
+        let loudness = measure(16_000, 1, &[vec![0; 16_000 * 5]]);
+        assert!(loudness.integrated.is_none());
+        assert!(loudness.replay_gain().is_none());
+        assert_eq!(loudness.range, 0.0);
+    }
+
+    #[test]
+    fn gates_drop_silence_and_quiet_passages() {
+        let rate = 16_000;
+        let alone = measure(rate, 1, &[tone(rate, 1, -20.0, 5.0)]).integrated.unwrap();
+
+        // silence is below the absolute gate, only the blocks over the edge count
+        let silence = measure(rate, 1, &[tone(rate, 1, -20.0, 5.0), vec![0; rate as usize * 5]]);
+        assert!((silence.integrated.unwrap() - alone).abs() < 0.3);
+
+        // 15dB down is below the relative gate
+        let quiet = measure(rate, 1, &[tone(rate, 1, -20.0, 5.0), tone(rate, 1, -35.0, 5.0)]);
+        assert!((quiet.integrated.unwrap() - alone).abs() < 0.3);
+
+        // 5dB down counts
+        let counted = measure(rate, 1, &[tone(rate, 1, -20.0, 5.0), tone(rate, 1, -25.0, 5.0)]);
+        assert!(counted.integrated.unwrap() < alone - 1.0);
+    }
+
+    #[test]
+    fn album_gates_over_every_track() {
+        let rate = 16_000;
+        let loud = measure(rate, 1, &[tone(rate, 1, -20.0, 5.0)]);
+        let quiet = measure(rate, 1, &[tone(rate, 1, -35.0, 5.0)]);
+
+        let album = Loudness::album([&loud, &quiet]);
+        assert!((album.integrated.unwrap() - loud.integrated.unwrap()).abs() < 0.3);
+        assert_eq!(album.true_peak, loud.true_peak);
+    }
+
+    #[test]
+    fn range_spans_the_loud_and_quiet_parts() {
+        // EBU Tech 3342 case 1
+        let rate = 16_000;
+        let loudness = measure(rate, 1, &[tone(rate, 1, -20.0, 10.0), tone(rate, 1, -30.0, 10.0)]);
+        assert!((loudness.range - 10.0).abs() < 1.0, "{}", loudness.range);
+    }
+
+    #[test]
+    fn true_peak_finds_peaks_between_samples() {
+        // a quarter of the rate, sampled at ±45°, every sample is 3dB below the peak
+        let rate = 48_000;
+        let samples: Vec<i32> = (0..rate as usize)
+            .map(|n| (0.5 * (std::f64::consts::PI * (n as f64 / 2.0 + 0.25)).sin() * i32::MAX as f64) as i32)
+            .collect();
+        let loudness = measure(rate, 1, &[samples]);
+
+        assert!((loudness.true_peak + 6.02).abs() < 0.5, "{}", loudness.true_peak);
+    }
+}
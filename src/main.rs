@@ -1,27 +1,61 @@
-use std::{cell::RefCell, collections::VecDeque, path::PathBuf, rc::Rc, sync::mpsc::{channel, Receiver}};
+use std::{
+    cell::RefCell,
+    collections::HashSet,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{mpsc::{channel, Receiver, Sender}, Arc},
+    thread,
+    time::Duration,
+};
 
-use alsa::pcm::State;
 use anyhow::{anyhow, Result};
 use clap::Parser;
 use ringbuf::{
-    storage::Heap,
-    traits::{Consumer, Observer, Producer, Split},
-    LocalRb
+    traits::{Consumer, Observer, Split},
+    HeapRb,
 };
-use tokio::task::{spawn_blocking, JoinHandle};
-use walkdir::{DirEntry, WalkDir};
+use tokio::task::spawn_blocking;
+use walkdir::WalkDir;
 
 use crate::{
-    decoder::{Decoder, DecoderError, DecoderManager}, event::PlayerCommand, player::Player
+    cli::PlayArgs,
+    cue::{CueSheet, CueTrack},
+    decoder::{Decoder, DecoderManager},
+    dsd::DopEncoder,
+    event::{PlayerCommand, PlayerEvent},
+    feed::{DecodeThread, Feed, Reply},
+    media::MediaSpec,
+    pipeline::Pipeline,
+    player::{realtime_priority, Player},
+    progress::Progress,
+    tags::Tags,
 };
 
+mod alsa_output;
 mod cli;
+mod cue;
 mod decoder;
+mod devices;
+mod dither;
+mod dsd;
 mod event;
+mod feed;
+mod gain;
+mod http;
+mod loudness;
 mod media;
+mod output;
+mod pipeline;
 mod player;
+mod progress;
+mod resampler;
+mod sample;
+mod scan;
 mod shared;
+mod source;
 mod store;
+mod tags;
+mod verify;
 
 const I32_BYTE: usize = i32::BITS as usize / 8;
 
@@ -31,6 +65,9 @@ const TMP_BUF_ALLOC: usize = (1024 * 256) / I32_BYTE;
 // 4mb i32
 const RING_BUF_ALLOC: usize = (1024 * 1024 * 4) / I32_BYTE;
 
+// frames handed to the device at once
+const WRITE_FRAMES: usize = 4096;
+
 #[tokio::main]
 async fn main() -> Result<()> {
     let args = cli::Args::parse();
@@ -38,166 +75,301 @@ async fn main() -> Result<()> {
     let (tx, rx) = channel();
 
     match args.command {
-        cli::Commands::Play { path, device } => {
-            let _player_handle: JoinHandle<Result<()>> = spawn_blocking(move || player(path, device, rx));
-            _player_handle.await?
+        cli::Commands::Play(play_args) if play_args.list_tracks => list_tracks(&play_args.path),
+        cli::Commands::Play(play_args) => {
+            let progress = Arc::new(Progress::default());
+            // handled once the first track is open
+            if let Some(start) = play_args.start {
+                tx.send(PlayerCommand::Seek(start))?;
+            }
+            let progress_in_player = progress.clone();
+            let (event_tx, event_rx) = channel();
+            // a thread of its own, the realtime policy it takes dies with it
+            let player_handle = thread::Builder::new()
+                .name("output".to_owned())
+                .spawn(move || player(play_args, rx, event_tx, progress_in_player))?;
+
+            while !player_handle.is_finished() {
+                tokio::time::sleep(Duration::from_millis(500)).await;
+                for event in event_rx.try_iter() {
+                    println!("\r{event}");
+                }
+                print_progress(&progress);
+            }
+            println!();
+            for event in event_rx.try_iter() {
+                println!("{event}");
+            }
+
+            player_handle.join().map_err(|_| anyhow!("output thread panicked"))?
         },
+        cli::Commands::Scan(scan_args) => scan::scan(scan_args).await,
+        cli::Commands::Verify(verify_args) => spawn_blocking(move || verify::verify(verify_args)).await?,
+        cli::Commands::Devices(devices_args) => devices::devices(devices_args),
         cli::Commands::PlayList { command } => {
             todo!()
         },
     }
 }
 
-fn player(path: impl Into<PathBuf>, device: String, rx: Receiver<PlayerCommand>) -> Result<()> {
-    let rb: LocalRb<Heap<i32>> = LocalRb::new(RING_BUF_ALLOC);
-    let (mut prod, mut cons) = rb.split();
-    let mut temp_buf = VecDeque::<i32>::with_capacity(TMP_BUF_ALLOC);
-
-    let mut dm = DecoderManager::default();
-    dm.open(path.into())?;
-    let spec = dm.spec().ok_or(anyhow!("unknown codec"))?;
-    let channel = spec.channel as usize;
-
-    let player = Player::new(&device)?;
-    player.init(spec)?;
-    let io = Rc::new(RefCell::new(Some(player.io_i32())));
-    let io_dsd = Rc::new(RefCell::new(Some(player.io_u32())));
-
-    let spec = Rc::new(RefCell::new(spec));
-
-    let spec_in_fn = spec.clone();
-    let io_in_fn = io.clone();
-    let io_dsd_in_fn = io_dsd.clone();
-
-    #[allow(clippy::type_complexity)]
-    let write_io: Box<dyn Fn(&[i32]) -> anyhow::Result<usize>> = Box::new(move |buf: &[i32]| {
-        match spec_in_fn.borrow().mode {
-            media::OutputMode::PCM => {
-                if let Some(Ok(io)) = &*io_in_fn.borrow() {
-                    Ok(io.writei(buf)? * channel)
-                } else {
-                    Ok(0)
-                }
+fn player(
+    args: PlayArgs,
+    rx: Receiver<PlayerCommand>,
+    events: Sender<PlayerEvent>,
+    progress: Arc<Progress>,
+) -> Result<()> {
+    let args = Arc::new(args);
+    let (prod, mut cons) = HeapRb::<i32>::new(RING_BUF_ALLOC).split();
+    let capacity = cons.capacity().get();
+    let mut decoder = DecodeThread::spawn(args.clone(), prod, progress.clone(), events.clone())?;
+
+    let output = output::open(args.output, args.device.as_deref())?;
+    let mut player = Player::new(output, args.dsd, args.dsd_pcm_rate, args.rate);
+    player.set_event_sender(events.clone());
+
+    // a late write is heard, a late decode is not as long as the ring holds out
+    if args.rt_priority > 0
+        && let Err(e) = realtime_priority(args.rt_priority)
+    {
+        let _ = events.send(PlayerEvent::NoRealtime(format!("SCHED_FIFO {}: {e}", args.rt_priority)));
+    }
+
+    // the decode thread opens the first track, then asks for the device
+    let first = decoder.recv()?;
+    let Feed::Open { src: first_spec, .. } = first else {
+        return Err(anyhow!("nothing to play"));
+    };
+    let spec = RefCell::new(first_spec);
+    let dop = RefCell::new(DopEncoder::new(first_spec.channel as usize));
+    let mut prebuffer = 0;
+
+    // device frames written and samples of `buf` they took
+    let write_io = |buf: &[i32]| -> Result<(usize, usize)> {
+        let spec = *spec.borrow();
+        let channel = spec.channel as usize;
+
+        match spec.mode {
+            media::OutputMode::PCM | media::OutputMode::DsdToPcm | media::OutputMode::DSD => {
+                let frames = player.write(buf)?;
+                Ok((frames, frames * channel))
             },
-            media::OutputMode::DSD => {
-                let buf = unsafe {
-                    std::slice::from_raw_parts(
-                        buf.as_ptr() as *const u32,
-                        buf.len()
-                    )
-                };
+            media::OutputMode::DoP => {
+                let mut dop = dop.borrow_mut();
+                let frames = player.write(dop.encode(buf))?;
 
-                if let Some(Ok(io)) = &*io_dsd_in_fn.borrow() {
-                    Ok(io.writei(buf)? * channel)
-                } else {
-                    Ok(0)
-                }
+                // every dsd word was split into two DoP frames
+                Ok((frames, dop.commit(frames) * channel))
             },
         }
-    });
-
-    let mut eof = false;
+    };
+
+    // reopen the device for a new spec, the pipeline goes back to the decode thread
+    let reinit = |src: MediaSpec, gain: Option<f32>, pos: Option<Duration>, duration: Option<Duration>| -> Result<Reply> {
+        let opened = player.init(src)?;
+        *spec.borrow_mut() = opened;
+        *dop.borrow_mut() = DopEncoder::new(opened.channel as usize);
+        let pipeline = Pipeline::new(&args, &player, src, opened, player.bits(), gain)?;
+        progress.reset(pos.unwrap_or_else(|| progress.elapsed()), player.rate()?, duration);
+        Ok(Reply::Opened { spec: opened, pipeline: Box::new(pipeline) })
+    };
+
+    let mut pending = Some(first);
+    let mut opened = false;
+    // seeks the decode thread did not answer yet, an end sent before them is void
+    let mut seeks = 0;
+    // the device waits for the prebuffer after opening, seeking and running dry
+    let mut prebuffering = true;
+    let mut paused = false;
 
     loop {
         if let Ok(cmd) = rx.try_recv() {
             match cmd {
-                PlayerCommand::Play(media_spec) => {
-                    player.drop()?;
-                    player.init(media_spec)?;
-                    let mut spec = spec.borrow_mut();
-                    *spec = media_spec;
-
-                    drop(io.take());
-                    *io.borrow_mut() = Some(player.io_i32());
-                    drop(io_dsd.take());
-                    *io_dsd.borrow_mut() = Some(player.io_u32());
-                },
                 PlayerCommand::Resume => {
                     player.pause(false)?;
+                    paused = false;
                 },
                 PlayerCommand::Pause => {
                     player.pause(true)?;
+                    paused = true;
+                },
+                // the decode thread answers with the feed
+                cmd => {
+                    if matches!(cmd, PlayerCommand::Seek(_)) {
+                        seeks += 1;
+                    }
+                    decoder.command(cmd);
                 },
             }
         }
 
-        player.wait(Some(32))?;
-        if !matches!(player.state(), State::Running | State::Prepared) {
-            player.prepare()?;
+        if pending.is_none() {
+            pending = decoder.try_recv()?;
         }
 
-        // consume the last data in ring buffer
-        if !cons.is_empty() {
-            let (right, left) = cons.as_slices();
-            let wr = write_io(right)?;
-            let wl = write_io(left)?;
-            cons.skip(wr + wl);
-        }
+        match pending.take() {
+            Some(Feed::End) if seeks > 0 => continue,
+            Some(Feed::Seeked { pos, duration }) => {
+                // nothing decoded before the jump may reach the device
+                seeks -= 1;
+                cons.clear();
+                player.discard()?;
+                dop.borrow_mut().reset();
+                progress.reset(pos, player.rate()?, duration);
+                decoder.reply(Reply::Cleared);
+                prebuffering = true;
+                continue;
+            },
+            // the ring is played out, the device goes on with the next spec or stops
+            Some(feed @ (Feed::Open { .. } | Feed::End)) if cons.is_empty() => {
+                if opened {
+                    player.drain()?;
+                    progress.set_delay(0);
+                }
+                let Feed::Open { src, gain, pos, duration } = feed else {
+                    break;
+                };
 
-        if !temp_buf.is_empty() {
-            let write_to_rb = prod.vacant_len().min(temp_buf.len());
-            let data = temp_buf.drain(..write_to_rb);
-            prod.push_iter(data);
+                let reply = reinit(src, gain, pos, duration)?;
+                prebuffer = feed::prebuffer_len(args.prebuffer, *spec.borrow(), player.rate()?, capacity);
+                decoder.reply(reply);
+                opened = true;
+                prebuffering = true;
+                continue;
+            },
+            feed => pending = feed,
         }
 
-        if temp_buf.is_empty() {
-            temp_buf.shrink_to(TMP_BUF_ALLOC);
+        if paused {
+            thread::park_timeout(feed::IDLE);
+            continue;
         }
 
-        if !prod.is_empty() {
+        // a waiting decode thread pushes nothing more
+        if prebuffering && cons.occupied_len() < prebuffer && pending.is_none() {
+            thread::park_timeout(feed::IDLE);
             continue;
         }
+        prebuffering = false;
 
-        // todo return eof event to controller
-        if prod.is_empty() && eof {
-            break;
+        player.wait(Duration::from_millis(32))?;
+
+        if let Ok(delay) = player.delay() {
+            progress.set_delay(delay);
         }
 
-        match dm.decode(&mut temp_buf) {
-            Ok(_) => {
-                let (right, left) = temp_buf.as_slices();
-                let wr = write_io(right)?;
-                let wl = write_io(left)?;
-                temp_buf.drain(..(wr + wl));
-
-                if !temp_buf.is_empty() {
-                    let write_to_rb = prod.vacant_len().min(temp_buf.len());
-                    let data = temp_buf.drain(..write_to_rb);
-                    prod.push_iter(data);
-                }
-            },
-            Err(DecoderError::EOF) => {
-                eof = true;
-                continue;
-            },
-            Err(DecoderError::Ignored) => { },
-            Err(_) => {
-                continue;
-            },
+        if cons.is_empty() {
+            // the decode thread fell behind
+            prebuffering = pending.is_none();
+            thread::park_timeout(feed::IDLE);
+            continue;
         }
 
-        if !matches!(player.state(), State::Running|State::Paused) {
-            player.start()?;
+        // small writes keep commands and the progress going while the device takes them
+        let chunk = WRITE_FRAMES * spec.borrow().channel as usize;
+        let (right, _) = cons.as_slices();
+        let (frames, written) = write_io(&right[..right.len().min(chunk)])?;
+        progress.add_written(frames, || {
+            cons.skip(written);
+        });
+
+        if cons.occupied_len() <= feed::low_watermark(capacity) {
+            decoder.wake();
+        }
+    }
+
+    decoder.finish()?;
+
+    let stats = player.stats();
+    if !stats.is_empty() {
+        let _ = events.send(PlayerEvent::XrunSummary(stats));
+    }
+
+    Ok(())
+}
+
+fn list_tracks(paths: &[PathBuf]) -> Result<()> {
+    for p in paths {
+        let mut dm = DecoderManager::default();
+        dm.open(p.clone())?;
+
+        println!("{}", p.display());
+        print_tags(&dm.metadata());
+        for track in dm.tracks() {
+            println!("  {track}");
         }
     }
 
-    player.drain()?;
     Ok(())
 }
 
-fn all_media_path(p: PathBuf) -> Vec<PathBuf> {
-    WalkDir::new(p)
+fn print_tags(tags: &Tags) {
+    let field = |name: &str, value: Option<String>| {
+        if let Some(value) = value.filter(|v| !v.is_empty()) {
+            println!("  {name}: {value}");
+        }
+    };
+    let pair = |n: Option<u32>, total: Option<u32>| match (n, total) {
+        (Some(n), Some(total)) => Some(format!("{n}/{total}")),
+        (n, _) => n.map(|n| n.to_string()),
+    };
+
+    field("title", tags.title.clone());
+    field("artist", Some(tags.artists.join(", ")));
+    field("album", tags.album.clone());
+    field("album artist", Some(tags.album_artists.join(", ")));
+    field("track", pair(tags.track_number, tags.track_total));
+    field("disc", pair(tags.disc_number, tags.disc_total));
+    field("date", tags.date.clone());
+    field("genre", Some(tags.genres.join(", ")));
+    field("composer", Some(tags.composers.join(", ")));
+    field("musicbrainz recording", tags.musicbrainz.recording_id.clone());
+    field("musicbrainz album", tags.musicbrainz.album_id.clone());
+    for picture in &tags.pictures {
+        let kind = if picture.front_cover { "front cover" } else { "picture" };
+        println!("  {kind}: {}, {} bytes", picture.mime, picture.data.len());
+    }
+}
+
+fn print_progress(progress: &Progress) {
+    let mmss = |d: Duration| format!("{:02}:{:02}", d.as_secs() / 60, d.as_secs() % 60);
+    let elapsed = mmss(progress.elapsed());
+    match progress.duration().zip(progress.remaining()) {
+        Some((duration, remaining)) => {
+            print!("\r{elapsed} / {} (-{})", mmss(duration), mmss(remaining));
+        },
+        None => print!("\r{elapsed}"),
+    }
+    let _ = std::io::stdout().flush();
+}
+
+/// Tracks below `p`, files split by a cue sheet are listed as its tracks only.
+fn all_media_path(p: PathBuf) -> Vec<CueTrack> {
+    let files: Vec<PathBuf> = WalkDir::new(p)
         .into_iter()
-        .filter_entry(|e| !is_media_file(e))
         .flatten()
+        .filter(|e| e.file_type().is_file())
         .map(|e| e.into_path())
-        .collect()
+        .collect();
+
+    let sheets: Vec<CueSheet> = files
+        .iter()
+        .filter(|p| p.extension().is_some_and(|e| e.eq_ignore_ascii_case("cue")))
+        .filter_map(|p| CueSheet::read(p).ok())
+        .collect();
+    let in_sheet: HashSet<&PathBuf> = sheets.iter().flat_map(|s| s.tracks.iter().map(|t| &t.path)).collect();
+
+    let mut tracks: Vec<CueTrack> = sheets.iter().flat_map(|s| s.tracks.iter().cloned()).collect();
+    for p in files.iter().filter(|p| is_media_file(p) && !in_sheet.contains(p)) {
+        tracks.extend(cue::tracks(p.clone()).unwrap_or_default());
+    }
+
+    tracks
 }
 
-fn is_media_file(e: &DirEntry) -> bool {
-    let p = e.path()
+fn is_media_file(p: &Path) -> bool {
+    let p = p
         .extension()
         .and_then(|s| s.to_str());
 
-    matches!(p, Some("flac"|"wav"|"ogg"|"aac"|"mp3"))
+    matches!(p, Some("flac"|"wav"|"ogg"|"aac"|"mp3"|"dsf"|"dff"))
 }
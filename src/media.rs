@@ -1,3 +1,7 @@
+use std::{path::PathBuf, time::Duration};
+
+use crate::decoder::TrackRange;
+
 pub const DEFAULT_ALBUM_NAME: &str = "Unknown Album";
 pub const DEFAULT_ALBUM_ID: i32 = 1;
 
@@ -6,11 +10,30 @@ pub const DEFAULT_ALBUM_ID: i32 = 1;
 pub enum OutputMode {
     PCM,
     DSD,
+    /// DSD carried in 24/32 bit pcm frames
+    DoP,
+    /// DSD converted to pcm before output, `sample_rate` is the pcm rate
+    DsdToPcm,
+}
+
+/// How DSD media is sent to the device.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, clap::ValueEnum)]
+pub enum DsdOutput {
+    /// native DSD, fallback to DoP, then to pcm conversion
+    #[default]
+    Auto,
+    Native,
+    #[value(name = "dop")]
+    DoP,
+    Pcm,
 }
 
 #[derive(Clone, Debug, sqlx::FromRow)]
 pub struct Media {
     pub file_path: String,
+    /// track range inside the file in microseconds
+    pub start_us: i64,
+    pub end_us: Option<i64>,
     pub album: Album,
     pub name: String,
     pub artist: String,
@@ -46,10 +69,35 @@ impl Default for AlbumInDb {
     }
 }
 
-#[derive(Clone, Copy, Debug)]
+/// Media row as seen by the loudness scan.
+#[derive(Clone, Debug, sqlx::FromRow)]
+pub struct MediaScan {
+    pub id: i32,
+    pub file: String,
+    pub start_us: i64,
+    pub end_us: Option<i64>,
+    pub album_id: Option<i32>,
+    pub scan_mtime: Option<i64>,
+    pub scan_size: Option<i64>,
+}
+
+impl MediaScan {
+    pub fn track(&self) -> TrackRange {
+        let us = |us: i64| Duration::from_micros(us.max(0) as u64);
+        TrackRange {
+            path: PathBuf::from(&self.file),
+            start: us(self.start_us),
+            end: self.end_us.map(us),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub struct MediaSpec {
     pub sample_rate: u32,
     pub channel: u32,
+    /// source bit depth, `None` for lossy codecs without one
+    pub bits: Option<u32>,
     pub mode: OutputMode,
 }
 
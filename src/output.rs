@@ -0,0 +1,544 @@
+use std::{
+    collections::VecDeque,
+    fmt::Display,
+    fs::File,
+    io::{BufWriter, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+
+use anyhow::{anyhow, Result};
+
+use crate::{
+    alsa_output::AlsaOutput,
+    devices::Capabilities,
+    media::{MediaSpec, OutputMode},
+    sample::{pack_s16, pack_s24_3le},
+};
+
+/// Where the samples go.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, clap::ValueEnum)]
+pub enum OutputKind {
+    /// the alsa device named by --device
+    #[default]
+    Alsa,
+    /// a wav file at --device, raw samples when it does not end in `.wav`
+    File,
+    /// nowhere, played at the real rate
+    Null,
+}
+
+/// A sink the player writes to. `configure` opens one exact mode, choosing
+/// between dsd and pcm is left to the player.
+pub trait OutputBackend {
+    /// Set up for `spec`, dropping whatever was pending. For pcm the sink may
+    /// settle on another rate, see `rate`.
+    fn configure(&mut self, spec: MediaSpec) -> Result<()>;
+    /// Frames per second of the configured sink, dsd counts whole words.
+    fn rate(&self) -> Result<u32>;
+    /// Sample depth of the configured sink, `None` for native dsd.
+    fn bits(&self) -> Option<u32>;
+    /// Write full scale S32 samples or `DSD_U32` words, returns the frames taken.
+    fn write(&mut self, buf: &[i32]) -> Result<usize>;
+    /// Wait up to `timeout` for room to write.
+    fn wait(&mut self, timeout: Duration) -> Result<()>;
+    fn pause(&mut self, pause: bool) -> Result<()>;
+    /// Block until everything written is played.
+    fn drain(&mut self) -> Result<()>;
+    /// Drop everything not played yet, e.g. for a seek.
+    fn discard(&mut self) -> Result<()>;
+    /// Frames written but not played yet.
+    fn delay(&self) -> Result<i64>;
+    /// Get the sink going again after `e` failed a call, returns what happened.
+    /// A disconnected sink is left closed until `reopen` succeeds.
+    fn recover(&mut self, e: anyhow::Error) -> Result<Xrun> {
+        Err(e)
+    }
+    /// Open the sink again after a disconnect, configured as before.
+    fn reopen(&mut self) -> Result<()> {
+        Ok(())
+    }
+    /// What the sink could be configured with, `rates` are tested besides the
+    /// common ones. `None` when it takes anything.
+    fn capabilities(&self, _rates: &[u32]) -> Option<Capabilities> {
+        None
+    }
+}
+
+/// An interruption of the stream the sink recovered from.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Xrun {
+    /// the device ran out of samples, it is refilled before it starts again
+    Underrun,
+    /// the device was suspended, e.g. with the machine
+    Suspend,
+    /// the device went away, e.g. a USB DAC was unplugged
+    Disconnect,
+}
+
+impl Display for Xrun {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Xrun::Underrun => write!(f, "underrun"),
+            Xrun::Suspend => write!(f, "suspend"),
+            Xrun::Disconnect => write!(f, "disconnect"),
+        }
+    }
+}
+
+/// Interruptions kept for the log.
+const XRUN_LOG: usize = 256;
+
+/// Interruptions since playback started.
+#[derive(Clone, Debug, Default)]
+pub struct XrunStats {
+    pub underruns: u32,
+    pub suspends: u32,
+    pub disconnects: u32,
+    /// the latest interruptions with when they happened, oldest first
+    pub log: VecDeque<(SystemTime, Xrun)>,
+}
+
+impl XrunStats {
+    pub fn record(&mut self, xrun: Xrun) {
+        match xrun {
+            Xrun::Underrun => self.underruns += 1,
+            Xrun::Suspend => self.suspends += 1,
+            Xrun::Disconnect => self.disconnects += 1,
+        }
+        if self.log.len() == XRUN_LOG {
+            self.log.pop_front();
+        }
+        self.log.push_back((SystemTime::now(), xrun));
+    }
+
+    pub fn count(&self, xrun: Xrun) -> u32 {
+        match xrun {
+            Xrun::Underrun => self.underruns,
+            Xrun::Suspend => self.suspends,
+            Xrun::Disconnect => self.disconnects,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.log.is_empty()
+    }
+}
+
+/// `3 underruns, 1 suspend, last 12s ago`.
+impl Display for XrunStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let plural = |n: u32, what: &str| match n {
+            1 => format!("1 {what}"),
+            n => format!("{n} {what}s"),
+        };
+        write!(f, "{}, {}, {}", plural(self.underruns, "underrun"), plural(self.suspends, "suspend"), plural(self.disconnects, "disconnect"))?;
+        if let Some((at, _)) = self.log.back() {
+            let ago = at.elapsed().unwrap_or_default().as_secs();
+            write!(f, ", last {ago}s ago")?;
+        }
+        Ok(())
+    }
+}
+
+pub fn open(kind: OutputKind, device: Option<&str>) -> Result<Box<dyn OutputBackend>> {
+    let device = || device.ok_or(anyhow!("no device, see --device"));
+    Ok(match kind {
+        OutputKind::Alsa => Box::new(AlsaOutput::new(device()?)?),
+        OutputKind::File => Box::new(FileOutput::new(device()?)),
+        OutputKind::Null => Box::new(NullOutput::default()),
+    })
+}
+
+/// Frame rate of a sink taking `spec` as is, DoP frames carry 16 dsd bits.
+pub fn frame_rate(spec: MediaSpec) -> u32 {
+    match spec.mode {
+        OutputMode::PCM | OutputMode::DsdToPcm => spec.sample_rate,
+        OutputMode::DSD => spec.sample_rate / 32,
+        OutputMode::DoP => spec.sample_rate / 16,
+    }
+}
+
+/// Writes samples to a file, canonical wav or headerless little endian.
+pub struct FileOutput {
+    path: PathBuf,
+    wav: bool,
+    file: Option<BufWriter<File>>,
+    /// rate, channel and depth of the open file
+    format: Option<(u32, u32, u32)>,
+    data_bytes: u64,
+    s16: Vec<i16>,
+    bytes: Vec<u8>,
+}
+
+/// Byte offsets of the sizes patched in once the length is known.
+const WAV_RIFF_SIZE: u64 = 4;
+const WAV_DATA_SIZE: u64 = 40;
+
+impl FileOutput {
+    pub fn new(path: impl AsRef<Path>) -> Self {
+        let path = path.as_ref().to_path_buf();
+        Self {
+            wav: path.extension().is_some_and(|e| e.eq_ignore_ascii_case("wav")),
+            path,
+            file: None,
+            format: None,
+            data_bytes: 0,
+            s16: Vec::new(),
+            bytes: Vec::new(),
+        }
+    }
+
+    fn write_header(file: &mut impl Write, (rate, channel, bits): (u32, u32, u32)) -> std::io::Result<()> {
+        let block_align = channel * bits / 8;
+        file.write_all(b"RIFF")?;
+        file.write_all(&0u32.to_le_bytes())?;
+        file.write_all(b"WAVEfmt ")?;
+        file.write_all(&16u32.to_le_bytes())?;
+        file.write_all(&1u16.to_le_bytes())?;
+        file.write_all(&(channel as u16).to_le_bytes())?;
+        file.write_all(&rate.to_le_bytes())?;
+        file.write_all(&(rate * block_align).to_le_bytes())?;
+        file.write_all(&(block_align as u16).to_le_bytes())?;
+        file.write_all(&(bits as u16).to_le_bytes())?;
+        file.write_all(b"data")?;
+        file.write_all(&0u32.to_le_bytes())
+    }
+
+    /// Flush and patch the wav sizes, the file stays playable after every drain.
+    fn finish(&mut self) -> Result<()> {
+        let Some(file) = self.file.as_mut() else {
+            return Ok(());
+        };
+
+        file.flush()?;
+        if self.wav {
+            let data = self.data_bytes.min(u32::MAX as u64 - 36) as u32;
+            let file = file.get_mut();
+            file.seek(SeekFrom::Start(WAV_RIFF_SIZE))?;
+            file.write_all(&(data + 36).to_le_bytes())?;
+            file.seek(SeekFrom::Start(WAV_DATA_SIZE))?;
+            file.write_all(&data.to_le_bytes())?;
+            file.seek(SeekFrom::End(0))?;
+        }
+        Ok(())
+    }
+}
+
+impl OutputBackend for FileOutput {
+    fn configure(&mut self, spec: MediaSpec) -> Result<()> {
+        let bits = match spec.mode {
+            OutputMode::DSD => return Err(anyhow!("files can not hold native dsd")),
+            // DoP needs 24 bits, converted dsd has no depth of its own
+            OutputMode::DoP => 24,
+            OutputMode::DsdToPcm => 32,
+            OutputMode::PCM => match spec.bits {
+                Some(bits) if bits <= 16 => 16,
+                Some(bits) if bits <= 24 => 24,
+                _ => 32,
+            },
+        };
+        let format = (frame_rate(spec), spec.channel, bits);
+
+        match self.format {
+            Some(open) if open == format => return Ok(()),
+            // a raw file is just samples, whoever reads it has to know about the change
+            Some(_) if !self.wav => {},
+            Some(_) => return Err(anyhow!("a wav file holds one format, {} can not change to {}Hz {bits} bit", self.path.display(), format.0)),
+            None => {
+                let mut file = BufWriter::new(File::create(&self.path)?);
+                if self.wav {
+                    Self::write_header(&mut file, format)?;
+                }
+                self.file = Some(file);
+            },
+        }
+
+        self.format = Some(format);
+        Ok(())
+    }
+
+    fn rate(&self) -> Result<u32> {
+        self.format.map(|(rate, ..)| rate).ok_or(anyhow!("output not configured"))
+    }
+
+    fn bits(&self) -> Option<u32> {
+        self.format.map(|(_, _, bits)| bits)
+    }
+
+    fn write(&mut self, buf: &[i32]) -> Result<usize> {
+        let (Some((_, channel, bits)), Some(file)) = (self.format, self.file.as_mut()) else {
+            return Err(anyhow!("output not configured"));
+        };
+
+        match bits {
+            16 => {
+                pack_s16(buf, &mut self.s16);
+                self.bytes.clear();
+                self.bytes.extend(self.s16.iter().flat_map(|s| s.to_le_bytes()));
+            },
+            24 => pack_s24_3le(buf, &mut self.bytes),
+            _ => {
+                self.bytes.clear();
+                self.bytes.extend(buf.iter().flat_map(|s| s.to_le_bytes()));
+            },
+        }
+
+        file.write_all(&self.bytes)?;
+        self.data_bytes += self.bytes.len() as u64;
+        Ok(buf.len() / channel as usize)
+    }
+
+    fn wait(&mut self, _timeout: Duration) -> Result<()> {
+        Ok(())
+    }
+
+    fn pause(&mut self, _pause: bool) -> Result<()> {
+        Ok(())
+    }
+
+    fn drain(&mut self) -> Result<()> {
+        self.finish()
+    }
+
+    fn discard(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn delay(&self) -> Result<i64> {
+        Ok(0)
+    }
+}
+
+impl Drop for FileOutput {
+    fn drop(&mut self) {
+        let _ = self.finish();
+    }
+}
+
+/// Buffer of the null sink, writes block beyond it like on a device.
+const NULL_BUFFER: Duration = Duration::from_millis(500);
+
+/// Throws samples away at the rate a device would play them.
+#[derive(Default)]
+pub struct NullOutput {
+    spec: Option<MediaSpec>,
+    rate: u32,
+    /// when the first written frame started to play
+    start: Option<Instant>,
+    paused_at: Option<Instant>,
+    written: u64,
+}
+
+impl NullOutput {
+    fn played(&self) -> u64 {
+        let Some(start) = self.start else {
+            return 0;
+        };
+        let now = self.paused_at.unwrap_or_else(Instant::now);
+        let frames = (now - start).as_secs_f64() * self.rate as f64;
+        (frames as u64).min(self.written)
+    }
+
+    fn room(&self) -> u64 {
+        let buffer = (NULL_BUFFER.as_secs_f64() * self.rate as f64) as u64;
+        buffer.saturating_sub(self.written - self.played())
+    }
+
+    fn reset(&mut self) {
+        self.start = None;
+        self.paused_at = None;
+        self.written = 0;
+    }
+}
+
+impl OutputBackend for NullOutput {
+    fn configure(&mut self, spec: MediaSpec) -> Result<()> {
+        self.spec = Some(spec);
+        self.rate = frame_rate(spec);
+        self.reset();
+        Ok(())
+    }
+
+    fn rate(&self) -> Result<u32> {
+        self.spec.map(|_| self.rate).ok_or(anyhow!("output not configured"))
+    }
+
+    fn bits(&self) -> Option<u32> {
+        match self.spec?.mode {
+            OutputMode::DSD => None,
+            OutputMode::PCM => Some(self.spec?.bits.unwrap_or(32)),
+            OutputMode::DoP | OutputMode::DsdToPcm => Some(32),
+        }
+    }
+
+    fn write(&mut self, buf: &[i32]) -> Result<usize> {
+        let channel = self.spec.ok_or(anyhow!("output not configured"))?.channel as usize;
+        if self.paused_at.is_some() {
+            return Ok(0);
+        }
+
+        // an underrun starts the clock over from the frames written so far
+        let now = Instant::now();
+        if self.start.is_none() || self.played() == self.written {
+            let behind = Duration::from_secs_f64(self.written as f64 / self.rate as f64);
+            self.start = now.checked_sub(behind);
+        }
+
+        let frames = (buf.len() / channel).min(self.room() as usize);
+        self.written += frames as u64;
+        Ok(frames)
+    }
+
+    fn wait(&mut self, timeout: Duration) -> Result<()> {
+        if self.room() == 0 {
+            thread::sleep(timeout.min(NULL_BUFFER / 8));
+        }
+        Ok(())
+    }
+
+    fn pause(&mut self, pause: bool) -> Result<()> {
+        match (pause, self.paused_at) {
+            (true, None) => self.paused_at = Some(Instant::now()),
+            (false, Some(at)) => {
+                self.start = self.start.map(|s| s + at.elapsed());
+                self.paused_at = None;
+            },
+            _ => {},
+        }
+        Ok(())
+    }
+
+    fn drain(&mut self) -> Result<()> {
+        if self.paused_at.is_none() {
+            let pending = self.written - self.played();
+            thread::sleep(Duration::from_secs_f64(pending as f64 / self.rate.max(1) as f64));
+        }
+        self.reset();
+        Ok(())
+    }
+
+    fn discard(&mut self) -> Result<()> {
+        self.reset();
+        Ok(())
+    }
+
+    fn delay(&self) -> Result<i64> {
+        Ok((self.written - self.played()) as i64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pcm(rate: u32, channel: u32, bits: u32) -> MediaSpec {
+        MediaSpec { sample_rate: rate, channel, bits: Some(bits), mode: OutputMode::PCM }
+    }
+
+    /// A ramp of distinct S32 samples, every channel of a frame apart.
+    fn source(frames: usize, channel: u32) -> Vec<i32> {
+        (0..frames * channel as usize).map(|i| (i as i32 - 500) << 16).collect()
+    }
+
+    /// Write `samples` in small chunks like the output loop, the frames taken each time.
+    fn play(out: &mut dyn OutputBackend, samples: &[i32], channel: u32) -> Result<Vec<usize>> {
+        samples.chunks(64 * channel as usize).map(|chunk| out.write(chunk)).collect()
+    }
+
+    fn u32_at(bytes: &[u8], at: u64) -> u32 {
+        let at = at as usize;
+        u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap())
+    }
+
+    fn temp(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("oto-{}-{name}", std::process::id()))
+    }
+
+    #[test]
+    fn wav_file_holds_the_samples_and_their_sizes() -> Result<()> {
+        let path = temp("out.wav");
+        let samples = source(1000, 2);
+        let mut out = FileOutput::new(&path);
+        out.configure(pcm(44100, 2, 16))?;
+        assert_eq!(out.rate()?, 44100);
+        assert_eq!(out.bits(), Some(16));
+        assert_eq!(play(&mut out, &samples, 2)?.iter().sum::<usize>(), 1000);
+        out.drain()?;
+
+        let bytes = std::fs::read(&path)?;
+        std::fs::remove_file(&path)?;
+        let data = 1000 * 2 * 2;
+        assert_eq!(bytes.len(), 44 + data);
+        assert_eq!(&bytes[..4], b"RIFF");
+        assert_eq!(u32_at(&bytes, WAV_RIFF_SIZE), data as u32 + 36);
+        assert_eq!(u32_at(&bytes, WAV_DATA_SIZE), data as u32);
+        // rate, byte rate and block align of the fmt chunk
+        assert_eq!(u32_at(&bytes, 24), 44100);
+        assert_eq!(u32_at(&bytes, 28), 44100 * 4);
+        assert_eq!(u16::from_le_bytes([bytes[32], bytes[33]]), 4);
+
+        let written: Vec<i32> = bytes[44..].chunks(2).map(|b| i16::from_le_bytes([b[0], b[1]]) as i32).collect();
+        assert_eq!(written, samples.iter().map(|s| s >> 16).collect::<Vec<_>>());
+        Ok(())
+    }
+
+    #[test]
+    fn wav_sizes_are_patched_when_dropped() -> Result<()> {
+        let path = temp("drop.wav");
+        let samples = source(300, 1);
+        {
+            let mut out = FileOutput::new(&path);
+            out.configure(pcm(48000, 1, 24))?;
+            play(&mut out, &samples, 1)?;
+        }
+
+        let bytes = std::fs::read(&path)?;
+        std::fs::remove_file(&path)?;
+        assert_eq!(u32_at(&bytes, WAV_RIFF_SIZE), 300 * 3 + 36);
+        assert_eq!(u32_at(&bytes, WAV_DATA_SIZE), 300 * 3);
+        let written: Vec<i32> = bytes[44..].chunks(3).map(|b| i32::from_le_bytes([0, b[0], b[1], b[2]])).collect();
+        assert_eq!(written, samples);
+        Ok(())
+    }
+
+    #[test]
+    fn wav_can_not_change_its_format() -> Result<()> {
+        let path = temp("respec.wav");
+        let mut out = FileOutput::new(&path);
+        out.configure(pcm(44100, 2, 16))?;
+        // the same format again is fine, another one not
+        out.configure(pcm(44100, 2, 16))?;
+        assert!(out.configure(pcm(96000, 2, 24)).is_err());
+        drop(out);
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn null_output_takes_a_buffer_and_plays_it_in_real_time() -> Result<()> {
+        let mut out = NullOutput::default();
+        assert!(out.write(&[0; 8]).is_err());
+        out.configure(pcm(8000, 2, 16))?;
+        assert_eq!(out.rate()?, 8000);
+        assert_eq!(out.bits(), Some(16));
+
+        // half a second of buffer, the rest of the source waits
+        let samples = source(8000, 2);
+        let taken: usize = play(&mut out, &samples, 2)?.iter().sum();
+        assert!((4000..4100).contains(&taken), "{taken}");
+        assert!(out.delay()? > 3900);
+
+        thread::sleep(Duration::from_millis(100));
+        assert!(out.delay()? < 3800);
+        assert!(out.write(&samples[..200])? > 0);
+
+        out.pause(true)?;
+        assert_eq!(out.write(&samples[..200])?, 0);
+        out.pause(false)?;
+        out.discard()?;
+        assert_eq!(out.delay()?, 0);
+        Ok(())
+    }
+}
@@ -0,0 +1,164 @@
+use std::collections::VecDeque;
+
+use anyhow::Result;
+
+use crate::{
+    cli::PlayArgs,
+    dither::{Dither, DitherMode, NoiseShaping},
+    dsd::DsdToPcm,
+    gain,
+    media::{MediaSpec, OutputMode},
+    player::Player,
+    resampler::Resampler,
+};
+
+/// Processing between the decoder and the device, rebuilt whenever the device is reopened.
+#[derive(Default)]
+pub struct Pipeline {
+    dsd_pcm: Option<DsdToPcm>,
+    resampler: Option<Resampler>,
+    /// linear ReplayGain factor
+    gain: Option<f32>,
+    dither: Option<Dither>,
+    /// settings to rebuild the dither when the gain changes, none for dsd output
+    dither_config: Option<DitherConfig>,
+    scratch: VecDeque<i32>,
+}
+
+struct DitherConfig {
+    mode: DitherMode,
+    shaping: NoiseShaping,
+    channel: usize,
+    source_bits: Option<u32>,
+    output_bits: Option<u32>,
+}
+
+impl DitherConfig {
+    fn build(&self, gain: bool) -> Option<Dither> {
+        // scaled samples use every bit
+        let source_bits = if gain { None } else { self.source_bits };
+        Dither::new(self.mode, self.shaping, self.channel, source_bits, self.output_bits)
+    }
+}
+
+impl Pipeline {
+    /// Stages needed to play `src` on a device opened as `opened` with `output_bits` per sample,
+    /// `gain` is the linear ReplayGain factor of the track.
+    pub fn new(
+        args: &PlayArgs,
+        player: &Player,
+        src: MediaSpec,
+        opened: MediaSpec,
+        output_bits: Option<u32>,
+        gain: Option<f32>,
+    ) -> Result<Self> {
+        let channel = opened.channel as usize;
+
+        // rate and depth of the samples leaving the decoder or the dsd converter
+        let (rate, source_bits) = match opened.mode {
+            OutputMode::PCM => (src.sample_rate, src.bits),
+            // converted dsd has no fixed depth
+            OutputMode::DsdToPcm => (player.dsd_pcm_rate(src.sample_rate), None),
+            OutputMode::DSD | OutputMode::DoP => return Ok(Self::default()),
+        };
+
+        let dsd_pcm = match opened.mode {
+            OutputMode::DsdToPcm => Some(DsdToPcm::new(src.sample_rate, rate, channel)?),
+            _ => None,
+        };
+
+        let resampler = (rate != opened.sample_rate)
+            .then(|| Resampler::new(rate, opened.sample_rate, channel, args.resample_quality));
+
+        // resampled samples lost their bit depth as well
+        let source_bits = if resampler.is_some() { None } else { source_bits };
+        let dither_config = DitherConfig {
+            mode: args.dither,
+            shaping: args.noise_shaping,
+            channel,
+            source_bits,
+            output_bits,
+        };
+
+        let mut pipeline = Self {
+            dsd_pcm,
+            resampler,
+            dither_config: Some(dither_config),
+            ..Default::default()
+        };
+        pipeline.set_gain(gain);
+        Ok(pipeline)
+    }
+
+    /// Switch the ReplayGain factor, e.g. for the next gapless track. Dsd output is never scaled.
+    pub fn set_gain(&mut self, gain: Option<f32>) {
+        let Some(config) = self.dither_config.as_ref() else {
+            return;
+        };
+
+        // unity gain keeps the output bit perfect
+        self.gain = gain.filter(|g| *g != 1.0);
+        self.dither = config.build(self.gain.is_some());
+    }
+
+    /// Drop state of the old position after a seek.
+    pub fn reset(&mut self) {
+        if let Some(conv) = self.dsd_pcm.as_mut() {
+            conv.reset();
+        }
+        if let Some(resampler) = self.resampler.as_mut() {
+            resampler.reset();
+        }
+    }
+
+    /// Move decoded samples through every stage to the end of `out`.
+    pub fn process(&mut self, decoded: &mut VecDeque<i32>, out: &mut VecDeque<i32>) {
+        let from = out.len();
+
+        if let Some(conv) = self.dsd_pcm.as_mut() {
+            conv.process(decoded.make_contiguous(), &mut self.scratch);
+            decoded.clear();
+            decoded.append(&mut self.scratch);
+        }
+
+        match self.resampler.as_mut() {
+            Some(resampler) => {
+                resampler.process(decoded.make_contiguous(), out);
+                decoded.clear();
+            },
+            None => out.append(decoded),
+        }
+
+        self.finish(out, from);
+    }
+
+    /// Move what the stages still hold to the end of `out` once the input ended,
+    /// e.g. before the device is reopened or stops.
+    pub fn flush(&mut self, out: &mut VecDeque<i32>) {
+        let from = out.len();
+        if let Some(conv) = self.dsd_pcm.as_mut() {
+            conv.flush(&mut self.scratch);
+        }
+
+        match self.resampler.as_mut() {
+            Some(resampler) => {
+                resampler.process(self.scratch.make_contiguous(), out);
+                self.scratch.clear();
+                resampler.flush(out);
+            },
+            None => out.append(&mut self.scratch),
+        }
+        self.finish(out, from);
+    }
+
+    /// Scale and dither the samples of `out` from `from` on.
+    fn finish(&mut self, out: &mut VecDeque<i32>, from: usize) {
+        if let Some(factor) = self.gain {
+            gain::apply(factor, out.range_mut(from..));
+        }
+
+        if let Some(dither) = self.dither.as_mut() {
+            dither.process(out.range_mut(from..));
+        }
+    }
+}
@@ -1,95 +1,437 @@
-use std::ops::Deref;
+use std::{cell::RefCell, fmt::Display, sync::mpsc::Sender, thread, time::Duration};
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 
-use alsa::{
-    pcm::{
-        HwParams, State
-    },
-    Direction,
-    PCM
+use crate::{
+    devices::Capabilities,
+    dsd::dsd_pcm_rate,
+    event::PlayerEvent,
+    media::{DsdOutput, MediaSpec, OutputMode},
+    output::{frame_rate, OutputBackend, Xrun, XrunStats},
 };
 
-use crate::media::{MediaSpec, OutputMode};
+/// SCHED_FIFO priority of the output thread, below what audio servers take.
+pub const DEFAULT_RT_PRIORITY: i32 = 50;
 
+/// Pause between attempts to open a disconnected device again.
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Attempts before a disconnected device is given up.
+const RECONNECT_ATTEMPTS: u32 = 30;
+
+/// How a spec reaches the device, in the order they are tried.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum OutputPath {
+    NativePcm,
+    NativeDsd,
+    DoP,
+    DsdToPcm,
+    Resampled,
+}
+
+impl Display for OutputPath {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OutputPath::NativePcm => write!(f, "native pcm"),
+            OutputPath::NativeDsd => write!(f, "native dsd"),
+            OutputPath::DoP => write!(f, "DoP"),
+            OutputPath::DsdToPcm => write!(f, "pcm conversion"),
+            OutputPath::Resampled => write!(f, "resampling"),
+        }
+    }
+}
+
+/// One way to play a spec, `spec` is what the device is configured with.
+#[derive(Clone, Copy, Debug)]
+pub struct Candidate {
+    pub path: OutputPath,
+    pub spec: MediaSpec,
+}
+
+/// Ways to play `spec`, best first: native pcm or dsd, DoP, pcm conversion, then
+/// resampling to the closest rate of `caps`. `output_rate` forces resampling.
+pub fn candidates(
+    spec: MediaSpec,
+    dsd_output: DsdOutput,
+    dsd_pcm_rate: u32,
+    output_rate: Option<u32>,
+    caps: Option<&Capabilities>,
+) -> Vec<Candidate> {
+    let mut candidates = Vec::new();
+    let (path, mode, rate) = match spec.mode {
+        OutputMode::DSD => {
+            if matches!(dsd_output, DsdOutput::Auto | DsdOutput::Native) {
+                candidates.push(Candidate { path: OutputPath::NativeDsd, spec });
+            }
+            if matches!(dsd_output, DsdOutput::Auto | DsdOutput::DoP) {
+                let spec = MediaSpec { mode: OutputMode::DoP, ..spec };
+                candidates.push(Candidate { path: OutputPath::DoP, spec });
+            }
+            if !matches!(dsd_output, DsdOutput::Auto | DsdOutput::Pcm) {
+                return candidates;
+            }
+            (OutputPath::DsdToPcm, OutputMode::DsdToPcm, dsd_pcm_rate)
+        },
+        mode => (OutputPath::NativePcm, mode, spec.sample_rate),
+    };
+
+    let pcm = |path, sample_rate| Candidate {
+        path,
+        spec: MediaSpec { sample_rate, mode, ..spec },
+    };
+    match output_rate {
+        Some(fixed) if fixed != rate => candidates.push(pcm(OutputPath::Resampled, fixed)),
+        _ => {
+            candidates.push(pcm(path, rate));
+            if let Some(alt) = caps.and_then(|caps| caps.resample_rate(rate)) {
+                candidates.push(pcm(OutputPath::Resampled, alt));
+            }
+        },
+    }
+
+    candidates
+}
+
+/// Run the calling thread with SCHED_FIFO `priority`, which needs CAP_SYS_NICE
+/// or an rtprio limit, e.g. from the audio group.
+pub fn realtime_priority(priority: i32) -> Result<()> {
+    let param = libc::sched_param { sched_priority: priority };
+    // SAFETY: pthread_self is the calling thread, param outlives the call
+    let err = unsafe { libc::pthread_setschedparam(libc::pthread_self(), libc::SCHED_FIFO, &param) };
+    if err != 0 {
+        return Err(std::io::Error::from_raw_os_error(err).into());
+    }
+    Ok(())
+}
+
+/// Drives an output backend, decides how dsd is sent and which rate pcm plays at.
 pub struct Player {
-    output: PCM,
+    output: RefCell<Box<dyn OutputBackend>>,
+    dsd_output: DsdOutput,
+    dsd_pcm_rate: Option<u32>,
+    /// fixed pcm rate, everything else is resampled to it
+    output_rate: Option<u32>,
+    events: Option<Sender<PlayerEvent>>,
+    stats: RefCell<XrunStats>,
 }
 
 impl Player {
-    pub fn new(device_name: impl AsRef<str>) -> Result<Self> {
-        let pcm = PCM::new(device_name.as_ref(), Direction::Playback, false)?;
+    pub fn new(
+        output: Box<dyn OutputBackend>,
+        dsd_output: DsdOutput,
+        dsd_pcm_rate: Option<u32>,
+        output_rate: Option<u32>,
+    ) -> Self {
+        Self {
+            output: RefCell::new(output),
+            dsd_output,
+            dsd_pcm_rate,
+            output_rate,
+            events: None,
+            stats: RefCell::default(),
+        }
+    }
 
-        Ok(Self {
-            output: pcm,
-        })
+    /// Pcm rate dsd of `dsd_rate` is converted to before resampling.
+    pub fn dsd_pcm_rate(&self, dsd_rate: u32) -> u32 {
+        self.dsd_pcm_rate.unwrap_or_else(|| dsd_pcm_rate(dsd_rate))
     }
 
-    pub fn set_hw_param(&self, spec: MediaSpec) -> Result<()> {
-        use OutputMode::*;
-        match spec.mode {
-            PCM => self.pcm_hw_param(spec.channel, spec.sample_rate),
-            DSD => self.dsd_hw_param(spec.channel, spec.sample_rate),
+    /// Report negotiation results to the controller instead of stdout.
+    pub fn set_event_sender(&mut self, events: Sender<PlayerEvent>) {
+        self.events = Some(events);
+    }
+
+    fn report(&self, event: PlayerEvent) {
+        match &self.events {
+            Some(events) => {
+                let _ = events.send(event);
+            },
+            None => println!("{event}"),
         }
     }
 
-    pub fn pcm_hw_param(&self, channel: u32, bit_rate: u32) -> Result<()> {
-        let hwp = HwParams::any(&self.output)?;
-        hwp.set_channels(channel)?;
-        hwp.set_rate(bit_rate, alsa::ValueOr::Nearest)?;
-        hwp.set_format(alsa::pcm::Format::S32LE)?;
-        hwp.set_access(alsa::pcm::Access::RWInterleaved)?;
-        self.output.hw_params(&hwp)?;
-        Ok(())
+    /// Configure the device for `spec` along the first path it takes, see `candidates`.
+    /// Returns the spec the device was opened with, for pcm output `sample_rate` is the device rate.
+    pub fn init(&self, spec: MediaSpec) -> Result<MediaSpec> {
+        let dsd_pcm_rate = self.dsd_pcm_rate(spec.sample_rate);
+        let rates: Vec<u32> = candidates(spec, self.dsd_output, dsd_pcm_rate, self.output_rate, None)
+            .iter()
+            .map(|c| frame_rate(c.spec))
+            .collect();
+        let caps = self.output.borrow().capabilities(&rates);
+        let candidates = candidates(spec, self.dsd_output, dsd_pcm_rate, self.output_rate, caps.as_ref());
+
+        let mut rejected = Vec::new();
+        for Candidate { path, spec: candidate } in candidates {
+            // what the device does not list is not tried, the probe can not see everything though
+            let checked = match &caps {
+                Some(caps) => caps.accepts(candidate).map(|_| ()).map_err(|e| anyhow!(e)),
+                None => Ok(()),
+            };
+
+            if let Err(e) = checked.and_then(|_| self.output.borrow_mut().configure(candidate)) {
+                self.report(PlayerEvent::OutputRejected { path, reason: e.to_string() });
+                rejected.push(format!("{path}: {e}"));
+                continue;
+            }
+
+            let mut opened = candidate;
+            // pcm is resampled to whatever rate the device settled on
+            if matches!(opened.mode, OutputMode::PCM | OutputMode::DsdToPcm) {
+                opened.sample_rate = self.rate()?;
+            }
+
+            let path = match path {
+                OutputPath::NativePcm | OutputPath::DsdToPcm if opened.sample_rate != candidate.sample_rate => {
+                    OutputPath::Resampled
+                },
+                path => path,
+            };
+            let reason = self.describe(path, spec, opened);
+            self.report(PlayerEvent::OutputChosen { path, reason });
+            return Ok(opened);
+        }
+
+        Err(anyhow!("no output path for {spec:?}: {}", rejected.join("; ")))
     }
 
-    pub fn dsd_hw_param(&self, channel: u32, bit_rate: u32) -> Result<()> {
-        let hwp = HwParams::any(&self.output)?;
-        hwp.set_channels(channel)?;
-        hwp.set_format(alsa::pcm::Format::DSDU32LE)?;
-        hwp.set_rate(bit_rate, alsa::ValueOr::Nearest)?;
-        hwp.set_access(alsa::pcm::Access::RWInterleaved)?;
-        self.output.hw_params(&hwp)?;
+    /// Why `path` plays `src` as `opened`, the bit depth is the one of the device.
+    fn describe(&self, path: OutputPath, src: MediaSpec, opened: MediaSpec) -> String {
+        let bits = self.bits();
+        let depth = match (src.bits.filter(|_| src.mode != OutputMode::DSD), bits) {
+            (Some(src), Some(out)) if out < src => format!(", dithered from {src} to {out} bit"),
+            (Some(src), Some(out)) if out > src => format!(", {src} bit padded to {out} bit"),
+            (_, Some(out)) => format!(", {out} bit"),
+            (_, None) => String::new(),
+        };
+
+        match path {
+            OutputPath::NativePcm => format!("{}Hz as is{depth}", opened.sample_rate),
+            OutputPath::NativeDsd => format!("DSD_U32_LE at {}Hz", opened.sample_rate / 32),
+            OutputPath::DoP => format!("{}Hz frames{depth}", opened.sample_rate / 16),
+            OutputPath::DsdToPcm => format!("dsd converted to {}Hz{depth}", opened.sample_rate),
+            OutputPath::Resampled => {
+                let from = match src.mode {
+                    OutputMode::DSD => self.dsd_pcm_rate(src.sample_rate),
+                    _ => src.sample_rate,
+                };
+                format!("{from}Hz resampled to {}Hz{depth}", opened.sample_rate)
+            },
+        }
+    }
+
+    /// Recover the device from a failed call, a disconnected one is waited for
+    /// up to `RECONNECT_ATTEMPTS` times. Errors the device can not recover from are returned.
+    fn recover(&self, e: anyhow::Error) -> Result<()> {
+        let xrun = self.output.borrow_mut().recover(e)?;
+        let count = {
+            let mut stats = self.stats.borrow_mut();
+            stats.record(xrun);
+            stats.count(xrun)
+        };
+        self.report(PlayerEvent::Xrun { xrun, count });
+
+        if xrun == Xrun::Disconnect {
+            for attempt in 1.. {
+                match self.output.borrow_mut().reopen() {
+                    Ok(()) => break,
+                    Err(e) if attempt == RECONNECT_ATTEMPTS => return Err(e.context("device did not come back")),
+                    Err(_) => thread::sleep(RECONNECT_INTERVAL),
+                }
+            }
+            self.report(PlayerEvent::Reconnected);
+        }
         Ok(())
     }
 
-    pub fn set_sw_param(&self, spec: MediaSpec) -> Result<()> {
-        use OutputMode::*;
-        match spec.mode {
-            PCM => self.pcm_sw_param(),
-            DSD => self.dsd_sw_param(),
+    /// Underruns, suspends and disconnects so far.
+    pub fn stats(&self) -> XrunStats {
+        self.stats.borrow().clone()
+    }
+
+    /// Frame rate the device was opened with.
+    pub fn rate(&self) -> Result<u32> {
+        self.output.borrow().rate()
+    }
+
+    /// Sample depth of the device, `None` for DSD.
+    pub fn bits(&self) -> Option<u32> {
+        self.output.borrow().bits()
+    }
+
+    /// Write full scale S32 samples, or `DSD_U32` words, returns the frames written.
+    /// Nothing is written when the device had to recover, the caller writes again.
+    pub fn write(&self, buf: &[i32]) -> Result<usize> {
+        let written = self.output.borrow_mut().write(buf);
+        written.or_else(|e| self.recover(e).map(|_| 0))
+    }
+
+    pub fn wait(&self, timeout: Duration) -> Result<()> {
+        let waited = self.output.borrow_mut().wait(timeout);
+        waited.or_else(|e| self.recover(e))
+    }
+
+    pub fn pause(&self, pause: bool) -> Result<()> {
+        let paused = self.output.borrow_mut().pause(pause);
+        paused.or_else(|e| self.recover(e))
+    }
+
+    /// What the device still holds is lost when it has to recover.
+    pub fn drain(&self) -> Result<()> {
+        let drained = self.output.borrow_mut().drain();
+        drained.or_else(|e| self.recover(e))
+    }
+
+    /// Drop what the device did not play yet.
+    pub fn discard(&self) -> Result<()> {
+        self.output.borrow_mut().discard()
+    }
+
+    pub fn delay(&self) -> Result<i64> {
+        self.output.borrow().delay()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alsa::pcm::Format;
+
+    use super::*;
+
+    const DSD64: MediaSpec = MediaSpec { sample_rate: 2822400, channel: 2, bits: Some(1), mode: OutputMode::DSD };
+    const CD: MediaSpec = MediaSpec { sample_rate: 44100, channel: 2, bits: Some(16), mode: OutputMode::PCM };
+
+    fn caps(rates: &[u32]) -> Capabilities {
+        Capabilities {
+            formats: vec![Format::S32LE],
+            rates: rates.to_vec(),
+            rate_range: (rates[0], rates[rates.len() - 1]),
+            channels: (2, 2),
         }
     }
 
-    pub fn pcm_sw_param(&self) -> Result<()> {
-        let swp = self.output.sw_params_current()?;
-        let hwp = self.output.hw_params_current()?;
-        swp.set_start_threshold(hwp.get_buffer_size().unwrap())?;
-        self.output.sw_params(&swp)?;
-        Ok(())
+    fn paths(candidates: &[Candidate]) -> Vec<OutputPath> {
+        candidates.iter().map(|c| c.path).collect()
     }
 
-    pub fn dsd_sw_param(&self) -> Result<()> {
-        self.pcm_sw_param()
+    /// Fails every write with a disconnect, comes back after `down` attempts to reopen.
+    struct Unplugged {
+        down: u32,
+        reopened: u32,
     }
 
-    pub fn init(&self, spec: MediaSpec) -> Result<()> {
-        self.set_hw_param(spec)?;
-        self.set_sw_param(spec)?;
+    impl OutputBackend for Unplugged {
+        fn configure(&mut self, _spec: MediaSpec) -> Result<()> {
+            Ok(())
+        }
+
+        fn rate(&self) -> Result<u32> {
+            Ok(44100)
+        }
+
+        fn bits(&self) -> Option<u32> {
+            Some(16)
+        }
+
+        fn write(&mut self, _buf: &[i32]) -> Result<usize> {
+            Err(anyhow!("unplugged"))
+        }
+
+        fn wait(&mut self, _timeout: Duration) -> Result<()> {
+            Ok(())
+        }
+
+        fn pause(&mut self, _pause: bool) -> Result<()> {
+            Ok(())
+        }
+
+        fn drain(&mut self) -> Result<()> {
+            Ok(())
+        }
+
+        fn discard(&mut self) -> Result<()> {
+            Ok(())
+        }
 
-        let status = self.output.status()?;
-        if !matches!(status.get_state(), State::Running | State::Prepared) {
-            self.output.prepare()?;
+        fn delay(&self) -> Result<i64> {
+            Ok(0)
         }
 
+        fn recover(&mut self, _e: anyhow::Error) -> Result<Xrun> {
+            Ok(Xrun::Disconnect)
+        }
+
+        fn reopen(&mut self) -> Result<()> {
+            self.reopened += 1;
+            match self.reopened > self.down {
+                true => Ok(()),
+                false => Err(anyhow!("no such device")),
+            }
+        }
+    }
+
+    #[test]
+    fn disconnected_device_is_reopened() -> Result<()> {
+        let (tx, rx) = std::sync::mpsc::channel();
+        let mut player = Player::new(Box::new(Unplugged { down: 1, reopened: 0 }), DsdOutput::Auto, None, None);
+        player.set_event_sender(tx);
+
+        // nothing written, the caller writes again
+        assert_eq!(player.write(&[0; 4])?, 0);
+        assert_eq!(player.stats().count(Xrun::Disconnect), 1);
+        let events: Vec<_> = rx.try_iter().collect();
+        assert!(matches!(events[..], [PlayerEvent::Xrun { xrun: Xrun::Disconnect, count: 1 }, PlayerEvent::Reconnected]));
         Ok(())
     }
-}
 
-impl Deref for Player {
-    type Target = PCM;
+    #[test]
+    fn dsd_falls_back_from_native_to_dop_to_pcm() {
+        let all = candidates(DSD64, DsdOutput::Auto, 88200, None, Some(&caps(&[44100, 96000, 176400])));
+        assert_eq!(paths(&all), [OutputPath::NativeDsd, OutputPath::DoP, OutputPath::DsdToPcm, OutputPath::Resampled]);
+        assert_eq!(all[0].spec, DSD64);
+        assert_eq!(all[1].spec.mode, OutputMode::DoP);
+        assert_eq!(all[1].spec.sample_rate, DSD64.sample_rate);
+        assert_eq!(all[2].spec.mode, OutputMode::DsdToPcm);
+        assert_eq!(all[2].spec.sample_rate, 88200);
+        // the same family before a closer rate
+        assert_eq!(all[3].spec.sample_rate, 176400);
+        assert_eq!(all[3].spec.mode, OutputMode::DsdToPcm);
+    }
+
+    #[test]
+    fn dsd_output_narrows_the_chain() {
+        assert_eq!(paths(&candidates(DSD64, DsdOutput::Native, 88200, None, None)), [OutputPath::NativeDsd]);
+        assert_eq!(paths(&candidates(DSD64, DsdOutput::DoP, 88200, None, None)), [OutputPath::DoP]);
+        assert_eq!(paths(&candidates(DSD64, DsdOutput::Pcm, 88200, None, None)), [OutputPath::DsdToPcm]);
+    }
+
+    #[test]
+    fn pcm_is_played_natively_then_resampled() {
+        assert_eq!(paths(&candidates(CD, DsdOutput::Auto, 0, None, None)), [OutputPath::NativePcm]);
+
+        let all = candidates(CD, DsdOutput::Auto, 0, None, Some(&caps(&[48000, 88200, 96000])));
+        assert_eq!(paths(&all), [OutputPath::NativePcm, OutputPath::Resampled]);
+        assert_eq!(all[0].spec, CD);
+        assert_eq!(all[1].spec.sample_rate, 88200);
+
+        // nothing to fall back to when the device takes only the source rate
+        let all = candidates(CD, DsdOutput::Auto, 0, None, Some(&caps(&[44100])));
+        assert_eq!(paths(&all), [OutputPath::NativePcm]);
+    }
+
+    #[test]
+    fn output_rate_forces_resampling() {
+        let all = candidates(CD, DsdOutput::Auto, 0, Some(96000), Some(&caps(&[44100, 96000])));
+        assert_eq!(paths(&all), [OutputPath::Resampled]);
+        assert_eq!(all[0].spec.sample_rate, 96000);
+
+        // the source rate already is the one asked for
+        let all = candidates(CD, DsdOutput::Auto, 0, Some(44100), None);
+        assert_eq!(paths(&all), [OutputPath::NativePcm]);
 
-    fn deref(&self) -> &Self::Target {
-        &self.output
+        let all = candidates(DSD64, DsdOutput::Pcm, 88200, Some(48000), None);
+        assert_eq!(paths(&all), [OutputPath::Resampled]);
+        assert_eq!(all[0].spec.mode, OutputMode::DsdToPcm);
     }
 }
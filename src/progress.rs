@@ -0,0 +1,154 @@
+use std::{
+    sync::Mutex,
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+
+/// Playback position shared between the player loop and controllers.
+#[derive(Default)]
+pub struct Progress {
+    state: Mutex<ProgressState>,
+}
+
+#[derive(Default)]
+struct ProgressState {
+    /// frames accepted by the device since the last reset
+    written: u64,
+    /// frames accepted by the device but not played yet
+    delay: u64,
+    /// device frame rate
+    rate: u32,
+    /// track position of the first written frame
+    offset: Duration,
+    duration: Option<Duration>,
+    /// written frame count where the next gapless track starts, with its duration
+    next: Option<(u64, Option<Duration>)>,
+}
+
+impl ProgressState {
+    fn played(&self) -> u64 {
+        self.written.saturating_sub(self.delay)
+    }
+
+    /// Move on to the next track once its first frame is heard.
+    fn advance(&mut self) {
+        if let Some((at, duration)) = self.next
+            && self.played() >= at
+        {
+            self.written -= at;
+            self.offset = Duration::ZERO;
+            self.duration = duration;
+            self.next = None;
+        }
+    }
+}
+
+impl Progress {
+    /// Start counting from `pos` of a track with `duration`, played at `rate` device frames per second.
+    pub fn reset(&self, pos: Duration, rate: u32, duration: Option<Duration>) {
+        *self.state.lock().unwrap() = ProgressState {
+            rate,
+            offset: pos,
+            duration,
+            ..Default::default()
+        };
+    }
+
+    /// The next track starts after `pending` frames not yet written to the device.
+    pub fn next_track(&self, pending: impl FnOnce() -> usize, duration: Option<Duration>) {
+        // frames leave the ring and count as written under the same lock
+        let mut state = self.state.lock().unwrap();
+        state.next = Some((state.written + pending() as u64, duration));
+    }
+
+    /// Count `frames` the device took, `consume` drops their samples from the ring
+    /// so a track change never sees them in both or neither.
+    pub fn add_written(&self, frames: usize, consume: impl FnOnce()) {
+        let mut state = self.state.lock().unwrap();
+        state.written += frames as u64;
+        consume();
+    }
+
+    pub fn set_delay(&self, frames: i64) {
+        self.state.lock().unwrap().delay = frames.max(0) as u64;
+    }
+
+    /// Time of the frame being heard right now.
+    pub fn elapsed(&self) -> Duration {
+        let mut state = self.state.lock().unwrap();
+        state.advance();
+
+        if state.rate == 0 {
+            return state.offset;
+        }
+
+        state.offset + Duration::from_secs_f64(state.played() as f64 / state.rate as f64)
+    }
+
+    pub fn duration(&self) -> Option<Duration> {
+        let mut state = self.state.lock().unwrap();
+        state.advance();
+        state.duration
+    }
+
+    pub fn remaining(&self) -> Option<Duration> {
+        self.duration().map(|d| d.saturating_sub(self.elapsed()))
+    }
+}
+
+/// Track position as `SECONDS`, `MM:SS` or `HH:MM:SS`, seconds may have a fraction.
+pub fn parse_position(s: &str) -> Result<Duration> {
+    if s.matches(':').count() > 2 {
+        return Err(anyhow!("expected SECONDS, MM:SS or HH:MM:SS"));
+    }
+
+    let mut fields = s.rsplit(':');
+    let secs = fields
+        .next()
+        .and_then(|f| f.parse::<f64>().ok())
+        .filter(|v| v.is_finite() && *v >= 0.0)
+        .ok_or(anyhow!("`{s}` is not a position"))?;
+
+    let mut total = secs;
+    for (f, unit) in fields.zip([60.0, 3600.0]) {
+        let v = f.parse::<u32>().map_err(|_| anyhow!("`{s}` is not a position"))?;
+        total += v as f64 * unit;
+    }
+
+    Ok(Duration::from_secs_f64(total))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_position_takes_seconds_and_clock_times() {
+        assert_eq!(parse_position("90").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse_position("1.5").unwrap(), Duration::from_millis(1500));
+        assert_eq!(parse_position("2:05").unwrap(), Duration::from_secs(125));
+        assert_eq!(parse_position("1:00:30.25").unwrap(), Duration::from_millis(3_630_250));
+        assert!(parse_position("x").is_err());
+        assert!(parse_position("-1").is_err());
+        assert!(parse_position("1:2:3:4").is_err());
+    }
+
+    #[test]
+    fn next_track_starts_after_the_pending_frames() {
+        let progress = Progress::default();
+        progress.reset(Duration::ZERO, 1000, Some(Duration::from_secs(60)));
+        progress.add_written(500, || {});
+        progress.next_track(|| 1000, Some(Duration::from_secs(30)));
+
+        // the old track plays on until the pending frames are heard
+        progress.add_written(900, || {});
+        assert_eq!(progress.elapsed(), Duration::from_millis(1400));
+        assert_eq!(progress.duration(), Some(Duration::from_secs(60)));
+
+        progress.add_written(300, || {});
+        progress.set_delay(100);
+        assert_eq!(progress.elapsed(), Duration::from_millis(100));
+        assert_eq!(progress.duration(), Some(Duration::from_secs(30)));
+    }
+}
@@ -0,0 +1,252 @@
+use std::collections::VecDeque;
+
+/// Most filter phases kept in the table, finer positions are interpolated.
+const MAX_PHASES: usize = 1024;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, clap::ValueEnum)]
+pub enum ResampleQuality {
+    Low,
+    Medium,
+    #[default]
+    High,
+}
+
+impl ResampleQuality {
+    /// Taps per output sample, passband edge relative to nyquist and kaiser beta.
+    fn params(&self) -> (usize, f64, f64) {
+        match self {
+            ResampleQuality::Low => (16, 0.85, 6.0),
+            ResampleQuality::Medium => (48, 0.91, 8.0),
+            ResampleQuality::High => (96, 0.95, 10.0),
+        }
+    }
+}
+
+fn gcd(a: u64, b: u64) -> u64 {
+    if b == 0 { a } else { gcd(b, a % b) }
+}
+
+/// Modified bessel function of the first kind, order 0.
+fn bessel_i0(x: f64) -> f64 {
+    let mut sum = 1.0;
+    let mut term = 1.0;
+    let mut k = 1.0;
+    while term > sum * 1e-12 {
+        term *= (x / (2.0 * k)).powi(2);
+        sum += term;
+        k += 1.0;
+    }
+    sum
+}
+
+/// Polyphase windowed sinc resampler for interleaved S32 samples.
+pub struct Resampler {
+    channel: usize,
+    taps: usize,
+    /// `phases` rows of `taps` coefficients, plus one extra row for interpolation
+    table: Vec<f32>,
+    phases: usize,
+    /// output step in input frames is `step / up`
+    up: u64,
+    step: u64,
+    /// input frames not consumed yet, interleaved
+    input: Vec<f32>,
+    /// frame of `input` the next output is centered on
+    pos: usize,
+    /// fractional part of the position, in `1 / up`
+    frac: u64,
+}
+
+impl Resampler {
+    pub fn new(from: u32, to: u32, channel: usize, quality: ResampleQuality) -> Self {
+        let g = gcd(from as u64, to as u64);
+        let up = to as u64 / g;
+        let step = from as u64 / g;
+
+        let (taps, rolloff, beta) = quality.params();
+        let phases = (up as usize).min(MAX_PHASES);
+        // the lower of both nyquists, relative to the input rate
+        let cutoff = 0.5 * (up as f64 / step as f64).min(1.0) * rolloff;
+
+        let half = taps as f64 / 2.0;
+        let mut table = Vec::with_capacity((phases + 1) * taps);
+        for p in 0..=phases {
+            let frac = p as f64 / phases as f64;
+            let row: Vec<f64> = (0..taps)
+                .map(|k| {
+                    // distance of input frame k to the output position
+                    let t = frac + half - 1.0 - k as f64;
+                    let sinc = if t == 0.0 {
+                        2.0 * cutoff
+                    } else {
+                        (2.0 * std::f64::consts::PI * cutoff * t).sin() / (std::f64::consts::PI * t)
+                    };
+                    let w = (1.0 - (t / half).powi(2)).max(0.0);
+                    sinc * bessel_i0(beta * w.sqrt()) / bessel_i0(beta)
+                })
+                .collect();
+
+            // unity gain at DC for every phase
+            let sum: f64 = row.iter().sum();
+            table.extend(row.iter().map(|v| (v / sum) as f32));
+        }
+
+        Self {
+            channel,
+            taps,
+            table,
+            phases,
+            up,
+            step,
+            input: vec![0.0; (taps / 2 - 1) * channel],
+            pos: taps / 2 - 1,
+            frac: 0,
+        }
+    }
+
+    /// Drop buffered input, e.g. after a seek.
+    pub fn reset(&mut self) {
+        self.input.clear();
+        self.input.resize((self.taps / 2 - 1) * self.channel, 0.0);
+        self.pos = self.taps / 2 - 1;
+        self.frac = 0;
+    }
+
+    /// Push out the filter tail at the end of the input, then start over like after `reset`.
+    pub fn flush(&mut self, out: &mut VecDeque<i32>) {
+        let tail = vec![0; self.taps / 2 * self.channel];
+        self.process(&tail, out);
+        self.reset();
+    }
+
+    pub fn process(&mut self, samples: &[i32], out: &mut VecDeque<i32>) {
+        let scale = 1.0 / i32::MAX as f32;
+        self.input.extend(samples.iter().map(|s| *s as f32 * scale));
+
+        let frames = self.input.len() / self.channel;
+        let half = self.taps / 2;
+
+        while self.pos + half < frames {
+            let phase = self.frac as f64 * self.phases as f64 / self.up as f64;
+            let p = phase as usize;
+            let w = (phase - p as f64) as f32;
+            let row0 = &self.table[p * self.taps..(p + 1) * self.taps];
+            let row1 = &self.table[(p + 1) * self.taps..(p + 2) * self.taps];
+
+            let first = self.pos + 1 - half;
+            for ch in 0..self.channel {
+                let mut v = 0.0;
+                for k in 0..self.taps {
+                    let h = row0[k] + (row1[k] - row0[k]) * w;
+                    v += h * self.input[(first + k) * self.channel + ch];
+                }
+                out.push_back((v.clamp(-1.0, 1.0) as f64 * i32::MAX as f64) as i32);
+            }
+
+            self.frac += self.step;
+            self.pos += (self.frac / self.up) as usize;
+            self.frac %= self.up;
+        }
+
+        // keep the history the next outputs still need
+        let drop = (self.pos + 1).saturating_sub(half).min(frames);
+        self.input.drain(..drop * self.channel);
+        self.pos -= drop;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sine(rate: u32, freq: f64, amplitude: f64, frames: usize) -> Vec<i32> {
+        (0..frames)
+            .map(|n| (amplitude * (2.0 * std::f64::consts::PI * freq * n as f64 / rate as f64).sin() * i32::MAX as f64) as i32)
+            .collect()
+    }
+
+    fn rms(samples: &[i32]) -> f64 {
+        let sum: f64 = samples.iter().map(|s| (*s as f64 / i32::MAX as f64).powi(2)).sum();
+        (sum / samples.len() as f64).sqrt()
+    }
+
+    #[test]
+    fn output_length_follows_the_rate_ratio() {
+        let mut resampler = Resampler::new(44_100, 48_000, 2, ResampleQuality::High);
+        let mut out = VecDeque::new();
+        for chunk in vec![0; 44_100 * 2].chunks(1000) {
+            resampler.process(chunk, &mut out);
+        }
+
+        // less the filter delay, which is still in the resampler
+        let frames = out.len() / 2;
+        assert!((48_000 - 60..=48_000).contains(&frames), "{frames}");
+    }
+
+    #[test]
+    fn dc_keeps_unity_gain() {
+        let mut resampler = Resampler::new(96_000, 44_100, 1, ResampleQuality::Medium);
+        let mut out = VecDeque::new();
+        resampler.process(&vec![i32::MAX / 2; 10_000], &mut out);
+
+        for s in out.iter().skip(100) {
+            assert!((*s as f64 / i32::MAX as f64 - 0.5).abs() < 1e-3);
+        }
+    }
+
+    #[test]
+    fn passband_tone_keeps_its_level() {
+        for quality in [ResampleQuality::Low, ResampleQuality::Medium, ResampleQuality::High] {
+            let mut resampler = Resampler::new(44_100, 48_000, 1, quality);
+            let mut out = VecDeque::new();
+            resampler.process(&sine(44_100, 1000.0, 0.5, 44_100), &mut out);
+
+            let out = Vec::from(out);
+            let level = 20.0 * (rms(&out[200..]) / rms(&sine(48_000, 1000.0, 0.5, 48_000))).log10();
+            assert!(level.abs() < 0.1, "{quality:?} {level}dB");
+        }
+    }
+
+    #[test]
+    fn downsampling_removes_tones_above_nyquist() {
+        let mut resampler = Resampler::new(96_000, 44_100, 1, ResampleQuality::High);
+        let mut out = VecDeque::new();
+        resampler.process(&sine(96_000, 30_000.0, 0.5, 96_000), &mut out);
+
+        let out = Vec::from(out);
+        let level = 20.0 * (rms(&out[200..]) / 0.5).log10();
+        assert!(level < -80.0, "{level}dB");
+    }
+
+    #[test]
+    fn flush_pushes_out_the_tail() {
+        let mut resampler = Resampler::new(44_100, 48_000, 2, ResampleQuality::High);
+        let mut out = VecDeque::new();
+        resampler.process(&vec![i32::MAX / 2; 44_100 * 2], &mut out);
+        resampler.flush(&mut out);
+
+        // one output frame for every input frame the filter reached, no delay left
+        let frames = out.len() / 2;
+        assert!((48_000..=48_001).contains(&frames), "{frames}");
+        // the last frames fade out into the silence after the input
+        let last = *out.back().unwrap() as f64 / i32::MAX as f64;
+        assert!((0.1..0.49).contains(&last), "{last}");
+
+        // and the next input starts from silence
+        out.clear();
+        resampler.process(&[0; 2000], &mut out);
+        assert!(out.iter().all(|s| *s == 0));
+    }
+
+    #[test]
+    fn reset_drops_the_history() {
+        let mut resampler = Resampler::new(44_100, 48_000, 1, ResampleQuality::Low);
+        let mut out = VecDeque::new();
+        resampler.process(&vec![i32::MAX / 2; 1000], &mut out);
+
+        resampler.reset();
+        out.clear();
+        resampler.process(&[0; 1000], &mut out);
+        assert!(out.iter().all(|s| *s == 0));
+    }
+}
@@ -0,0 +1,20 @@
+/// Keep the upper 16 bits of S32 samples.
+pub fn pack_s16(samples: &[i32], out: &mut Vec<i16>) {
+    out.clear();
+    out.extend(samples.iter().map(|s| (s >> 16) as i16));
+}
+
+/// Keep the upper 24 bits of S32 samples, right aligned in 32 bits.
+pub fn pack_s24(samples: &[i32], out: &mut Vec<i32>) {
+    out.clear();
+    out.extend(samples.iter().map(|s| s >> 8));
+}
+
+/// Pack the upper 24 bits of S32 samples into S24_3LE bytes.
+pub fn pack_s24_3le(samples: &[i32], out: &mut Vec<u8>) {
+    out.clear();
+    out.extend(samples.iter().flat_map(|s| {
+        let [_, b0, b1, b2] = s.to_le_bytes();
+        [b0, b1, b2]
+    }));
+}
@@ -0,0 +1,163 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    path::Path,
+    sync::{atomic::{AtomicUsize, Ordering}, Mutex},
+    thread,
+    time::UNIX_EPOCH,
+};
+
+use anyhow::{anyhow, Result};
+use tokio::task::spawn_blocking;
+
+use crate::{
+    cli::ScanArgs,
+    decoder::{Decoder, DecoderError, DecoderManager, TrackRange},
+    dsd::{dsd_pcm_rate, DsdToPcm},
+    loudness::{Loudness, LoudnessMeter},
+    media::{MediaScan, MediaSpec, OutputMode, DEFAULT_ALBUM_ID},
+    store::Store,
+};
+
+/// Analyze the loudness of every library file changed since the last scan.
+pub async fn scan(args: ScanArgs) -> Result<()> {
+    let mut store = Store::new().await?;
+    let media = store.get_media_scan().await?;
+
+    let changed: HashSet<i32> = media
+        .iter()
+        .filter(|m| args.force || file_stamp(Path::new(&m.file)).ok() != m.scan_mtime.zip(m.scan_size))
+        .map(|m| m.id)
+        .collect();
+
+    // album loudness gates over every track, one changed track measures the whole album again
+    let albums: HashSet<i32> = media
+        .iter()
+        .filter(|m| changed.contains(&m.id))
+        .filter_map(|m| m.album_id)
+        .filter(|id| *id != DEFAULT_ALBUM_ID)
+        .collect();
+
+    let todo: Vec<MediaScan> = media
+        .into_iter()
+        .filter(|m| changed.contains(&m.id) || m.album_id.is_some_and(|id| albums.contains(&id)))
+        .collect();
+
+    let jobs = args.jobs
+        .or_else(|| thread::available_parallelism().ok().map(|n| n.get()))
+        .unwrap_or(1);
+    println!("scan {} files with {jobs} jobs", todo.len());
+    let results = spawn_blocking(move || analyze_all(todo, jobs)).await?;
+
+    let mut album_tracks: HashMap<i32, Vec<Loudness>> = HashMap::new();
+    for r in results {
+        store.set_media_loudness(r.media.id, &r.loudness, r.mtime, r.size).await?;
+        if let Some(id) = r.media.album_id.filter(|id| albums.contains(id)) {
+            album_tracks.entry(id).or_default().push(r.loudness);
+        }
+    }
+
+    for (id, tracks) in album_tracks {
+        store.set_album_loudness(id, &Loudness::album(&tracks)).await?;
+    }
+
+    store.commit().await
+}
+
+struct Scanned {
+    media: MediaScan,
+    loudness: Loudness,
+    mtime: i64,
+    size: i64,
+}
+
+/// Modification time and size, a file with other values is scanned again.
+fn file_stamp(p: &Path) -> Result<(i64, i64)> {
+    let meta = std::fs::metadata(p)?;
+    let mtime = meta.modified()?.duration_since(UNIX_EPOCH)?.as_secs();
+    Ok((mtime as i64, meta.len() as i64))
+}
+
+/// Analyze on `jobs` threads, files that fail are reported and left out.
+fn analyze_all(media: Vec<MediaScan>, jobs: usize) -> Vec<Scanned> {
+    let next = AtomicUsize::new(0);
+    let results = Mutex::new(Vec::with_capacity(media.len()));
+
+    thread::scope(|s| {
+        for _ in 0..jobs.max(1) {
+            s.spawn(|| {
+                while let Some(m) = media.get(next.fetch_add(1, Ordering::Relaxed)) {
+                    // stamp before decoding, a file written meanwhile is scanned again next time
+                    match file_stamp(Path::new(&m.file)).and_then(|stamp| Ok((analyze(m.track())?, stamp))) {
+                        Ok((loudness, (mtime, size))) => {
+                            match loudness.integrated.zip(loudness.replay_gain()) {
+                                Some((l, gain)) => println!(
+                                    "{}: {l:.1} LUFS, {:.1} LU, {:.1} dBTP, gain {gain:+.2} dB",
+                                    m.file, loudness.range, loudness.true_peak,
+                                ),
+                                None => println!("{}: silent", m.file),
+                            }
+                            results.lock().unwrap().push(Scanned { media: m.clone(), loudness, mtime, size });
+                        },
+                        Err(e) => println!("skip {}: {e}", m.file),
+                    }
+                }
+            });
+        }
+    });
+
+    results.into_inner().unwrap()
+}
+
+/// Decode a whole track into a loudness meter, dsd is converted to pcm first.
+fn analyze(track: TrackRange) -> Result<Loudness> {
+    let mut dm = DecoderManager::default();
+    dm.open(track)?;
+
+    let mut buf = VecDeque::new();
+    let mut pcm = VecDeque::new();
+    // a chained stream may change its spec, every part is measured on its own
+    let mut parts = Vec::new();
+    let mut meter: Option<(LoudnessMeter, Option<DsdToPcm>)> = None;
+
+    loop {
+        let (mut m, mut dsd) = match meter.take() {
+            Some(meter) => meter,
+            None => open_meter(dm.spec().ok_or(anyhow!("unknown codec"))?)?,
+        };
+
+        let decoded = dm.decode(&mut buf);
+        match dsd.as_mut() {
+            Some(conv) => {
+                conv.process(buf.make_contiguous(), &mut pcm);
+                m.process(pcm.make_contiguous());
+                pcm.clear();
+            },
+            None => m.process(buf.make_contiguous()),
+        }
+        buf.clear();
+
+        match decoded {
+            Err(DecoderError::Abandoned(e)) => return Err((*e).into()),
+            Err(DecoderError::EOF) => {
+                parts.push(m.finish());
+                break;
+            },
+            Err(DecoderError::SpecChanged) => parts.push(m.finish()),
+            // skipped packets are left out of the measure
+            Ok(_) | Err(_) => meter = Some((m, dsd)),
+        }
+    }
+
+    Ok(Loudness::album(&parts))
+}
+
+fn open_meter(spec: MediaSpec) -> Result<(LoudnessMeter, Option<DsdToPcm>)> {
+    let channel = spec.channel as usize;
+    Ok(match spec.mode {
+        OutputMode::DSD => {
+            let rate = dsd_pcm_rate(spec.sample_rate);
+            (LoudnessMeter::new(rate, channel), Some(DsdToPcm::new(spec.sample_rate, rate, channel)?))
+        },
+        _ => (LoudnessMeter::new(spec.sample_rate, channel), None),
+    })
+}
@@ -0,0 +1,140 @@
+use std::{
+    collections::VecDeque,
+    io::{self, Read, Seek, SeekFrom},
+};
+
+use symphonia::core::io::MediaSource;
+
+/// Bytes kept behind the read position of a non-seekable stream.
+const WINDOW: usize = 64 * 1024;
+
+/// Media source whose head can be sniffed without seeking back. Non-seekable
+/// streams, e.g. stdin, keep a window of recent bytes for short backward seeks
+/// and seek forward by reading.
+pub struct BufferedSource {
+    inner: Box<dyn MediaSource>,
+    seekable: bool,
+    /// bytes read from `inner`, the first one at `buf_start`
+    buf: VecDeque<u8>,
+    buf_start: u64,
+    /// read position of this source
+    pos: u64,
+}
+
+impl BufferedSource {
+    pub fn new(inner: Box<dyn MediaSource>) -> Self {
+        Self {
+            seekable: inner.is_seekable(),
+            inner,
+            buf: VecDeque::new(),
+            buf_start: 0,
+            pos: 0,
+        }
+    }
+
+    /// Position of `inner`, everything before it is buffered or dropped.
+    fn inner_pos(&self) -> u64 {
+        self.buf_start + self.buf.len() as u64
+    }
+
+    /// Next `len` bytes without consuming them, fewer at the end of the stream.
+    pub fn peek(&mut self, len: usize) -> io::Result<Vec<u8>> {
+        let end = self.pos + len as u64;
+        let mut chunk = [0u8; 4096];
+        while self.inner_pos() < end {
+            let n = self.inner.read(&mut chunk[..(end - self.inner_pos()).min(4096) as usize])?;
+            if n == 0 {
+                break;
+            }
+            self.buf.extend(&chunk[..n]);
+        }
+
+        let from = (self.pos - self.buf_start) as usize;
+        Ok(self.buf.range(from..).take(len).copied().collect())
+    }
+
+    /// Drop buffered bytes nobody can seek back to anymore.
+    fn trim(&mut self) {
+        let keep_from = if self.seekable {
+            self.pos
+        } else {
+            self.pos.saturating_sub(WINDOW as u64)
+        };
+
+        let drop = keep_from.saturating_sub(self.buf_start).min(self.buf.len() as u64);
+        self.buf.drain(..drop as usize);
+        self.buf_start += drop;
+    }
+}
+
+impl Read for BufferedSource {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        let n = if self.pos < self.inner_pos() {
+            let from = (self.pos - self.buf_start) as usize;
+            let mut n = 0;
+            for (dst, src) in out.iter_mut().zip(self.buf.range(from..)) {
+                *dst = *src;
+                n += 1;
+            }
+            n
+        } else {
+            let n = self.inner.read(out)?;
+            if self.seekable {
+                // nothing is kept, the buffer is empty once it is read through
+                self.buf_start += n as u64;
+            } else {
+                self.buf.extend(&out[..n]);
+            }
+            n
+        };
+
+        self.pos += n as u64;
+        self.trim();
+        Ok(n)
+    }
+}
+
+impl Seek for BufferedSource {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let target = match pos {
+            SeekFrom::Start(n) => Some(n),
+            SeekFrom::Current(d) => self.pos.checked_add_signed(d),
+            SeekFrom::End(d) => match self.inner.byte_len() {
+                Some(len) => len.checked_add_signed(d),
+                None => return Err(io::Error::new(io::ErrorKind::Unsupported, "stream length unknown")),
+            },
+        };
+        let target = target.ok_or(io::Error::new(io::ErrorKind::InvalidInput, "seek before the start"))?;
+
+        if (self.buf_start..=self.inner_pos()).contains(&target) {
+            self.pos = target;
+        } else if self.seekable {
+            self.inner.seek(SeekFrom::Start(target))?;
+            self.buf.clear();
+            self.buf_start = target;
+            self.pos = target;
+        } else if target > self.inner_pos() {
+            self.pos = self.inner_pos();
+            let len = target - self.pos;
+            let skipped = io::copy(&mut self.by_ref().take(len), &mut io::sink())?;
+            if self.pos != target {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, format!("stream ended after {skipped} bytes")));
+            }
+        } else {
+            return Err(io::Error::other("seek back beyond the buffer of a non-seekable stream"));
+        }
+
+        self.trim();
+        Ok(self.pos)
+    }
+}
+
+impl MediaSource for BufferedSource {
+    fn is_seekable(&self) -> bool {
+        self.seekable
+    }
+
+    fn byte_len(&self) -> Option<u64> {
+        self.inner.byte_len()
+    }
+}
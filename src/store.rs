@@ -1,10 +1,33 @@
 use anyhow::Result;
 use sqlx::{Pool, Sqlite, Row};
 
-use crate::{media::{Album, AlbumInDb, Media}, shared::PROJ_DIRS};
+use crate::{
+    loudness::Loudness,
+    media::{Album, AlbumInDb, Media, MediaScan},
+    shared::PROJ_DIRS,
+};
+
+const INIT: &str = include_str!("../sql/init.sql");
+
+/// Makes the media table again on databases that are unique by file alone.
+const REBUILD_MEDIA: &str = include_str!("../sql/rebuild_media.sql");
 
 const TRASITION_COMMIT_LIMIT: u8 = 64;
 
+/// Columns added after the first release, created on databases that miss them.
+const ADDED_COLUMNS: &[(&str, &str, &str)] = &[
+    ("album", "loudness", "REAL"),
+    ("album", "loudness_range", "REAL"),
+    ("album", "true_peak", "REAL"),
+    ("media", "loudness", "REAL"),
+    ("media", "loudness_range", "REAL"),
+    ("media", "true_peak", "REAL"),
+    ("media", "scan_mtime", "INTEGER"),
+    ("media", "scan_size", "INTEGER"),
+    ("media", "start_us", "INTEGER NOT NULL DEFAULT 0"),
+    ("media", "end_us", "INTEGER"),
+];
+
 pub struct Store {
     conn: Pool<Sqlite>,
     tx: Option<sqlx::SqliteTransaction<'static>>,
@@ -14,20 +37,62 @@ pub struct Store {
 impl Store {
     pub async fn new() -> Result<Self> {
         let db_path = format!("sqlite:///{}", PROJ_DIRS.data_dir().join("db.sqlite").to_string_lossy());
-        let conn = Pool::<Sqlite>::connect(&db_path).await?;
-        let mut store = Self {
+        Self::open(&db_path).await
+    }
+
+    async fn open(db_path: &str) -> Result<Self> {
+        let mut conn = Pool::<Sqlite>::connect(db_path).await?;
+        if Self::init(&conn).await? {
+            // other connections of the pool still plan statements on the old table
+            conn.close().await;
+            conn = Pool::<Sqlite>::connect(db_path).await?;
+        }
+
+        Ok(Self {
             conn,
             tx: None,
             trasition: 0,
-        };
-        store.init().await?;
-        Ok(store)
+        })
     }
 
-    async fn init(&mut self) -> Result<()> {
-        let query = include_str!("../sql/init.sql");
-        sqlx::raw_sql(query).execute(&self.conn).await?;
-        Ok(())
+    /// Create or update the schema, true when the media table was made again.
+    async fn init(conn: &Pool<Sqlite>) -> Result<bool> {
+        sqlx::raw_sql(INIT).execute(conn).await?;
+
+        for (table, column, ty) in ADDED_COLUMNS {
+            let exists: i32 = sqlx::query("SELECT COUNT(*) AS n FROM pragma_table_info(?) WHERE name = ?;")
+                .bind(table)
+                .bind(column)
+                .fetch_one(conn)
+                .await?
+                .try_get("n")?;
+
+            if exists == 0 {
+                sqlx::raw_sql(&format!("ALTER TABLE {table} ADD COLUMN {column} {ty};"))
+                    .execute(conn)
+                    .await?;
+            }
+        }
+
+        // a unique index on nothing but the file keeps cue tracks out
+        let file_unique: i32 = sqlx::query("
+SELECT COUNT(*) AS n FROM pragma_index_list('media') AS l
+WHERE l.\"unique\" = 1 AND (SELECT group_concat(name) FROM pragma_index_info(l.name)) = 'file';
+        ")
+            .fetch_one(conn)
+            .await?
+            .try_get("n")?;
+
+        if file_unique > 0 {
+            let mut tx = conn.begin().await?;
+            sqlx::raw_sql(REBUILD_MEDIA).execute(&mut *tx).await?;
+            tx.commit().await?;
+
+            // indexes and the view went with the old table
+            sqlx::raw_sql(INIT).execute(conn).await?;
+        }
+
+        Ok(file_unique > 0)
     }
 
     pub async fn commit(&mut self) -> Result<()> {
@@ -82,4 +147,182 @@ RETURNING id;
 
         Ok(id)
     }
+
+    pub async fn get_media_scan(&mut self) -> Result<Vec<MediaScan>> {
+        let query = "SELECT id, file, start_us, end_us, album_id, scan_mtime, scan_size FROM media;";
+        let media = sqlx::query_as::<_, MediaScan>(query)
+            .fetch_all(&self.conn)
+            .await?;
+
+        Ok(media)
+    }
+
+    /// Store the analysis of a media file, `mtime` and `size` mark the file as scanned.
+    pub async fn set_media_loudness(&mut self, id: i32, loudness: &Loudness, mtime: i64, size: i64) -> Result<()> {
+        let query = "
+UPDATE media
+SET loudness = ?, loudness_range = ?, true_peak = ?, scan_mtime = ?, scan_size = ?
+WHERE id = ?;
+        ";
+
+        if self.tx.is_none() {
+            self.tx = Some(self.conn.begin().await?);
+        }
+
+        let tx = self.tx.as_mut().unwrap();
+        sqlx::query(query)
+            .bind(loudness.integrated)
+            .bind(loudness.range)
+            // digital silence has no peak
+            .bind(loudness.true_peak.is_finite().then_some(loudness.true_peak))
+            .bind(mtime)
+            .bind(size)
+            .bind(id)
+            .execute(&mut **tx)
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn set_album_loudness(&mut self, id: i32, loudness: &Loudness) -> Result<()> {
+        let query = "UPDATE album SET loudness = ?, loudness_range = ?, true_peak = ? WHERE id = ?;";
+
+        if self.tx.is_none() {
+            self.tx = Some(self.conn.begin().await?);
+        }
+
+        let tx = self.tx.as_mut().unwrap();
+        sqlx::query(query)
+            .bind(loudness.integrated)
+            .bind(loudness.range)
+            // digital silence has no peak
+            .bind(loudness.true_peak.is_finite().then_some(loudness.true_peak))
+            .bind(id)
+            .execute(&mut **tx)
+            .await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A fresh database file, `name` keeps parallel tests apart.
+    fn db_path(name: &str) -> String {
+        let p = std::env::temp_dir().join(format!("oto-{name}-{}.sqlite", std::process::id()));
+        let _ = std::fs::remove_file(&p);
+        format!("sqlite://{}?mode=rwc", p.display())
+    }
+
+    type MediaRow = (i64, String, i64, Option<i64>, String, Option<String>, i64, Option<i64>, Option<f64>, Option<i64>, Option<i64>);
+
+    async fn rows(store: &Store) -> Vec<MediaRow> {
+        sqlx::query_as("
+SELECT id, file, start_us, end_us, name, artist, album_id, track, loudness, scan_mtime, scan_size
+FROM media ORDER BY id;
+        ")
+            .fetch_all(&store.conn)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn file_unique_table_is_rebuilt() {
+        let path = db_path("rebuild");
+        {
+            // a database of the release before cue sheets, analysed already
+            let conn = Pool::<Sqlite>::connect(&path).await.unwrap();
+            sqlx::raw_sql("
+CREATE TABLE album (id INTEGER PRIMARY KEY AUTOINCREMENT, name TEXT NOT NULL, year INTEGER, track INTEGER, cover TEXT);
+CREATE TABLE media (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    file TEXT NOT NULL,
+    name TEXT NOT NULL,
+    artist TEXT,
+    album_id INTEGER,
+    track INTEGER,
+    loudness REAL,
+    loudness_range REAL,
+    true_peak REAL,
+    scan_mtime INTEGER,
+    scan_size INTEGER,
+    UNIQUE(file),
+    FOREIGN KEY (album_id) REFERENCES album(id)
+);
+INSERT INTO album (name) VALUES ('Unknown Album'), ('Album');
+INSERT INTO media (file, name, artist, album_id, track, loudness, scan_mtime, scan_size)
+VALUES ('a.flac', 'One', 'Artist', 2, 1, -14.5, 1700000000, 1234);
+INSERT INTO media (file, name, album_id) VALUES ('b.flac', 'Two', 1);
+DELETE FROM media WHERE file = 'a.flac';
+INSERT INTO media (file, name, artist, album_id, track, loudness, scan_mtime, scan_size)
+VALUES ('a.flac', 'One', 'Artist', 2, 1, -14.5, 1700000000, 1234);
+            ")
+                .execute(&conn)
+                .await
+                .unwrap();
+            conn.close().await;
+        }
+
+        let store = Store::open(&path).await.unwrap();
+
+        // every row keeps its id and values, the gap in the ids included
+        assert_eq!(rows(&store).await, [
+            (2, "b.flac".to_owned(), 0, None, "Two".to_owned(), None, 1, None, None, None, None),
+            (3, "a.flac".to_owned(), 0, None, "One".to_owned(), Some("Artist".to_owned()), 2, Some(1), Some(-14.5), Some(1700000000), Some(1234)),
+        ]);
+
+        // a second track of the same file fits now, the same track twice still does not
+        let insert = "INSERT INTO media (file, start_us, name, album_id) VALUES ('a.flac', ?, 'Cue', 2);";
+        sqlx::query(insert).bind(180_000_000).execute(&store.conn).await.unwrap();
+        assert!(sqlx::query(insert).bind(180_000_000).execute(&store.conn).await.is_err());
+
+        // new rows go after the old ids
+        let id: i64 = sqlx::query("SELECT id FROM media WHERE start_us = 180000000;")
+            .fetch_one(&store.conn)
+            .await
+            .unwrap()
+            .try_get("id")
+            .unwrap();
+        assert_eq!(id, 4);
+
+        // the view and indexes are back
+        let n: i32 = sqlx::query("SELECT COUNT(*) AS n FROM media_with_album WHERE album_name = 'Album';")
+            .fetch_one(&store.conn)
+            .await
+            .unwrap()
+            .try_get("n")
+            .unwrap();
+        assert_eq!(n, 2);
+        let n: i32 = sqlx::query("SELECT COUNT(*) AS n FROM pragma_index_list('media') WHERE name = 'idx_media_album_id';")
+            .fetch_one(&store.conn)
+            .await
+            .unwrap()
+            .try_get("n")
+            .unwrap();
+        assert_eq!(n, 1);
+    }
+
+    #[tokio::test]
+    async fn current_table_is_kept() {
+        let path = db_path("current");
+        let store = Store::open(&path).await.unwrap();
+        sqlx::query("INSERT INTO media (file, name, album_id) VALUES ('a.flac', 'One', 1);")
+            .execute(&store.conn)
+            .await
+            .unwrap();
+        store.conn.close().await;
+
+        // opening again finds nothing to rebuild
+        let conn = Pool::<Sqlite>::connect(&path).await.unwrap();
+        assert!(!Store::init(&conn).await.unwrap());
+        let n: i32 = sqlx::query("SELECT COUNT(*) AS n FROM media;")
+            .fetch_one(&conn)
+            .await
+            .unwrap()
+            .try_get("n")
+            .unwrap();
+        assert_eq!(n, 1);
+    }
 }
@@ -0,0 +1,411 @@
+use std::fmt::Display;
+
+use id3::TagLike;
+use symphonia::core::meta::{MetadataRevision, StandardTagKey, StandardVisualKey};
+
+/// Embedded artwork.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Picture {
+    pub mime: String,
+    pub front_cover: bool,
+    pub data: Vec<u8>,
+}
+
+/// MusicBrainz identifiers, as written by Picard.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MusicBrainzIds {
+    pub recording_id: Option<String>,
+    pub release_track_id: Option<String>,
+    pub album_id: Option<String>,
+    pub release_group_id: Option<String>,
+    pub work_id: Option<String>,
+    pub artist_ids: Vec<String>,
+    pub album_artist_ids: Vec<String>,
+}
+
+/// Tags of a track with the same fields whatever the container wrote,
+/// multi valued fields keep every value in order.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Tags {
+    pub title: Option<String>,
+    pub artists: Vec<String>,
+    pub album: Option<String>,
+    pub album_artists: Vec<String>,
+    pub track_number: Option<u32>,
+    pub track_total: Option<u32>,
+    pub disc_number: Option<u32>,
+    pub disc_total: Option<u32>,
+    /// as tagged, `2001`, `2001-09-11` or a full timestamp
+    pub date: Option<String>,
+    pub genres: Vec<String>,
+    pub composers: Vec<String>,
+    pub musicbrainz: MusicBrainzIds,
+    pub pictures: Vec<Picture>,
+}
+
+/// Fields a tag can set.
+enum Field {
+    Title,
+    Artist,
+    Album,
+    AlbumArtist,
+    TrackNumber,
+    TrackTotal,
+    DiscNumber,
+    DiscTotal,
+    Date,
+    Genre,
+    Composer,
+    RecordingId,
+    ReleaseTrackId,
+    AlbumId,
+    ReleaseGroupId,
+    WorkId,
+    ArtistId,
+    AlbumArtistId,
+}
+
+impl Field {
+    fn from_std_key(key: StandardTagKey) -> Option<Self> {
+        Some(match key {
+            StandardTagKey::TrackTitle => Field::Title,
+            StandardTagKey::Artist => Field::Artist,
+            StandardTagKey::Album => Field::Album,
+            StandardTagKey::AlbumArtist => Field::AlbumArtist,
+            StandardTagKey::TrackNumber => Field::TrackNumber,
+            StandardTagKey::TrackTotal => Field::TrackTotal,
+            StandardTagKey::DiscNumber => Field::DiscNumber,
+            StandardTagKey::DiscTotal => Field::DiscTotal,
+            StandardTagKey::Date | StandardTagKey::ReleaseDate => Field::Date,
+            StandardTagKey::Genre => Field::Genre,
+            StandardTagKey::Composer => Field::Composer,
+            StandardTagKey::MusicBrainzRecordingId | StandardTagKey::MusicBrainzTrackId => Field::RecordingId,
+            StandardTagKey::MusicBrainzReleaseTrackId => Field::ReleaseTrackId,
+            StandardTagKey::MusicBrainzAlbumId => Field::AlbumId,
+            StandardTagKey::MusicBrainzReleaseGroupId => Field::ReleaseGroupId,
+            StandardTagKey::MusicBrainzWorkId => Field::WorkId,
+            StandardTagKey::MusicBrainzArtistId => Field::ArtistId,
+            StandardTagKey::MusicBrainzAlbumArtistId => Field::AlbumArtistId,
+            _ => return None,
+        })
+    }
+
+    /// Keys readers leave without a standard key, e.g. id3 TXXX descriptions
+    /// like `MusicBrainz Album Id` or vorbis comments like `MUSICBRAINZ_ALBUMID`.
+    fn from_key(key: &str) -> Option<Self> {
+        let key = key.rsplit(':').next().unwrap_or(key);
+        let key: String = key
+            .chars()
+            .filter(|c| c.is_ascii_alphanumeric())
+            .map(|c| c.to_ascii_lowercase())
+            .collect();
+
+        Some(match key.as_str() {
+            "title" => Field::Title,
+            "artist" | "artists" => Field::Artist,
+            "album" => Field::Album,
+            "albumartist" => Field::AlbumArtist,
+            "tracknumber" => Field::TrackNumber,
+            "tracktotal" | "totaltracks" => Field::TrackTotal,
+            "discnumber" => Field::DiscNumber,
+            "disctotal" | "totaldiscs" => Field::DiscTotal,
+            "date" | "year" => Field::Date,
+            "genre" => Field::Genre,
+            "composer" => Field::Composer,
+            "musicbrainztrackid" | "musicbrainzrecordingid" => Field::RecordingId,
+            "musicbrainzreleasetrackid" => Field::ReleaseTrackId,
+            "musicbrainzalbumid" => Field::AlbumId,
+            "musicbrainzreleasegroupid" => Field::ReleaseGroupId,
+            "musicbrainzworkid" => Field::WorkId,
+            "musicbrainzartistid" => Field::ArtistId,
+            "musicbrainzalbumartistid" => Field::AlbumArtistId,
+            _ => return None,
+        })
+    }
+}
+
+/// `3` or `3/12`, number and total.
+fn parse_pair(value: &str) -> (Option<u32>, Option<u32>) {
+    let mut parts = value.split('/').map(|p| p.trim().parse().ok());
+    (parts.next().flatten(), parts.next().flatten())
+}
+
+fn set_text(field: &mut Option<String>, value: &str) {
+    if !value.is_empty() {
+        *field = Some(value.to_owned());
+    }
+}
+
+fn push_unique(values: &mut Vec<String>, value: &str) {
+    if !value.is_empty() && !values.iter().any(|v| v == value) {
+        values.push(value.to_owned());
+    }
+}
+
+impl Tags {
+    /// Tags of one metadata revision.
+    pub fn from_revision(rev: &MetadataRevision) -> Self {
+        let mut tags = Self::default();
+        for tag in rev.tags() {
+            let field = tag.std_key.and_then(Field::from_std_key).or_else(|| Field::from_key(&tag.key));
+            if let Some(field) = field {
+                // id3v2.4 separates multiple values with nul
+                for value in tag.value.to_string().split('\0') {
+                    tags.set(&field, value.trim());
+                }
+            }
+        }
+
+        tags.pictures = rev
+            .visuals()
+            .iter()
+            .map(|v| Picture {
+                mime: v.media_type.clone(),
+                front_cover: v.usage == Some(StandardVisualKey::FrontCover),
+                data: v.data.to_vec(),
+            })
+            .collect();
+
+        tags
+    }
+
+    /// Tags of an id3 tag, as found in dsd files.
+    pub fn from_id3(tag: &id3::Tag) -> Self {
+        let mut tags = Self {
+            title: tag.title().map(str::to_owned),
+            artists: tag.artists().unwrap_or_default().into_iter().map(str::to_owned).collect(),
+            album: tag.album().map(str::to_owned),
+            album_artists: tag.album_artist().into_iter().flat_map(|a| a.split('\0')).map(str::to_owned).collect(),
+            track_number: tag.track(),
+            track_total: tag.total_tracks(),
+            disc_number: tag.disc(),
+            disc_total: tag.total_discs(),
+            date: tag.date_recorded().or(tag.date_released()).map(|d| d.to_string()),
+            genres: tag.genres().unwrap_or_default().into_iter().map(str::to_owned).collect(),
+            ..Default::default()
+        };
+
+        if let Some(values) = tag.get("TCOM").and_then(|f| f.content().text_values()) {
+            values.for_each(|v| push_unique(&mut tags.composers, v));
+        }
+
+        for text in tag.extended_texts() {
+            if let Some(field) = Field::from_key(&text.description) {
+                for value in text.value.split('\0') {
+                    tags.set(&field, value.trim());
+                }
+            }
+        }
+
+        // Picard writes the recording id as a unique file identifier
+        for ufid in tag.unique_file_identifiers() {
+            if ufid.owner_identifier == "http://musicbrainz.org" {
+                tags.musicbrainz.recording_id = Some(String::from_utf8_lossy(&ufid.identifier).into_owned());
+            }
+        }
+
+        tags.pictures = tag
+            .pictures()
+            .map(|p| Picture {
+                mime: p.mime_type.clone(),
+                front_cover: p.picture_type == id3::frame::PictureType::CoverFront,
+                data: p.data.clone(),
+            })
+            .collect();
+
+        tags
+    }
+
+    fn set(&mut self, field: &Field, value: &str) {
+        let mb = &mut self.musicbrainz;
+        match field {
+            Field::Title => set_text(&mut self.title, value),
+            Field::Artist => push_unique(&mut self.artists, value),
+            Field::Album => set_text(&mut self.album, value),
+            Field::AlbumArtist => push_unique(&mut self.album_artists, value),
+            Field::TrackNumber => {
+                let (number, total) = parse_pair(value);
+                self.track_number = number.or(self.track_number);
+                self.track_total = total.or(self.track_total);
+            },
+            Field::TrackTotal => self.track_total = value.parse().ok().or(self.track_total),
+            Field::DiscNumber => {
+                let (number, total) = parse_pair(value);
+                self.disc_number = number.or(self.disc_number);
+                self.disc_total = total.or(self.disc_total);
+            },
+            Field::DiscTotal => self.disc_total = value.parse().ok().or(self.disc_total),
+            Field::Date => set_text(&mut self.date, value),
+            Field::Genre => push_unique(&mut self.genres, value),
+            Field::Composer => push_unique(&mut self.composers, value),
+            Field::RecordingId => set_text(&mut mb.recording_id, value),
+            Field::ReleaseTrackId => set_text(&mut mb.release_track_id, value),
+            Field::AlbumId => set_text(&mut mb.album_id, value),
+            Field::ReleaseGroupId => set_text(&mut mb.release_group_id, value),
+            Field::WorkId => set_text(&mut mb.work_id, value),
+            // vorbis comments may also join several ids with `/`
+            Field::ArtistId => value.split('/').for_each(|v| push_unique(&mut mb.artist_ids, v.trim())),
+            Field::AlbumArtistId => value.split('/').for_each(|v| push_unique(&mut mb.album_artist_ids, v.trim())),
+        }
+    }
+
+    /// Overlay the fields `newer` has, e.g. the next revision of a stream or the
+    /// format tags over a leading id3 tag.
+    pub fn update(&mut self, newer: Tags) {
+        let Tags {
+            title,
+            artists,
+            album,
+            album_artists,
+            track_number,
+            track_total,
+            disc_number,
+            disc_total,
+            date,
+            genres,
+            composers,
+            musicbrainz,
+            pictures,
+        } = newer;
+
+        let replace = |old: &mut Vec<String>, new: Vec<String>| {
+            if !new.is_empty() {
+                *old = new;
+            }
+        };
+
+        self.title = title.or(self.title.take());
+        replace(&mut self.artists, artists);
+        self.album = album.or(self.album.take());
+        replace(&mut self.album_artists, album_artists);
+        self.track_number = track_number.or(self.track_number);
+        self.track_total = track_total.or(self.track_total);
+        self.disc_number = disc_number.or(self.disc_number);
+        self.disc_total = disc_total.or(self.disc_total);
+        self.date = date.or(self.date.take());
+        replace(&mut self.genres, genres);
+        replace(&mut self.composers, composers);
+
+        let mb = &mut self.musicbrainz;
+        mb.recording_id = musicbrainz.recording_id.or(mb.recording_id.take());
+        mb.release_track_id = musicbrainz.release_track_id.or(mb.release_track_id.take());
+        mb.album_id = musicbrainz.album_id.or(mb.album_id.take());
+        mb.release_group_id = musicbrainz.release_group_id.or(mb.release_group_id.take());
+        mb.work_id = musicbrainz.work_id.or(mb.work_id.take());
+        replace(&mut mb.artist_ids, musicbrainz.artist_ids);
+        replace(&mut mb.album_artist_ids, musicbrainz.album_artist_ids);
+
+        if !pictures.is_empty() {
+            self.pictures = pictures;
+        }
+    }
+}
+
+/// `Artist - Title`, with what of both is known.
+impl Display for Tags {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let artists = self.artists.join(", ");
+        match (artists.is_empty(), &self.title) {
+            (false, Some(title)) => write!(f, "{artists} - {title}"),
+            (true, Some(title)) => write!(f, "{title}"),
+            (false, None) => write!(f, "{artists}"),
+            (true, None) => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use symphonia::core::meta::{MetadataBuilder, Tag, Value};
+
+    use super::*;
+
+    fn revision(tags: &[(Option<StandardTagKey>, &str, &str)]) -> MetadataRevision {
+        let mut builder = MetadataBuilder::new();
+        for (std_key, key, value) in tags {
+            builder.add_tag(Tag::new(*std_key, key, Value::from(*value)));
+        }
+        builder.metadata()
+    }
+
+    #[test]
+    fn vorbis_comments_are_normalized() {
+        let tags = Tags::from_revision(&revision(&[
+            (Some(StandardTagKey::TrackTitle), "TITLE", "Song"),
+            (Some(StandardTagKey::Artist), "ARTIST", "A"),
+            (Some(StandardTagKey::Artist), "ARTIST", "B"),
+            (Some(StandardTagKey::Artist), "ARTIST", "A"),
+            (Some(StandardTagKey::TrackNumber), "TRACKNUMBER", "3/12"),
+            (None, "TOTALDISCS", "2"),
+            (Some(StandardTagKey::DiscNumber), "DISCNUMBER", "1"),
+            (Some(StandardTagKey::Genre), "GENRE", ""),
+            (None, "MUSICBRAINZ_ALBUMID", "album-id"),
+            (None, "MUSICBRAINZ_ARTISTID", "a-id / b-id"),
+            (None, "COMMENT", "ignored"),
+        ]));
+
+        assert_eq!(tags.title.as_deref(), Some("Song"));
+        assert_eq!(tags.artists, ["A", "B"]);
+        assert_eq!((tags.track_number, tags.track_total), (Some(3), Some(12)));
+        assert_eq!((tags.disc_number, tags.disc_total), (Some(1), Some(2)));
+        assert!(tags.genres.is_empty());
+        assert_eq!(tags.musicbrainz.album_id.as_deref(), Some("album-id"));
+        assert_eq!(tags.musicbrainz.artist_ids, ["a-id", "b-id"]);
+    }
+
+    #[test]
+    fn id3v24_values_split_at_nul() {
+        let tags = Tags::from_revision(&revision(&[
+            (Some(StandardTagKey::Artist), "TPE1", "A\0B"),
+            (None, "TXXX:MusicBrainz Release Group Id", "group-id"),
+        ]));
+
+        assert_eq!(tags.artists, ["A", "B"]);
+        assert_eq!(tags.musicbrainz.release_group_id.as_deref(), Some("group-id"));
+    }
+
+    #[test]
+    fn id3_tags_of_dsd_files_are_normalized() {
+        let mut tag = id3::Tag::new();
+        tag.set_title("Song");
+        tag.set_artist("A\0B");
+        tag.set_track(3);
+        tag.set_total_tracks(12);
+        tag.set_text("TCOM", "C");
+        tag.add_frame(id3::frame::ExtendedText {
+            description: "MusicBrainz Album Id".to_owned(),
+            value: "album-id".to_owned(),
+        });
+        tag.add_frame(id3::frame::UniqueFileIdentifier {
+            owner_identifier: "http://musicbrainz.org".to_owned(),
+            identifier: b"recording-id".to_vec(),
+        });
+
+        let tags = Tags::from_id3(&tag);
+        assert_eq!(tags.title.as_deref(), Some("Song"));
+        assert_eq!(tags.artists, ["A", "B"]);
+        assert_eq!((tags.track_number, tags.track_total), (Some(3), Some(12)));
+        assert_eq!(tags.composers, ["C"]);
+        assert_eq!(tags.musicbrainz.album_id.as_deref(), Some("album-id"));
+        assert_eq!(tags.musicbrainz.recording_id.as_deref(), Some("recording-id"));
+    }
+
+    #[test]
+    fn update_keeps_what_the_newer_tags_lack() {
+        let mut tags = Tags {
+            title: Some("Old".to_owned()),
+            album: Some("Album".to_owned()),
+            artists: vec!["A".to_owned()],
+            ..Default::default()
+        };
+        tags.update(Tags {
+            title: Some("New".to_owned()),
+            ..Default::default()
+        });
+
+        assert_eq!(tags.title.as_deref(), Some("New"));
+        assert_eq!(tags.album.as_deref(), Some("Album"));
+        assert_eq!(tags.to_string(), "A - New");
+        assert_eq!(Tags::default().to_string(), "");
+    }
+}
@@ -0,0 +1,270 @@
+use std::{collections::VecDeque, path::Path};
+
+use anyhow::{anyhow, Result};
+use walkdir::WalkDir;
+
+use crate::{
+    cli::VerifyArgs,
+    decoder::{path_hint, Decoder, DecoderError, DecoderManager, PcmDecoder},
+};
+
+/// Result of decoding one file end to end.
+pub struct VerifyReport {
+    /// checksum match, none when the file has no checksum
+    pub checksum: Option<bool>,
+    /// packets that failed to read or decode
+    pub skipped_packets: u64,
+    /// decoding was given up after too many failures in a row
+    pub abandoned: bool,
+}
+
+impl VerifyReport {
+    pub fn passed(&self) -> bool {
+        self.checksum != Some(false) && self.skipped_packets == 0 && !self.abandoned
+    }
+}
+
+/// Decode every file below the given paths and report the broken ones.
+pub fn verify(args: VerifyArgs) -> Result<()> {
+    let files = args.path.into_iter().flat_map(|p| {
+        WalkDir::new(p)
+            .into_iter()
+            .flatten()
+            .filter(|e| e.file_type().is_file() && crate::is_media_file(e.path()))
+            .map(|e| e.into_path())
+    });
+
+    let (mut checked, mut failed) = (0, 0);
+    for p in files {
+        checked += 1;
+        match verify_file(&p, args.error_budget) {
+            Ok(report) if report.passed() => match report.checksum {
+                Some(_) => println!("ok   {}", p.display()),
+                None => println!("ok   {} (no checksum)", p.display()),
+            },
+            Ok(report) => {
+                failed += 1;
+                let mut reasons = Vec::new();
+                if report.checksum == Some(false) {
+                    reasons.push("md5 mismatch".to_owned());
+                }
+                if report.skipped_packets > 0 {
+                    reasons.push(format!("{} bad packets", report.skipped_packets));
+                }
+                if report.abandoned {
+                    reasons.push(format!("gave up after {} failures in a row", args.error_budget));
+                }
+                println!("FAIL {}: {}", p.display(), reasons.join(", "));
+            },
+            Err(e) => {
+                failed += 1;
+                println!("FAIL {}: {e}", p.display());
+            },
+        }
+    }
+
+    println!("{checked} files checked, {failed} failed");
+    if failed > 0 {
+        return Err(anyhow!("{failed} files failed verification"));
+    }
+
+    Ok(())
+}
+
+/// Run the whole default track through `PcmDecoder` with checksum verification,
+/// `budget` failures in a row give the file up.
+pub fn verify_file(p: &Path, budget: u32) -> Result<VerifyReport> {
+    // dsd containers have no checksum, they are only read to the end
+    if p.extension().is_some_and(|e| e.eq_ignore_ascii_case("dsf") || e.eq_ignore_ascii_case("dff")) {
+        let mut dm = DecoderManager::default();
+        dm.set_error_budget(budget);
+        dm.open(p.to_path_buf())?;
+        let (failures, abandoned) = read_to_end(&mut dm, budget)?;
+
+        return Ok(VerifyReport {
+            checksum: None,
+            skipped_packets: failures,
+            abandoned,
+        });
+    }
+
+    let file = std::fs::File::open(p)?;
+    let mut decoder = PcmDecoder::new(Box::new(file), &path_hint(p), None, true)?;
+    let (_, abandoned) = read_to_end(&mut decoder, budget)?;
+
+    Ok(VerifyReport {
+        // a checksum over part of the file means nothing
+        checksum: if abandoned { None } else { decoder.finalize() },
+        skipped_packets: decoder.skipped_packets(),
+        abandoned,
+    })
+}
+
+/// Decode until the end, returns the failed reads and decodes and whether
+/// `budget` of them in a row gave up.
+fn read_to_end(decoder: &mut dyn Decoder, budget: u32) -> Result<(u64, bool)> {
+    let mut buf = VecDeque::new();
+    let (mut failures, mut in_row) = (0, 0);
+
+    loop {
+        match decoder.decode(&mut buf) {
+            Ok(_) => in_row = 0,
+            Err(DecoderError::Ignored) | Err(DecoderError::SpecChanged) => {},
+            Err(DecoderError::Io(_)) | Err(DecoderError::Corrupt(_)) => {
+                failures += 1;
+                in_row += 1;
+                if in_row >= budget.max(1) {
+                    return Ok((failures, true));
+                }
+            },
+            Err(DecoderError::Abandoned(_)) => return Ok((failures + 1, true)),
+            Err(DecoderError::EOF) => return Ok((failures, false)),
+            Err(e) => return Err(e.into()),
+        }
+        buf.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use symphonia::core::{
+        checksum::{Crc16Ansi, Crc8Ccitt, Md5},
+        io::Monitor,
+    };
+
+    use super::*;
+    use crate::{decoder::AudioTrack, gain::ReplayGain, media::MediaSpec, tags::Tags};
+
+    /// Fails every read after `good` packets.
+    struct Broken {
+        good: u32,
+    }
+
+    impl Decoder for Broken {
+        fn decode(&mut self, buf: &mut VecDeque<i32>) -> Result<(), DecoderError> {
+            if self.good == 0 {
+                return Err(DecoderError::Io("unreadable".to_owned()));
+            }
+            self.good -= 1;
+            buf.push_back(0);
+            Ok(())
+        }
+
+        fn spec(&self) -> Option<MediaSpec> {
+            None
+        }
+
+        fn seek(&mut self, _pos: Duration) -> Result<(), DecoderError> {
+            Ok(())
+        }
+
+        fn frames(&self) -> Option<u64> {
+            None
+        }
+
+        fn replay_gain(&self) -> ReplayGain {
+            ReplayGain::default()
+        }
+
+        fn tracks(&self) -> Vec<AudioTrack> {
+            vec![]
+        }
+
+        fn metadata(&self) -> Tags {
+            Tags::default()
+        }
+    }
+
+    #[test]
+    fn endless_failures_give_up_at_the_budget() {
+        let (failures, abandoned) = read_to_end(&mut Broken { good: 3 }, 16).unwrap();
+        assert!(abandoned);
+        assert_eq!(failures, 16);
+    }
+
+    #[test]
+    fn dsd_files_are_media_files() {
+        for ext in ["flac", "wav", "dsf", "dff"] {
+            assert!(crate::is_media_file(Path::new(&format!("a.{ext}"))));
+        }
+        assert!(!crate::is_media_file(Path::new("a.cue")));
+    }
+
+    /// A mono 16 bit 44.1kHz FLAC file of one verbatim frame, `md5` goes into STREAMINFO.
+    fn flac(samples: &[i16], md5: [u8; 16]) -> Vec<u8> {
+        let mut file = b"fLaC".to_vec();
+        // last metadata block, STREAMINFO of 34 bytes
+        file.extend([0x80, 0, 0, 34]);
+        let block = samples.len() as u16;
+        file.extend(block.to_be_bytes());
+        file.extend(block.to_be_bytes());
+        file.extend([0; 6]);
+        // 20 bit rate, 3 bit channels - 1, 5 bit depth - 1, 36 bit sample count
+        let packed = (44100u64 << 44) | (15 << 36) | samples.len() as u64;
+        file.extend(packed.to_be_bytes());
+        file.extend(md5);
+
+        // fixed block size, 8 bit block size - 1 at the end, 44.1kHz, mono, 16 bit, frame 0
+        let mut frame = vec![0xff, 0xf8, 0x69, 0x08, 0x00, (block - 1) as u8];
+        let mut crc8 = Crc8Ccitt::new(0);
+        crc8.process_buf_bytes(&frame);
+        frame.push(crc8.crc());
+        // verbatim subframe
+        frame.push(0x02);
+        frame.extend(samples.iter().flat_map(|s| s.to_be_bytes()));
+        let mut crc16 = Crc16Ansi::new(0);
+        crc16.process_buf_bytes(&frame);
+        frame.extend(crc16.crc().to_be_bytes());
+
+        file.extend(frame);
+        file
+    }
+
+    /// MD5 of the samples the way FLAC sums them, little endian.
+    fn md5(samples: &[i16]) -> [u8; 16] {
+        let mut md5 = Md5::default();
+        md5.process_buf_bytes(&samples.iter().flat_map(|s| s.to_le_bytes()).collect::<Vec<_>>());
+        md5.md5()
+    }
+
+    /// Verify `bytes` written to a temporary `.flac` file.
+    fn verify_flac(name: &str, bytes: &[u8]) -> VerifyReport {
+        let p = std::env::temp_dir().join(format!("oto-{}-{name}.flac", std::process::id()));
+        std::fs::write(&p, bytes).unwrap();
+        let report = verify_file(&p, 16);
+        std::fs::remove_file(&p).unwrap();
+        report.unwrap()
+    }
+
+    fn samples() -> Vec<i16> {
+        (0..64).map(|i| (i * 997 % 4000 - 2000) as i16).collect()
+    }
+
+    #[test]
+    fn matching_md5_passes() {
+        let samples = samples();
+        let report = verify_flac("ok", &flac(&samples, md5(&samples)));
+        assert_eq!(report.checksum, Some(true));
+        assert_eq!(report.skipped_packets, 0);
+        assert!(report.passed());
+    }
+
+    #[test]
+    fn flipped_md5_byte_fails() {
+        let samples = samples();
+        let mut sum = md5(&samples);
+        sum[7] ^= 0x01;
+        let report = verify_flac("bad", &flac(&samples, sum));
+        assert_eq!(report.checksum, Some(false));
+        assert!(!report.passed());
+    }
+
+    #[test]
+    fn zero_md5_is_no_checksum() {
+        let report = verify_flac("none", &flac(&samples(), [0; 16]));
+        assert_eq!(report.checksum, None);
+        assert!(report.passed());
+    }
+}